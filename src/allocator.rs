@@ -0,0 +1,740 @@
+/// All bots trade out of the same Manifold account, so each gets a budget
+/// it can't spend past. Ledgers are kept at cost, and the unfilled part of
+/// a bot's open limit orders is held back from its budget until they're
+/// cancelled or expire. Holdings are marked to market when we have prices
+/// for them. Ledgers can be kept in a file, so that a bot's holdings can be
+/// liquidated from another process.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::errors;
+use crate::internal_packet as ip;
+use crate::manifold_types as mt;
+use crate::risk::RiskRejection;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Budget {
+    /// A fixed amount of mana
+    Fixed(f64),
+    /// A fraction of the account's equity (balance plus investment value)
+    FractionOfEquity(f64),
+}
+
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+struct HoldingKey {
+    contract_id: String,
+    answer_id: Option<String>,
+    outcome: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Holding {
+    shares: f64,
+    cost: f64,
+}
+
+/// A limit order that can still fill
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenOrder {
+    key: HoldingKey,
+    order_amount: f64,
+    /// What's filled so far, which is in the holdings already
+    filled_amount: f64,
+    filled_shares: f64,
+    /// ms since epoch
+    expires_at: Option<u64>,
+}
+
+impl OpenOrder {
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    /// The part of the order that's still held back
+    fn reserved(&self) -> f64 {
+        (self.order_amount - self.filled_amount).max(0.0)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Ledger {
+    #[serde(with = "holding_list")]
+    holdings: HashMap<HoldingKey, Holding>,
+    realized_pnl: f64,
+    /// By bet id
+    #[serde(default)]
+    open_orders: HashMap<String, OpenOrder>,
+}
+
+/// JSON maps need string keys, so holdings are written as a list of
+/// (key, holding) pairs
+mod holding_list {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::{Holding, HoldingKey};
+
+    pub fn serialize<S: Serializer>(
+        holdings: &HashMap<HoldingKey, Holding>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(holdings.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<HoldingKey, Holding>, D::Error> {
+        Ok(Vec::<(HoldingKey, Holding)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+impl Ledger {
+    fn open_cost(&self) -> f64 {
+        self.holdings.values().map(|h| h.cost).sum()
+    }
+
+    /// Held back for orders that haven't expired by `now`
+    fn reserved(&self, now: u64) -> f64 {
+        self.open_orders
+            .values()
+            .filter(|order| !order.expired(now))
+            .map(OpenOrder::reserved)
+            .sum()
+    }
+
+    /// Holdings at their marks, or at cost where we don't have one
+    fn market_value(&self, marks: &HashMap<HoldingKey, f64>) -> f64 {
+        self.holdings
+            .iter()
+            .map(|(key, holding)| match marks.get(key) {
+                Some(price) => holding.shares * price,
+                None => holding.cost,
+            })
+            .sum()
+    }
+
+    fn apply(&mut self, key: &HoldingKey, amount: f64, shares: f64) {
+        let holding = self.holdings.entry(key.clone()).or_default();
+
+        if shares >= 0.0 {
+            holding.shares += shares;
+            holding.cost += amount;
+        } else {
+            // take cost off in proportion to the shares sold
+            let sold_fraction = if holding.shares > 0.0 {
+                (-shares / holding.shares).min(1.0)
+            } else {
+                1.0
+            };
+            let cost_sold = holding.cost * sold_fraction;
+
+            holding.shares = (holding.shares + shares).max(0.0);
+            holding.cost -= cost_sold;
+            self.realized_pnl += -amount - cost_sold;
+        }
+
+        if holding.shares <= 0.0 {
+            self.holdings.remove(key);
+        }
+    }
+
+    /// Books what `order_id` has filled beyond what we saw, given it's
+    /// filled `amount` for `shares` in all
+    fn catch_up(&mut self, order_id: &str, amount: f64, shares: f64) {
+        let order = match self.open_orders.get_mut(order_id) {
+            Some(order) => order,
+            None => return,
+        };
+
+        let missed_amount = amount - order.filled_amount;
+        let missed_shares = shares - order.filled_shares;
+        if missed_shares > 0.0 {
+            debug!("order {order_id} filled {missed_shares} more shares than we saw");
+            order.filled_amount = amount;
+            order.filled_shares = shares;
+            let key = order.key.clone();
+            self.apply(&key, missed_amount, missed_shares);
+        }
+    }
+
+    fn release_expired(&mut self, now: u64) {
+        self.open_orders.retain(|_, order| !order.expired(now));
+    }
+}
+
+/// Per-bot numbers for reporting
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BotEquity {
+    pub bot_id: String,
+    pub allocation: f64,
+    /// cost of the positions the bot holds
+    pub open_cost: f64,
+    /// the positions at their latest prices, or at cost where we have none
+    pub market_value: f64,
+    pub unrealized_pnl: f64,
+    pub realized_pnl: f64,
+    /// unfilled part of the bot's open limit orders
+    pub reserved: f64,
+    /// what the bot can still spend
+    pub available: f64,
+    /// allocation + realized and unrealized pnl
+    pub equity: f64,
+}
+
+impl fmt::Display for BotEquity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: equity {:.2} (allocation {:.2}, realized {:.2}, unrealized {:.2}) | \
+             positions {:.2} at cost {:.2} | reserved {:.2} | available {:.2}",
+            self.bot_id,
+            self.equity,
+            self.allocation,
+            self.realized_pnl,
+            self.unrealized_pnl,
+            self.market_value,
+            self.open_cost,
+            self.reserved,
+            self.available,
+        )
+    }
+}
+
+/// A fill, as parsed from a `bet` or `sell` response. Both responses are
+/// bets; sales have negative amount and shares. A limit order that can
+/// still fill comes with the order.
+#[derive(Debug)]
+struct Fill {
+    key: HoldingKey,
+    amount: f64,
+    shares: f64,
+    order: Option<(String, OpenOrder)>,
+}
+
+impl Fill {
+    fn from_response(packet: &ip::InternalPacket, response: &str) -> Option<Self> {
+        let bet = serde_json::from_str::<serde_json::Value>(response).ok()?;
+
+        let key = HoldingKey {
+            contract_id: bet["contractId"]
+                .as_str()
+                .map(|s| s.to_string())
+                .or(packet.market_id())?,
+            answer_id: bet["answerId"].as_str().map(|s| s.to_string()),
+            outcome: bet["outcome"].as_str()?.to_string(),
+        };
+        let amount = bet["amount"].as_f64()?;
+        let shares = bet["shares"].as_f64()?;
+
+        let open = bet["isFilled"] == false && bet["isCancelled"] == false;
+        let order = match (bet["id"].as_str(), bet["orderAmount"].as_f64()) {
+            (Some(id), Some(order_amount)) if open => Some((
+                id.to_string(),
+                OpenOrder {
+                    key: key.clone(),
+                    order_amount,
+                    filled_amount: amount,
+                    filled_shares: shares,
+                    expires_at: bet["expiresAt"].as_u64(),
+                },
+            )),
+            _ => None,
+        };
+
+        Some(Self {
+            key,
+            amount,
+            shares,
+            order,
+        })
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis() as u64
+}
+
+#[derive(Debug, Default)]
+pub struct CapitalAllocator {
+    budgets: HashMap<String, Budget>,
+    ledgers: HashMap<String, Ledger>,
+    /// Latest price of a share of each holding
+    marks: HashMap<HoldingKey, f64>,
+    account_equity: Option<f64>,
+    ledger_path: Option<PathBuf>,
+}
+
+impl CapitalAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_budget(&mut self, bot_id: String, budget: Budget) {
+        self.budgets.insert(bot_id, budget);
+    }
+
+    pub fn update_account_equity(&mut self, equity: f64) {
+        self.account_equity = Some(equity);
+    }
+
+    /// Keep the ledgers in this file from now on, starting from the ones
+    /// already there
+    pub fn persist_to(&mut self, path: PathBuf) {
+        match Self::load(&path) {
+            Ok(ledgers) => self.ledgers = ledgers,
+            Err(e) => error!("couldn't load {}: {e}", path.display()),
+        }
+        self.ledger_path = Some(path);
+    }
+
+    fn load(path: &PathBuf) -> Result<HashMap<String, Ledger>, errors::Error> {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                serde_json::from_str(&contents).map_err(|e| errors::Error::parse(e, &contents))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self) {
+        let path = match &self.ledger_path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let saved = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .map_err(errors::Error::from)
+            .and_then(|_| Ok(serde_json::to_string_pretty(&self.ledgers)?))
+            .and_then(|contents| Ok(fs::write(path, contents)?));
+
+        if let Err(e) = saved {
+            error!("couldn't save {}: {e}", path.display());
+        }
+    }
+
+    /// The positions the bot holds, by its ledger, with `amount` at cost
+    pub fn holdings(&self, bot_id: &str) -> Vec<mt::Position> {
+        let ledger = match self.ledgers.get(bot_id) {
+            Some(ledger) => ledger,
+            None => return vec![],
+        };
+
+        ledger
+            .holdings
+            .iter()
+            .map(|(key, holding)| mt::Position {
+                outcome: key.outcome.clone(),
+                contract_id: key.contract_id.clone(),
+                answer_id: key.answer_id.clone(),
+                amount: holding.cost,
+                shares: holding.shares,
+            })
+            .collect()
+    }
+
+    /// How much mana the bot is allocated right now. Fractional budgets are
+    /// zero until we know the account's equity.
+    pub fn allocation(&self, bot_id: &str) -> Option<f64> {
+        match self.budgets.get(bot_id)? {
+            Budget::Fixed(amount) => Some(*amount),
+            Budget::FractionOfEquity(fraction) => {
+                Some(fraction * self.account_equity.unwrap_or(0.0))
+            }
+        }
+    }
+
+    /// The markets any bot holds shares in
+    pub fn held_markets(&self) -> Vec<String> {
+        let mut markets = self
+            .ledgers
+            .values()
+            .flat_map(|ledger| ledger.holdings.keys())
+            .map(|key| key.contract_id.clone())
+            .collect::<Vec<String>>();
+        markets.sort();
+        markets.dedup();
+        markets
+    }
+
+    /// Marks the holdings in `market` at its current probabilities
+    pub fn mark(&mut self, market: &mt::FullMarket) {
+        let keys = self
+            .ledgers
+            .values()
+            .flat_map(|ledger| ledger.holdings.keys())
+            .filter(|key| key.contract_id == market.lite_market.id)
+            .cloned()
+            .collect::<Vec<HoldingKey>>();
+
+        for key in keys {
+            let position = mt::Position {
+                outcome: key.outcome.clone(),
+                contract_id: key.contract_id.clone(),
+                answer_id: key.answer_id.clone(),
+                amount: 0.0,
+                shares: 0.0,
+            };
+            if let Some(price) = position.outcome_probability(market) {
+                self.marks.insert(key, price);
+            }
+        }
+    }
+
+    pub fn bot_equity(&self, bot_id: &str) -> Option<BotEquity> {
+        let allocation = self.allocation(bot_id)?;
+        let ledger = self.ledgers.get(bot_id).cloned().unwrap_or_default();
+
+        let reserved = ledger.reserved(now_ms());
+        let open_cost = ledger.open_cost();
+        let market_value = ledger.market_value(&self.marks);
+        let unrealized_pnl = market_value - open_cost;
+
+        Some(BotEquity {
+            bot_id: bot_id.to_string(),
+            allocation,
+            open_cost,
+            market_value,
+            unrealized_pnl,
+            realized_pnl: ledger.realized_pnl,
+            reserved,
+            available: allocation + ledger.realized_pnl - open_cost - reserved,
+            equity: allocation + ledger.realized_pnl + unrealized_pnl,
+        })
+    }
+
+    pub fn report(&self) -> Vec<BotEquity> {
+        let mut report = self
+            .budgets
+            .keys()
+            .filter_map(|bot_id| self.bot_equity(bot_id))
+            .collect::<Vec<BotEquity>>();
+        report.sort_by(|a, b| a.bot_id.cmp(&b.bot_id));
+        report
+    }
+
+    /// Rejects bets the bot doesn't have the budget for. Bots without a
+    /// budget can't bet at all.
+    pub fn check(&self, packet: &ip::InternalPacket) -> Result<(), RiskRejection> {
+        if !matches!(packet.method, ip::Method::Post) || packet.endpoint != "bet" {
+            return Ok(());
+        }
+
+        let amount = packet
+            .data
+            .as_ref()
+            .and_then(|d| d["amount"].as_f64())
+            .unwrap_or(0.0);
+
+        let available = match self.bot_equity(&packet.bot_id) {
+            Some(equity) => equity.available,
+            None => {
+                return Err(RiskRejection::NoBudget {
+                    bot_id: packet.bot_id.clone(),
+                })
+            }
+        };
+
+        if amount > available {
+            return Err(RiskRejection::BudgetExceeded {
+                bot_id: packet.bot_id.clone(),
+                amount,
+                available,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Debits bets and credits sales to the bot's ledger. `response` is the
+    /// body of the accepted `bet`, `sell` or `bet/cancel` request. A limit
+    /// order that doesn't fill straight away holds back the rest of its
+    /// amount until it's cancelled or expires.
+    pub fn record_fill(&mut self, packet: &ip::InternalPacket, response: &str) {
+        if let Some(order_id) = packet.endpoint.strip_prefix("bet/cancel/") {
+            self.record_cancel(&packet.bot_id, order_id, response);
+            return;
+        }
+
+        let fill = match Fill::from_response(packet, response) {
+            Some(fill) => fill,
+            None => return,
+        };
+
+        debug!("allocator fill for {}: {:?}", packet.bot_id, fill);
+
+        let ledger = self.ledgers.entry(packet.bot_id.clone()).or_default();
+        ledger.apply(&fill.key, fill.amount, fill.shares);
+        if let Some((order_id, order)) = fill.order {
+            ledger.open_orders.insert(order_id, order);
+        }
+        ledger.release_expired(now_ms());
+
+        self.save();
+    }
+
+    /// A later fill of the open limit order `order_id`, whichever bot's it
+    /// is. `amount` is what the order paid for `shares`.
+    pub fn record_order_fill(&mut self, order_id: &str, amount: f64, shares: f64) {
+        let ledger = self
+            .ledgers
+            .values_mut()
+            .find(|ledger| ledger.open_orders.contains_key(order_id));
+        let ledger = match ledger {
+            Some(ledger) => ledger,
+            None => return,
+        };
+
+        let order = ledger.open_orders.get_mut(order_id).unwrap();
+        order.filled_amount += amount;
+        order.filled_shares += shares;
+        let key = order.key.clone();
+        if order.reserved() <= 0.0 {
+            ledger.open_orders.remove(order_id);
+        }
+
+        ledger.apply(&key, amount, shares);
+        self.save();
+    }
+
+    /// Releases what's left of a cancelled order. The response is the
+    /// order, with everything it filled, which catches up fills we missed.
+    fn record_cancel(&mut self, bot_id: &str, order_id: &str, response: &str) {
+        let ledger = match self.ledgers.get_mut(bot_id) {
+            Some(ledger) => ledger,
+            None => return,
+        };
+        if !ledger.open_orders.contains_key(order_id) {
+            return;
+        }
+
+        let filled = serde_json::from_str::<serde_json::Value>(response)
+            .ok()
+            .and_then(|bet| Some((bet["amount"].as_f64()?, bet["shares"].as_f64()?)));
+        if let Some((amount, shares)) = filled {
+            ledger.catch_up(order_id, amount, shares);
+        }
+        ledger.open_orders.remove(order_id);
+
+        self.save();
+    }
+
+    /// Markets we have open orders in
+    pub fn open_order_markets(&self) -> Vec<String> {
+        let mut markets = self
+            .ledgers
+            .values()
+            .flat_map(|ledger| ledger.open_orders.values())
+            .map(|order| order.key.contract_id.clone())
+            .collect::<Vec<String>>();
+        markets.sort();
+        markets.dedup();
+        markets
+    }
+
+    /// Catches an open order up with `bet`, the order as the API has it
+    /// now, for when we may have missed some of its fills. Bets that aren't
+    /// our open orders are ignored.
+    pub fn sync_order(&mut self, bet: &mt::Bet) {
+        let ledger = self
+            .ledgers
+            .values_mut()
+            .find(|ledger| ledger.open_orders.contains_key(&bet.id));
+        let ledger = match ledger {
+            Some(ledger) => ledger,
+            None => return,
+        };
+
+        ledger.catch_up(&bet.id, bet.amount, bet.shares);
+        let open = bet
+            .limit_props
+            .as_ref()
+            .is_some_and(|order| !order.is_filled && !order.is_cancelled);
+        if !open {
+            ledger.open_orders.remove(&bet.id);
+        }
+
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bet_packet(bot_id: &str, amount: f64) -> ip::InternalPacket {
+        ip::InternalPacket::new(
+            bot_id.to_string(),
+            ip::Method::Post,
+            "bet".to_string(),
+            vec![],
+            Some(serde_json::json!({"amount": amount, "contractId": "m", "outcome": "YES"})),
+        )
+    }
+
+    fn fill_response(amount: f64, shares: f64) -> String {
+        serde_json::json!({
+            "contractId": "m",
+            "outcome": "YES",
+            "amount": amount,
+            "shares": shares,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_budget_debit_credit() {
+        let mut allocator = CapitalAllocator::new();
+        allocator.set_budget("a".to_string(), Budget::Fixed(100.0));
+
+        assert!(matches!(
+            allocator.check(&bet_packet("b", 1.0)),
+            Err(RiskRejection::NoBudget { .. })
+        ));
+
+        let packet = bet_packet("a", 80.0);
+        assert!(allocator.check(&packet).is_ok());
+        allocator.record_fill(&packet, &fill_response(80.0, 160.0));
+
+        assert!(matches!(
+            allocator.check(&bet_packet("a", 30.0)),
+            Err(RiskRejection::BudgetExceeded { .. })
+        ));
+
+        // sell half the shares for 50, realizing 10 of profit
+        let sell = ip::InternalPacket::new(
+            "a".to_string(),
+            ip::Method::Post,
+            "market/m/sell".to_string(),
+            vec![],
+            None,
+        );
+        allocator.record_fill(&sell, &fill_response(-50.0, -80.0));
+
+        let equity = allocator.bot_equity("a").unwrap();
+        assert_eq!(equity.open_cost, 40.0);
+        assert_eq!(equity.realized_pnl, 10.0);
+        assert_eq!(equity.available, 70.0);
+        assert_eq!(equity.equity, 110.0);
+    }
+
+    fn limit_response(amount: f64, shares: f64, expires_at: Option<u64>) -> String {
+        serde_json::json!({
+            "id": "order",
+            "contractId": "m",
+            "outcome": "YES",
+            "amount": amount,
+            "shares": shares,
+            "orderAmount": 50.0,
+            "limitProb": 0.5,
+            "isFilled": false,
+            "isCancelled": false,
+            "expiresAt": expires_at,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_limit_order_reserved() {
+        let mut allocator = CapitalAllocator::new();
+        allocator.set_budget("a".to_string(), Budget::Fixed(100.0));
+
+        // 10 of the 50 fills straight away, the other 40 is held back
+        allocator.record_fill(&bet_packet("a", 50.0), &limit_response(10.0, 20.0, None));
+        let equity = allocator.bot_equity("a").unwrap();
+        assert_eq!(equity.open_cost, 10.0);
+        assert_eq!(equity.reserved, 40.0);
+        assert_eq!(equity.available, 50.0);
+        assert!(allocator.check(&bet_packet("a", 60.0)).is_err());
+
+        allocator.record_order_fill("order", 15.0, 30.0);
+        let equity = allocator.bot_equity("a").unwrap();
+        assert_eq!(equity.open_cost, 25.0);
+        assert_eq!(equity.reserved, 25.0);
+        assert_eq!(equity.available, 50.0);
+
+        // the cancelled order has filled 5 more than we saw
+        let cancel = ip::InternalPacket::new(
+            "a".to_string(),
+            ip::Method::Post,
+            "bet/cancel/order".to_string(),
+            vec![],
+            None,
+        );
+        allocator.record_fill(&cancel, &limit_response(30.0, 60.0, None));
+        let equity = allocator.bot_equity("a").unwrap();
+        assert_eq!(equity.open_cost, 30.0);
+        assert_eq!(equity.reserved, 0.0);
+        assert_eq!(equity.available, 70.0);
+
+        // expired orders hold nothing back
+        allocator.record_fill(&bet_packet("a", 50.0), &limit_response(0.0, 0.0, Some(1)));
+        assert_eq!(allocator.bot_equity("a").unwrap().reserved, 0.0);
+    }
+
+    #[test]
+    fn test_marked_equity() {
+        let mut allocator = CapitalAllocator::new();
+        allocator.set_budget("a".to_string(), Budget::Fixed(100.0));
+        allocator.record_fill(&bet_packet("a", 40.0), &fill_response(40.0, 80.0));
+
+        // at cost until marked
+        assert_eq!(allocator.bot_equity("a").unwrap().equity, 100.0);
+        assert_eq!(allocator.held_markets(), vec!["m".to_string()]);
+
+        allocator.mark(&crate::testing::market("m").probability(0.75).build());
+        let equity = allocator.bot_equity("a").unwrap();
+        assert_eq!(equity.market_value, 60.0);
+        assert_eq!(equity.unrealized_pnl, 20.0);
+        assert_eq!(equity.equity, 120.0);
+        // what's spendable is still at cost
+        assert_eq!(equity.available, 60.0);
+    }
+
+    #[test]
+    fn test_fraction_of_equity() {
+        let mut allocator = CapitalAllocator::new();
+        allocator.set_budget("a".to_string(), Budget::FractionOfEquity(0.1));
+
+        assert_eq!(allocator.allocation("a"), Some(0.0));
+        allocator.update_account_equity(2000.0);
+        assert_eq!(allocator.allocation("a"), Some(200.0));
+        assert!(allocator.check(&bet_packet("a", 200.0)).is_ok());
+    }
+
+    #[test]
+    fn test_persisted_holdings() {
+        let path =
+            std::env::temp_dir().join(format!("mmm_ledger_test_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut allocator = CapitalAllocator::new();
+        allocator.persist_to(path.clone());
+        allocator.record_fill(&bet_packet("a", 80.0), &fill_response(80.0, 160.0));
+
+        // e.g. `mmm liquidate --bot a`
+        let mut other = CapitalAllocator::new();
+        other.persist_to(path.clone());
+        let holdings = other.holdings("a");
+        assert_eq!(holdings.len(), 1);
+        assert_eq!(holdings[0].contract_id, "m");
+        assert_eq!(holdings[0].shares, 160.0);
+        assert!(other.holdings("b").is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,534 @@
+/// Typed methods for the Manifold API, from <https://docs.manifold.markets/api>.
+/// Reads go at normal priority and writes at urgent priority, except market
+/// searches, which go at background priority as they're for finding markets
+/// to trade rather than trading them. Everything still goes through the
+/// client's rate limits and retries.
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::coms::{self, ManifoldClient};
+use crate::errors::Error;
+use crate::manifold_types as mt;
+use crate::rate_limiter::Priority;
+
+/// Query parameters, skipping the ones that aren't set
+#[derive(Default)]
+struct Query(Vec<(String, String)>);
+
+impl Query {
+    fn opt(mut self, key: &str, value: Option<impl ToString>) -> Self {
+        if let Some(value) = value {
+            self.0.push((key.to_string(), value.to_string()));
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchSort {
+    MostPopular,
+    Newest,
+    Score,
+    DailyScore,
+    FreshnessScore,
+    Volume24Hours,
+    Liquidity,
+    Subsidy,
+    LastUpdated,
+    CloseDate,
+    StartTime,
+    ResolveDate,
+    Random,
+    BountyAmount,
+    ProbDescending,
+    ProbAscending,
+}
+
+impl SearchSort {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SearchSort::MostPopular => "most-popular",
+            SearchSort::Newest => "newest",
+            SearchSort::Score => "score",
+            SearchSort::DailyScore => "daily-score",
+            SearchSort::FreshnessScore => "freshness-score",
+            SearchSort::Volume24Hours => "24-hour-vol",
+            SearchSort::Liquidity => "liquidity",
+            SearchSort::Subsidy => "subsidy",
+            SearchSort::LastUpdated => "last-updated",
+            SearchSort::CloseDate => "close-date",
+            SearchSort::StartTime => "start-time",
+            SearchSort::ResolveDate => "resolve-date",
+            SearchSort::Random => "random",
+            SearchSort::BountyAmount => "bounty-amount",
+            SearchSort::ProbDescending => "prob-descending",
+            SearchSort::ProbAscending => "prob-ascending",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchFilter {
+    All,
+    Open,
+    Closed,
+    Resolved,
+    ClosingThisMonth,
+    ClosingNextMonth,
+}
+
+impl SearchFilter {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SearchFilter::All => "all",
+            SearchFilter::Open => "open",
+            SearchFilter::Closed => "closed",
+            SearchFilter::Resolved => "resolved",
+            SearchFilter::ClosingThisMonth => "closing-this-month",
+            SearchFilter::ClosingNextMonth => "closing-next-month",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchContractType {
+    All,
+    Binary,
+    MultipleChoice,
+    DependentMultipleChoice,
+    IndependentMultipleChoice,
+    Bounty,
+    Poll,
+    Number,
+}
+
+impl SearchContractType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SearchContractType::All => "ALL",
+            SearchContractType::Binary => "BINARY",
+            SearchContractType::MultipleChoice => "MULTIPLE_CHOICE",
+            SearchContractType::DependentMultipleChoice => "DEPENDENT_MULTIPLE_CHOICE",
+            SearchContractType::IndependentMultipleChoice => "INDEPENDENT_MULTIPLE_CHOICE",
+            SearchContractType::Bounty => "BOUNTY",
+            SearchContractType::Poll => "POLL",
+            SearchContractType::Number => "NUMBER",
+        }
+    }
+}
+
+/// Filters for `search-markets`; unset ones are left to the API's defaults
+#[derive(Debug, Clone, Default)]
+pub struct MarketSearch {
+    pub term: String,
+    pub sort: Option<SearchSort>,
+    pub filter: Option<SearchFilter>,
+    pub contract_type: Option<SearchContractType>,
+    pub topic_slug: Option<String>,
+    pub creator_id: Option<String>,
+    /// Only markets with at least this much liquidity
+    pub liquidity: Option<f64>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+impl MarketSearch {
+    fn query(&self) -> Vec<(String, String)> {
+        Query(vec![("term".to_string(), self.term.clone())])
+            .opt("sort", self.sort.map(|s| s.as_str()))
+            .opt("filter", self.filter.map(|f| f.as_str()))
+            .opt("contractType", self.contract_type.map(|c| c.as_str()))
+            .opt("topicSlug", self.topic_slug.as_ref())
+            .opt("creatorId", self.creator_id.as_ref())
+            .opt("liquidity", self.liquidity)
+            .opt("limit", self.limit)
+            .opt("offset", self.offset)
+            .0
+    }
+}
+
+/// Filters for `bets`
+#[derive(Debug, Clone, Default)]
+pub struct BetQuery {
+    pub user_id: Option<String>,
+    pub username: Option<String>,
+    pub contract_id: Option<String>,
+    pub contract_slug: Option<String>,
+    /// Bets placed before the bet with this id
+    pub before: Option<String>,
+    /// Bets placed after the bet with this id
+    pub after: Option<String>,
+    pub limit: Option<u32>,
+    /// Only open limit orders
+    pub open_limit_orders: bool,
+}
+
+impl BetQuery {
+    pub(crate) fn query(&self) -> Vec<(String, String)> {
+        Query::default()
+            .opt("userId", self.user_id.as_ref())
+            .opt("username", self.username.as_ref())
+            .opt("contractId", self.contract_id.as_ref())
+            .opt("contractSlug", self.contract_slug.as_ref())
+            .opt("before", self.before.as_ref())
+            .opt("after", self.after.as_ref())
+            .opt("limit", self.limit)
+            .opt("kinds", self.open_limit_orders.then_some("open-limit"))
+            .0
+    }
+}
+
+/// Filters for `comments`
+#[derive(Debug, Clone, Default)]
+pub struct CommentQuery {
+    pub contract_id: Option<String>,
+    pub contract_slug: Option<String>,
+    pub user_id: Option<String>,
+    pub limit: Option<u32>,
+    pub page: Option<u32>,
+}
+
+impl CommentQuery {
+    fn query(&self) -> Vec<(String, String)> {
+        Query::default()
+            .opt("contractId", self.contract_id.as_ref())
+            .opt("contractSlug", self.contract_slug.as_ref())
+            .opt("userId", self.user_id.as_ref())
+            .opt("limit", self.limit)
+            .opt("page", self.page)
+            .0
+    }
+}
+
+/// Body of a `bet` POST
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct BetRequest {
+    #[serde(rename = "contractId")]
+    pub contract_id: String,
+    pub amount: f64,
+    pub outcome: String,
+    #[serde(rename = "answerId", skip_serializing_if = "Option::is_none")]
+    pub answer_id: Option<String>,
+    /// Makes it a limit order
+    #[serde(rename = "limitProb", skip_serializing_if = "Option::is_none")]
+    pub limit_prob: Option<f64>,
+    /// ms since epoch
+    #[serde(rename = "expiresAt", skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+/// Body of a `market/{id}/sell` POST
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SellRequest {
+    /// Defaults to whichever outcome we hold
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<String>,
+    #[serde(rename = "answerId", skip_serializing_if = "Option::is_none")]
+    pub answer_id: Option<String>,
+    /// Defaults to all of them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shares: Option<f64>,
+}
+
+/// Body of a `market` POST
+#[derive(Serialize, Debug, Clone)]
+pub struct CreateMarket {
+    #[serde(rename = "outcomeType")]
+    pub outcome_type: mt::MarketOutcomeType,
+    pub question: String,
+    #[serde(
+        rename = "descriptionMarkdown",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub description_markdown: Option<String>,
+    /// ms since epoch
+    #[serde(rename = "closeTime", skip_serializing_if = "Option::is_none")]
+    pub close_time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<mt::Visibility>,
+    #[serde(rename = "groupIds", skip_serializing_if = "Vec::is_empty")]
+    pub group_ids: Vec<String>,
+    /// BINARY only, 1 to 99
+    #[serde(rename = "initialProb", skip_serializing_if = "Option::is_none")]
+    pub initial_prob: Option<u32>,
+    /// PSEUDO_NUMERIC only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(rename = "isLogScale", skip_serializing_if = "Option::is_none")]
+    pub is_log_scale: Option<bool>,
+    #[serde(rename = "initialValue", skip_serializing_if = "Option::is_none")]
+    pub initial_value: Option<f64>,
+    /// MULTIPLE_CHOICE only
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub answers: Vec<String>,
+    #[serde(
+        rename = "shouldAnswersSumToOne",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub should_answers_sum_to_one: Option<bool>,
+}
+
+impl CreateMarket {
+    pub fn binary(question: String, initial_prob: u32) -> Self {
+        Self {
+            outcome_type: mt::MarketOutcomeType::Binary,
+            question,
+            description_markdown: None,
+            close_time: None,
+            visibility: None,
+            group_ids: vec![],
+            initial_prob: Some(initial_prob),
+            min: None,
+            max: None,
+            is_log_scale: None,
+            initial_value: None,
+            answers: vec![],
+            should_answers_sum_to_one: None,
+        }
+    }
+}
+
+/// Body of a `market/{id}/resolve` POST
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Resolution {
+    /// YES, NO, MKT or CANCEL; for multiple choice, an answer id, MKT or CANCEL
+    pub outcome: String,
+    /// BINARY resolved to MKT, 0 to 100
+    #[serde(rename = "probabilityInt", skip_serializing_if = "Option::is_none")]
+    pub probability_int: Option<f64>,
+    /// Multiple choice resolved to MKT: answer id to weight
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolutions: Option<std::collections::HashMap<String, f64>>,
+    /// PSEUDO_NUMERIC only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+}
+
+impl ManifoldClient {
+    async fn get_json<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        query_params: &[(String, String)],
+    ) -> Result<T, Error> {
+        let resp = self.get(Priority::Normal, endpoint, query_params).await?;
+        coms::response_into::<T>(resp).await
+    }
+
+    async fn post_json<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        data: &impl Serialize,
+    ) -> Result<T, Error> {
+        let data = serde_json::to_value(data)?;
+        let resp = self
+            .post(Priority::Urgent, endpoint, &[], Some(&data))
+            .await?;
+        coms::response_into::<T>(resp).await
+    }
+
+    pub async fn me(&self) -> Result<mt::User, Error> {
+        self.get_json("me", &[]).await
+    }
+
+    pub async fn user(&self, username: &str) -> Result<mt::LiteUser, Error> {
+        self.get_json(&format!("user/{username}"), &[]).await
+    }
+
+    pub async fn user_by_id(&self, user_id: &str) -> Result<mt::LiteUser, Error> {
+        self.get_json(&format!("user/by-id/{user_id}"), &[]).await
+    }
+
+    /// Newest first; `before` is a user id to page from
+    pub async fn users(
+        &self,
+        limit: Option<u32>,
+        before: Option<&str>,
+    ) -> Result<Vec<mt::LiteUser>, Error> {
+        let query = Query::default().opt("limit", limit).opt("before", before).0;
+        self.get_json("users", &query).await
+    }
+
+    /// Newest first; `before` is a market id to page from
+    pub async fn markets(
+        &self,
+        limit: Option<u32>,
+        before: Option<&str>,
+    ) -> Result<Vec<mt::LiteMarket>, Error> {
+        let query = Query::default().opt("limit", limit).opt("before", before).0;
+        self.get_json("markets", &query).await
+    }
+
+    pub async fn market(&self, market_id: &str) -> Result<mt::FullMarket, Error> {
+        self.get_json(&format!("market/{market_id}"), &[]).await
+    }
+
+    pub async fn market_by_slug(&self, slug: &str) -> Result<mt::FullMarket, Error> {
+        self.get_json(&format!("slug/{slug}"), &[]).await
+    }
+
+    /// At background priority, so searches wait behind bots' reads
+    pub async fn search_markets(
+        &self,
+        search: &MarketSearch,
+    ) -> Result<Vec<mt::LiteMarket>, Error> {
+        let resp = self
+            .get(Priority::Background, "search-markets", &search.query())
+            .await?;
+        coms::response_into(resp).await
+    }
+
+    pub async fn bets(&self, query: &BetQuery) -> Result<Vec<mt::Bet>, Error> {
+        self.get_json("bets", &query.query()).await
+    }
+
+    pub async fn comments(&self, query: &CommentQuery) -> Result<Vec<mt::Comment>, Error> {
+        self.get_json("comments", &query.query()).await
+    }
+
+    pub async fn groups(&self) -> Result<Vec<mt::Group>, Error> {
+        self.get_json("groups", &[]).await
+    }
+
+    pub async fn group(&self, slug: &str) -> Result<mt::Group, Error> {
+        self.get_json(&format!("group/{slug}"), &[]).await
+    }
+
+    pub async fn group_by_id(&self, group_id: &str) -> Result<mt::Group, Error> {
+        self.get_json(&format!("group/by-id/{group_id}"), &[]).await
+    }
+
+    /// Positions in a market, optionally just one user's
+    pub async fn market_positions(
+        &self,
+        market_id: &str,
+        user_id: Option<&str>,
+    ) -> Result<Vec<mt::ContractMetric>, Error> {
+        let query = Query::default().opt("userId", user_id).0;
+        self.get_json(&format!("market/{market_id}/positions"), &query)
+            .await
+    }
+
+    pub async fn user_portfolio(&self, user_id: &str) -> Result<mt::Portfolio, Error> {
+        let query = Query::default().opt("userId", Some(user_id)).0;
+        self.get_json("get-user-portfolio", &query).await
+    }
+
+    pub async fn user_contract_metrics(
+        &self,
+        user_id: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<mt::UserContractMetrics, Error> {
+        let query = Query::default()
+            .opt("userId", Some(user_id))
+            .opt("limit", Some(limit))
+            .opt("offset", Some(offset))
+            .0;
+        self.get_json("get-user-contract-metrics-with-contracts", &query)
+            .await
+    }
+
+    pub async fn place_bet(&self, bet: &BetRequest) -> Result<mt::Bet, Error> {
+        self.post_json("bet", bet).await
+    }
+
+    pub async fn sell(&self, market_id: &str, sale: &SellRequest) -> Result<mt::Bet, Error> {
+        self.post_json(&format!("market/{market_id}/sell"), sale)
+            .await
+    }
+
+    /// Cancels a limit order
+    pub async fn cancel_bet(&self, bet_id: &str) -> Result<(), Error> {
+        let resp = self
+            .post(Priority::Urgent, &format!("bet/cancel/{bet_id}"), &[], None)
+            .await?;
+        coms::response_into::<Value>(resp).await.map(|_| ())
+    }
+
+    pub async fn create_market(&self, market: &CreateMarket) -> Result<mt::LiteMarket, Error> {
+        self.post_json("market", market).await
+    }
+
+    pub async fn resolve_market(
+        &self,
+        market_id: &str,
+        resolution: &Resolution,
+    ) -> Result<(), Error> {
+        self.post_json::<Value>(&format!("market/{market_id}/resolve"), resolution)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn add_liquidity(&self, market_id: &str, amount: f64) -> Result<(), Error> {
+        self.post_json::<Value>(
+            &format!("market/{market_id}/add-liquidity"),
+            &serde_json::json!({ "amount": amount }),
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(query: &[(String, String)]) -> Vec<(&str, &str)> {
+        query
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_search_query() {
+        let search = MarketSearch {
+            term: "sudoku".to_string(),
+            sort: Some(SearchSort::Volume24Hours),
+            filter: Some(SearchFilter::Open),
+            contract_type: Some(SearchContractType::Binary),
+            limit: Some(5),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            pairs(&search.query()),
+            vec![
+                ("term", "sudoku"),
+                ("sort", "24-hour-vol"),
+                ("filter", "open"),
+                ("contractType", "BINARY"),
+                ("limit", "5"),
+            ]
+        );
+
+        let bets = BetQuery {
+            contract_id: Some("m".to_string()),
+            open_limit_orders: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            pairs(&bets.query()),
+            vec![("contractId", "m"), ("kinds", "open-limit")]
+        );
+    }
+
+    #[test]
+    fn test_bet_request_body() {
+        let bet = BetRequest {
+            contract_id: "m".to_string(),
+            amount: 10.0,
+            outcome: "YES".to_string(),
+            limit_prob: Some(0.4),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            serde_json::to_value(&bet).unwrap(),
+            serde_json::json!({"contractId": "m", "amount": 10.0, "outcome": "YES", "limitProb": 0.4})
+        );
+    }
+}
@@ -0,0 +1,164 @@
+/// Bet streams as subscribers see them. Polling and the websocket both send
+/// into a `BetFeed`, which drops bets it has already sent, so subscribers
+/// get each bet once, along with events for when bets may be missing.
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::manifold_types as mt;
+
+/// How many bet ids a feed remembers to deduplicate against
+const SEEN_CAPACITY: usize = 1024;
+
+// almost every event is a bet, so boxing them would cost more than it saves
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum BetStreamEvent {
+    Bet(mt::Bet),
+    /// Bets may have been missed, `missed` of them if we know how many.
+    /// Anything derived from earlier bets (e.g. the market probability)
+    /// should be refreshed.
+    Gap {
+        missed: Option<u64>,
+    },
+    /// The source of bets was failing and has recovered, without missing
+    /// any
+    Reconnected,
+}
+
+/// Recently seen bet ids
+#[derive(Default)]
+pub struct Seen {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl Seen {
+    /// Remembers `id`, returning false if it was already remembered
+    pub fn insert(&mut self, id: &str) -> bool {
+        if self.ids.contains(id) {
+            return false;
+        }
+
+        if self.order.len() >= SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+
+        self.ids.insert(id.to_string());
+        self.order.push_back(id.to_string());
+        true
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+}
+
+/// The sending side of a bet stream
+#[derive(Clone)]
+pub struct BetFeed {
+    tx: broadcast::Sender<BetStreamEvent>,
+    seen: Arc<Mutex<Seen>>,
+}
+
+impl BetFeed {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+
+        Self {
+            tx,
+            seen: Arc::new(Mutex::new(Seen::default())),
+        }
+    }
+
+    pub fn subscribe(&self) -> BetStream {
+        BetStream {
+            rx: self.tx.subscribe(),
+        }
+    }
+
+    /// Sends `bet` unless it's been sent already. Returns whether it was new.
+    pub fn send_bet(&self, bet: mt::Bet) -> bool {
+        if !self.seen.lock().unwrap().insert(&bet.id) {
+            return false;
+        }
+
+        self.send(BetStreamEvent::Bet(bet));
+        true
+    }
+
+    pub fn send(&self, event: BetStreamEvent) {
+        if self.tx.send(event).is_err() {
+            debug!("no subscribers for bet stream");
+        }
+    }
+}
+
+/// The receiving side of a bet stream
+pub struct BetStream {
+    rx: broadcast::Receiver<BetStreamEvent>,
+}
+
+impl BetStream {
+    /// The next event, or None once nothing is sending any more. Falling
+    /// too far behind skips the oldest events, which shows up as a `Gap`.
+    pub async fn recv(&mut self) -> Option<BetStreamEvent> {
+        match self.rx.recv().await {
+            Ok(event) => Some(event),
+            Err(RecvError::Lagged(missed)) => Some(BetStreamEvent::Gap {
+                missed: Some(missed),
+            }),
+            Err(RecvError::Closed) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn bet(id: &str) -> mt::Bet {
+        testing::bet(id).build()
+    }
+
+    #[tokio::test]
+    async fn test_dedupe() {
+        let feed = BetFeed::new(8);
+        let mut stream = feed.subscribe();
+
+        assert!(feed.send_bet(bet("a")));
+        assert!(feed.send_bet(bet("b")));
+        assert!(!feed.send_bet(bet("a")));
+        drop(feed);
+
+        let mut ids = vec![];
+        while let Some(event) = stream.recv().await {
+            match event {
+                BetStreamEvent::Bet(bet) => ids.push(bet.id),
+                e => panic!("unexpected {e:?}"),
+            }
+        }
+        assert_eq!(ids, vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_lagged() {
+        let feed = BetFeed::new(2);
+        let mut stream = feed.subscribe();
+
+        for id in ["a", "b", "c", "d"] {
+            feed.send_bet(bet(id));
+        }
+
+        assert!(matches!(
+            stream.recv().await,
+            Some(BetStreamEvent::Gap { missed: Some(2) })
+        ));
+        assert!(matches!(stream.recv().await, Some(BetStreamEvent::Bet(b)) if b.id == "c"));
+    }
+}
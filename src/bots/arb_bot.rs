@@ -1,10 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use async_trait::async_trait;
-use log::{debug, error, info, warn};
-use tokio::sync::{broadcast, mpsc};
+use log::{debug, info, warn};
 
-use crate::bots::Bot;
+use crate::bots::{Bot, BotHealth};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::events::{Event, EventFilter, EventKind};
 use crate::manifold_types;
 
 use crate::internal_packet::{InternalPacket, Method};
@@ -13,37 +13,25 @@ pub struct ArbitrageBot {
     id: String,
     market: manifold_types::FullMarket,
     answers: HashMap<String, manifold_types::Answer>,
-    bot_to_mh_tx: mpsc::Sender<InternalPacket>,
-    mh_to_bot_rx: broadcast::Receiver<InternalPacket>,
+    /// Answers whose probability we lost track of in a gap in the bet stream
+    stale_answers: HashSet<String>,
+    circuit_breaker: CircuitBreaker,
+    health: BotHealth,
 }
 
 impl ArbitrageBot {
     pub fn new(
         id: String,
         market: manifold_types::FullMarket,
-        bot_to_mh_tx: mpsc::Sender<InternalPacket>,
-        mh_to_bot_rx: broadcast::Receiver<InternalPacket>,
+        circuit_breaker: CircuitBreaker,
     ) -> Self {
-        let mut id_to_answers = HashMap::new();
-
-        match &market.answers {
-            Some(answers) => {
-                for answer in answers {
-                    id_to_answers.insert(answer.id.clone(), answer.clone());
-                }
-            }
-            None => {
-                error!("market {} has no answers", &market.lite_market.question);
-                panic!("market {} has no answers", &market.lite_market.question);
-            }
-        }
-
         Self {
             id,
             market,
-            answers: id_to_answers,
-            bot_to_mh_tx,
-            mh_to_bot_rx,
+            answers: HashMap::new(),
+            stale_answers: HashSet::new(),
+            circuit_breaker,
+            health: BotHealth::Healthy,
         }
     }
 
@@ -72,24 +60,6 @@ impl ArbitrageBot {
         bets
     }
 
-    async fn make_bets(&mut self, bets: Vec<manifold_types::BotBet>) {
-        for bet in bets {
-            self.bot_to_mh_tx
-                .send(self.botbet_to_internal_coms_packet(bet))
-                .await
-                .unwrap();
-
-            match self.mh_to_bot_rx.recv().await {
-                Ok(resp) => {
-                    info!("made bet {:?}", resp);
-                }
-                Err(e) => {
-                    error!("mh_to_bot_rx gave error {e}");
-                }
-            }
-        }
-    }
-
     fn botbet_to_internal_coms_packet(&self, bet: manifold_types::BotBet) -> InternalPacket {
         InternalPacket::new(
             self.get_id(),
@@ -106,11 +76,40 @@ impl ArbitrageBot {
     }
 }
 
-#[async_trait]
 impl Bot for ArbitrageBot {
-    async fn run(&mut self, mut rx: broadcast::Receiver<manifold_types::Bet>) {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn event_filter(&self) -> EventFilter {
+        EventFilter::default()
+            .market(self.market.lite_market.id.clone())
+            .kind(EventKind::NewBet)
+    }
+
+    fn markets(&self) -> Vec<String> {
+        vec![self.market.lite_market.id.clone()]
+    }
+
+    fn on_start(&mut self, markets: &[manifold_types::FullMarket]) -> Vec<InternalPacket> {
         info!("starting arbitrage bot");
 
+        if let Some(market) = markets.first() {
+            self.market = market.clone();
+        }
+
+        self.answers = match &self.market.answers {
+            Some(answers) => answers
+                .iter()
+                .map(|answer| (answer.id.clone(), answer.clone()))
+                .collect(),
+            None => {
+                let reason = format!("market {} has no answers", self.market.lite_market.question);
+                self.health = BotHealth::Failed(reason);
+                return vec![];
+            }
+        };
+
         let tot_prob = self.find_arb();
         if tot_prob >= 1. {
             info!("FOUND ARB OPPORTUNITY! {tot_prob}");
@@ -123,51 +122,125 @@ impl Bot for ArbitrageBot {
         info!("want to make {} bets", bets_to_make.len());
         debug!("bets to make {:?}", bets_to_make);
 
-        self.make_bets(bets_to_make).await;
+        bets_to_make
+            .into_iter()
+            .map(|bet| self.botbet_to_internal_coms_packet(bet))
+            .collect()
+    }
+
+    fn on_event(&mut self, event: &Event) -> Vec<InternalPacket> {
+        let bet = match event {
+            Event::NewBet(bet) => bet,
+            Event::Gap { missed, .. } => {
+                warn!("ArbitrageBot missed events ({missed:?})");
+                self.stale_answers = self.answers.keys().cloned().collect();
+                return vec![];
+            }
+            _ => return vec![],
+        };
+
+        debug!("{:?}", bet);
+
+        let answer_id = match &bet.answer_id {
+            Some(answer_id) => answer_id,
+            None => {
+                warn!("bet {} has no answer_id", bet.id);
+                return vec![];
+            }
+        };
 
-        let mut i: u64 = 0;
-        loop {
-            let bet: manifold_types::Bet = match rx.recv().await {
-                Ok(bet) => bet,
-                Err(e) => {
-                    warn!("in ArbitrageBot::run {e}");
-                    continue;
-                }
-            };
+        let answer = match self.answers.get_mut(answer_id) {
+            Some(answer) => answer,
+            None => {
+                warn!("bet {} is on an answer we don't know, {answer_id}", bet.id);
+                return vec![];
+            }
+        };
 
-            debug!("{i} {:?}", bet);
+        debug!(
+            "answer_id {answer_id} prob before {} new prob {} our previous prob{}",
+            bet.prob_before, bet.prob_after, answer.probability
+        );
 
-            let answer_id = &bet.answer_id.expect("answer_id is None");
+        // after a gap, the first bet on each answer catches us up
+        let stale = self.stale_answers.remove(answer_id);
 
-            debug!(
-                "answer_id {answer_id} prob before {} new prob {} our previous prob{}",
-                &bet.prob_before,
-                &bet.prob_after,
-                self.answers.get_mut(answer_id).unwrap().probability
+        if bet.prob_before != answer.probability && !stale {
+            warn!(
+                "bet_prev_prob {} != our_prev_prob {}",
+                bet.prob_before, answer.probability
             );
+            self.circuit_breaker.report_divergence(&self.id);
+        }
 
-            let bet_prev_prob = &bet.prob_before;
-            let bet_after_prob = &bet.prob_after;
-            let our_prev_prob = &self.answers.get_mut(answer_id).unwrap().probability;
+        answer.probability = bet.prob_after;
 
-            if bet_prev_prob != our_prev_prob {
-                warn!(
-                    "bet_prev_prob {} != our_prev_prob {}",
-                    bet_prev_prob, our_prev_prob
-                );
-            }
+        vec![]
+    }
 
-            self.answers.get_mut(answer_id).unwrap().probability = *bet_after_prob;
+    fn on_order_update(&mut self, packet: &InternalPacket) -> Vec<InternalPacket> {
+        info!("made bet {:?}", packet);
+        vec![]
+    }
 
-            i += 1;
-        }
+    fn health(&self) -> BotHealth {
+        self.health.clone()
     }
+}
 
-    fn get_id(&self) -> String {
-        self.id.clone()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, BotHarness};
+
+    fn bot(market: manifold_types::FullMarket) -> ArbitrageBot {
+        ArbitrageBot::new(
+            "arb".to_string(),
+            market,
+            CircuitBreaker::new(Default::default()),
+        )
     }
 
-    fn close(&self) {
-        println!("closing arbitrage bot");
+    #[tokio::test]
+    async fn test_start_bets_on_every_answer() {
+        let market = testing::market("m")
+            .answers(vec![
+                testing::answer("a1").probability(0.25),
+                testing::answer("a2").probability(0.5),
+            ])
+            .build();
+        let mut harness = BotHarness::new(bot(market.clone()));
+
+        let mut sent = harness.start(&[market]).await;
+        sent.sort_by_key(|p| p.data.as_ref().unwrap()["answerId"].to_string());
+
+        // bets are proportional to 1 / probability, and add up to M500
+        let amounts = sent
+            .iter()
+            .map(|p| {
+                let data = p.data.as_ref().unwrap();
+                assert_eq!(p.endpoint, "bet");
+                assert_eq!(data["contractId"], "m");
+                assert_eq!(data["outcome"], "YES");
+                data["amount"].as_f64().unwrap()
+            })
+            .collect::<Vec<f64>>();
+        assert!((amounts[0] - 500.0 * 2.0 / 3.0).abs() < 1e-9, "{amounts:?}");
+        assert!((amounts[1] - 500.0 / 3.0).abs() < 1e-9, "{amounts:?}");
+        assert_eq!(harness.health(), BotHealth::Healthy);
+
+        // bets only update what the bot knows
+        let bet = testing::bet("b").answer("a1").probs(0.25, 0.3).build();
+        assert!(harness.bets(vec![bet]).await.is_empty());
+        assert_eq!(harness.bot.answers["a1"].probability, 0.3);
+    }
+
+    #[tokio::test]
+    async fn test_start_without_answers() {
+        let market = testing::market("m").build();
+        let mut harness = BotHarness::new(bot(market.clone()));
+
+        assert!(harness.start(&[market]).await.is_empty());
+        assert!(matches!(harness.health(), BotHealth::Failed(_)));
     }
 }
@@ -1,18 +1,19 @@
-use async_trait::async_trait;
-use log::{debug, error, info, warn};
-use tokio::sync::{broadcast, mpsc};
+use log::{debug, info, warn};
 
 use crate::bots::Bot;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::errors::ApiFailure;
+use crate::events::{Event, EventFilter, EventKind};
 use crate::manifold_types;
 
 use crate::internal_packet::{InternalPacket, Method};
 
-struct EWMA {
+struct Ewma {
     s0: f64,
     alpha: f64,
 }
 
-impl EWMA {
+impl Ewma {
     fn new(s0: f64, alpha: f64) -> Self {
         Self { s0, alpha }
     }
@@ -25,60 +26,56 @@ impl EWMA {
 
 pub struct EWMABot {
     id: String,
+    market_id: String,
 
-    bot_to_mh_tx: mpsc::Sender<InternalPacket>,
-    mh_to_bot_rx: broadcast::Receiver<InternalPacket>,
+    circuit_breaker: CircuitBreaker,
 
-    ewma_1: EWMA,
-    ewma_2: EWMA,
+    ewma_1: Ewma,
+    ewma_2: Ewma,
 
-    // used as a sanity check
-    current_probability: f64,
+    // used as a sanity check; None until the first bet, and after a gap
+    current_probability: Option<f64>,
     p1_above_p2: bool,
+
+    // set once the API tells us the market's closed, after which we stop
+    market_closed: bool,
 }
 
 impl EWMABot {
     pub fn new(
         id: String,
-        bot_to_mh_tx: mpsc::Sender<InternalPacket>,
-        mh_to_bot_rx: broadcast::Receiver<InternalPacket>,
+        market_id: String,
+        circuit_breaker: CircuitBreaker,
         alpha_1: f64,
         alpha_2: f64,
     ) -> Self {
-        let ewma_1 = EWMA::new(0.0, alpha_1);
-        let ewma_2 = EWMA::new(0.0, alpha_2);
+        let ewma_1 = Ewma::new(0.0, alpha_1);
+        let ewma_2 = Ewma::new(0.0, alpha_2);
 
         Self {
             id,
-            bot_to_mh_tx,
-            mh_to_bot_rx,
+            market_id,
+            circuit_breaker,
             ewma_1,
             ewma_2,
-            current_probability: 0.0,
+            current_probability: None,
             p1_above_p2: true,
-        }
-    }
-
-    async fn make_trades(&mut self, trades: Vec<InternalPacket>) {
-        for trade in trades {
-            self.bot_to_mh_tx.send(trade).await.unwrap();
-
-            match self.mh_to_bot_rx.recv().await {
-                Ok(resp) => info!("made bet {:?}", resp),
-                Err(e) => error!("mh_to_bot_rx gave error {e}"),
-            }
+            market_closed: false,
         }
     }
 
     fn update_prob(&mut self, bet: &manifold_types::Bet) -> manifold_types::Side {
-        if self.current_probability != bet.prob_before {
-            warn!(
-                "bot's current_probability ({}) is not prob_before ({})",
-                self.current_probability, bet.prob_after
-            );
+        if let Some(current_probability) = self.current_probability {
+            if current_probability != bet.prob_before {
+                warn!(
+                    "bot's current_probability ({}) is not prob_before ({})",
+                    current_probability, bet.prob_after
+                );
+                self.circuit_breaker.report_divergence(&self.id);
+            }
         }
 
-        self.current_probability = bet.prob_after;
+        self.current_probability = Some(bet.prob_after);
 
         let v1 = self.ewma_1.update(bet.prob_before);
         let v2 = self.ewma_2.update(bet.prob_after);
@@ -95,65 +92,138 @@ impl EWMABot {
     }
 }
 
-#[async_trait]
 impl Bot for EWMABot {
-    async fn run(&mut self, mut rx: broadcast::Receiver<manifold_types::Bet>) {
-        info!("starting arbitrage bot");
-
-        let mut i: u64 = 0;
-        loop {
-            let bet: manifold_types::Bet = match rx.recv().await {
-                Ok(bet) => bet,
-                Err(e) => {
-                    warn!("in EWMABot::run {e}");
-                    continue;
-                }
-            };
-
-            debug!("{i} {:?}", bet);
-
-            match self.update_prob(&bet) {
-                manifold_types::Side::Buy => {
-                    let buy_bet = InternalPacket::new(
-                        self.get_id(),
-                        Method::Post,
-                        "bet".to_string(),
-                        vec![],
-                        Some(serde_json::json!({
-                            "amount": bet.amount,
-                            "contractId": bet.contract_id,
-                            "outcome": bet.outcome,
-                        })),
-                    );
-
-                    self.make_trades(vec![buy_bet]).await;
-                }
-                manifold_types::Side::Sell => {
-                    let sell_bet = InternalPacket::new(
-                        self.get_id(),
-                        Method::Post,
-                        format!("market/{}/sell", bet.contract_id),
-                        vec![],
-                        Some(serde_json::json!({
-                            "outcome": bet.outcome,
-                            "shares": bet.amount
-                        })),
-                    );
-
-                    self.make_trades(vec![sell_bet]).await;
-                }
-                manifold_types::Side::NoOp => {}
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn event_filter(&self) -> EventFilter {
+        EventFilter::default()
+            .market(self.market_id.clone())
+            .kind(EventKind::NewBet)
+    }
+
+    fn on_event(&mut self, event: &Event) -> Vec<InternalPacket> {
+        let bet = match event {
+            Event::NewBet(bet) => bet,
+            Event::Gap { missed, .. } => {
+                warn!("EWMABot missed events ({missed:?})");
+                self.current_probability = None;
+                return vec![];
             }
+            _ => return vec![],
+        };
 
-            i += 1;
+        if self.market_closed {
+            return vec![];
+        }
+
+        debug!("{:?}", bet);
+
+        match self.update_prob(bet) {
+            manifold_types::Side::Buy => vec![InternalPacket::new(
+                self.get_id(),
+                Method::Post,
+                "bet".to_string(),
+                vec![],
+                Some(serde_json::json!({
+                    "amount": bet.amount,
+                    "contractId": bet.contract_id,
+                    "outcome": bet.outcome,
+                })),
+            )],
+            manifold_types::Side::Sell => vec![InternalPacket::new(
+                self.get_id(),
+                Method::Post,
+                format!("market/{}/sell", bet.contract_id),
+                vec![],
+                Some(serde_json::json!({
+                    "outcome": bet.outcome,
+                    "shares": bet.amount
+                })),
+            )],
+            manifold_types::Side::NoOp => vec![],
         }
     }
 
-    fn get_id(&self) -> String {
-        self.id.clone()
+    fn on_order_update(&mut self, packet: &InternalPacket) -> Vec<InternalPacket> {
+        match &packet.error {
+            Some(ApiFailure::MarketClosed(e)) => {
+                warn!(
+                    "{} stopping, {} is closed: {}",
+                    self.id, self.market_id, e.message
+                );
+                self.market_closed = true;
+            }
+            Some(e) => warn!("bet failed: {e:?}"),
+            None => info!("made bet {:?}", packet),
+        }
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ApiError;
+    use crate::testing::{self, BotHarness};
+
+    fn bet(prob_before: f64, prob_after: f64) -> manifold_types::Bet {
+        testing::bet("b").probs(prob_before, prob_after).build()
+    }
+
+    #[tokio::test]
+    async fn test_crossovers() {
+        let mut harness = BotHarness::new(EWMABot::new(
+            "ewma".to_string(),
+            "m".to_string(),
+            CircuitBreaker::new(Default::default()),
+            0.4,
+            0.7,
+        ));
+
+        // the averages start at 0, and the prob_before one ends up below
+        let sell = harness.bets(vec![bet(0.5, 0.6)]).await;
+        assert_eq!(sell.len(), 1);
+        assert_eq!(sell[0].endpoint, "market/m/sell");
+        assert_eq!(
+            sell[0].data,
+            Some(serde_json::json!({"outcome": "YES", "shares": 10.0}))
+        );
+
+        let buy = harness.bets(vec![bet(0.6, 0.2)]).await;
+        assert_eq!(buy.len(), 1);
+        assert_eq!(buy[0].endpoint, "bet");
+        assert_eq!(
+            buy[0].data,
+            Some(serde_json::json!({"amount": 10.0, "contractId": "m", "outcome": "YES"}))
+        );
+
+        assert!(harness.bets(vec![bet(0.2, 0.2)]).await.is_empty());
+
+        // other markets' bets don't get through the filter
+        let elsewhere = testing::bet("b").market("other").probs(0.9, 0.1).build();
+        assert!(harness.bets(vec![elsewhere]).await.is_empty());
+        assert_eq!(harness.sent().len(), 2);
     }
 
-    fn close(&self) {
-        println!("closing arbitrage bot");
+    #[tokio::test]
+    async fn test_market_closed() {
+        let closed = ApiFailure::MarketClosed(ApiError {
+            message: "Trading is closed.".to_string(),
+            details: None,
+        });
+        let mut harness = BotHarness::new(EWMABot::new(
+            "ewma".to_string(),
+            "m".to_string(),
+            CircuitBreaker::new(Default::default()),
+            0.4,
+            0.7,
+        ))
+        .fail("market/m/sell", closed);
+
+        assert_eq!(harness.bets(vec![bet(0.5, 0.6)]).await.len(), 1);
+        // would have been a buy
+        assert!(harness.bets(vec![bet(0.6, 0.2)]).await.is_empty());
     }
 }
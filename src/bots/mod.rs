@@ -1,14 +1,73 @@
-use async_trait::async_trait;
-
-use tokio::sync::broadcast;
+use std::time::Duration;
 
+use crate::events::{Event, EventFilter};
+use crate::internal_packet::InternalPacket;
+use crate::liquidation::LiquidationFilter;
 use crate::manifold_types;
 
-#[async_trait]
-pub trait Bot {
-    async fn run(&mut self, rx: broadcast::Receiver<manifold_types::Bet>);
+/// How a bot is doing, checked by the supervisor after every hook
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum BotHealth {
+    Healthy,
+    /// Still trading, but something's off
+    Degraded(String),
+    /// Can't carry on; the supervisor halts the bot
+    Failed(String),
+}
+
+/// What to do with a bot's positions once it has stopped
+#[derive(Debug, Clone, Default)]
+pub struct FlattenPlan {
+    /// Sent before liquidating, e.g. to cancel open limit orders
+    pub orders: Vec<InternalPacket>,
+    /// Which of the bot's positions to sell, if any. `bot_id` is filled in
+    /// by the supervisor.
+    pub liquidate: Option<LiquidationFilter>,
+}
+
+/// A strategy, driven by the supervisor. Every hook returns the orders the
+/// bot wants sent to the MarketHandler; their responses come back through
+/// `on_order_update`.
+pub trait Bot: Send {
     fn get_id(&self) -> String;
-    fn close(&self);
+
+    /// Which events `on_event` gets
+    fn event_filter(&self) -> EventFilter;
+
+    /// Markets to snapshot for `on_start`
+    fn markets(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn on_start(&mut self, _markets: &[manifold_types::FullMarket]) -> Vec<InternalPacket> {
+        vec![]
+    }
+
+    fn on_event(&mut self, event: &Event) -> Vec<InternalPacket>;
+
+    /// The MarketHandler's response to one of our orders, or why it
+    /// failed: a risk rejection, or an error from the API
+    fn on_order_update(&mut self, _packet: &InternalPacket) -> Vec<InternalPacket> {
+        vec![]
+    }
+
+    /// How often `on_timer` is called, if at all
+    fn timer_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    fn on_timer(&mut self) -> Vec<InternalPacket> {
+        vec![]
+    }
+
+    fn on_shutdown(&mut self) -> FlattenPlan {
+        FlattenPlan::default()
+    }
+
+    fn health(&self) -> BotHealth {
+        BotHealth::Healthy
+    }
 }
 
 pub mod arb_bot;
@@ -0,0 +1,531 @@
+/// Halts trading for a bot, or for everyone, when things look wrong.
+/// Once tripped, trading only resumes through an explicit `resume`, either
+/// in-process or by `mmm resume` editing the state file.
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::errors;
+
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// API errors in a row from one bot's packets before that bot is halted
+    pub max_consecutive_errors: u32,
+
+    /// API errors in a row across all bots before everything is halted
+    pub max_global_consecutive_errors: u32,
+
+    /// Halt everything once equity (balance plus the value of positions) is
+    /// this far below its peak
+    pub max_drawdown: f64,
+
+    /// Halt everything if equity drops by more than this...
+    pub max_balance_drop: f64,
+    /// ...within this window
+    pub balance_drop_window: Duration,
+
+    /// Halt a bot if the probabilities it sees diverge from what it expects
+    /// this many times...
+    pub max_prob_divergences: u32,
+    /// ...within this window
+    pub divergence_window: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            max_consecutive_errors: 5,
+            max_global_consecutive_errors: 10,
+            max_drawdown: 1000.0,
+            max_balance_drop: 500.0,
+            balance_drop_window: Duration::from_secs(60 * 60),
+            max_prob_divergences: 10,
+            divergence_window: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TripReason {
+    ConsecutiveErrors(u32),
+    Drawdown {
+        peak: f64,
+        #[serde(alias = "balance")]
+        equity: f64,
+    },
+    /// Of equity, despite the name
+    BalanceDrop {
+        drop: f64,
+        window_secs: u64,
+    },
+    ProbDivergence(u32),
+    /// The bot reported itself as failed
+    Unhealthy(String),
+    Manual(String),
+}
+
+impl fmt::Display for TripReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TripReason::ConsecutiveErrors(n) => write!(f, "{n} consecutive api errors"),
+            TripReason::Drawdown { peak, equity } => {
+                write!(f, "drawdown from {peak:.2} to {equity:.2}")
+            }
+            TripReason::BalanceDrop { drop, window_secs } => {
+                write!(f, "equity dropped {drop:.2} in {window_secs}s")
+            }
+            TripReason::ProbDivergence(n) => write!(f, "{n} probability divergences"),
+            TripReason::Unhealthy(reason) => write!(f, "unhealthy: {reason}"),
+            TripReason::Manual(reason) => write!(f, "manual halt: {reason}"),
+        }
+    }
+}
+
+/// What's halted. This is what gets written to the state file.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct HaltState {
+    pub global: Option<TripReason>,
+    pub bots: HashMap<String, TripReason>,
+}
+
+impl HaltState {
+    pub fn load(path: &PathBuf) -> Result<Self, errors::Error> {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                serde_json::from_str(&contents).map_err(|e| errors::Error::parse(e, &contents))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &PathBuf) -> Result<(), errors::Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let contents = serde_json::to_string_pretty(self)?;
+        Ok(fs::write(path, contents)?)
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    config: BreakerConfig,
+    state: HaltState,
+    state_path: Option<PathBuf>,
+    /// Modification time and length of the state file when we last read or
+    /// wrote it, so it's only reread when it changes
+    state_stamp: Option<(SystemTime, u64)>,
+
+    consecutive_errors: HashMap<String, u32>,
+    global_consecutive_errors: u32,
+
+    peak_equity: Option<f64>,
+    equity_history: VecDeque<(Instant, f64)>,
+
+    divergences: HashMap<String, VecDeque<Instant>>,
+}
+
+impl Inner {
+    /// With a state file, the file is the source of truth for what's halted,
+    /// so `mmm halt` / `mmm resume` from another process take effect. Halts
+    /// lifted in the file reset their counters, as `resume` does.
+    fn sync_from_disk(&mut self) {
+        let path = match &self.state_path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+
+        let stamp = Self::stamp(&path);
+        if stamp.is_some() && stamp == self.state_stamp {
+            return;
+        }
+
+        let state = match HaltState::load(&path) {
+            Ok(state) => state,
+            Err(e) => {
+                error!("couldn't load {}: {e}", path.display());
+                return;
+            }
+        };
+        self.state_stamp = stamp;
+
+        if self.state.global.is_some() && state.global.is_none() {
+            info!("all trading resumed through {}", path.display());
+            self.reset_global();
+        }
+
+        let resumed: Vec<String> = self
+            .state
+            .bots
+            .keys()
+            .filter(|bot_id| !state.bots.contains_key(*bot_id))
+            .cloned()
+            .collect();
+        for bot_id in resumed {
+            info!("bot {bot_id} resumed through {}", path.display());
+            self.reset_bot(&bot_id);
+        }
+
+        self.state = state;
+    }
+
+    fn save(&mut self) {
+        if let Some(path) = &self.state_path {
+            match self.state.save(path) {
+                Ok(()) => self.state_stamp = Self::stamp(path),
+                Err(e) => error!("couldn't save {}: {e}", path.display()),
+            }
+        }
+    }
+
+    fn stamp(path: &PathBuf) -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    fn reset_global(&mut self) {
+        self.global_consecutive_errors = 0;
+        self.peak_equity = None;
+        self.equity_history.clear();
+    }
+
+    fn reset_bot(&mut self, bot_id: &str) {
+        self.consecutive_errors.remove(bot_id);
+        self.divergences.remove(bot_id);
+    }
+
+    fn trip(&mut self, bot_id: Option<&str>, reason: TripReason) {
+        self.sync_from_disk();
+
+        match bot_id {
+            Some(bot_id) => {
+                if self.state.bots.contains_key(bot_id) {
+                    return;
+                }
+                error!("halting bot {bot_id}: {reason}");
+                self.state.bots.insert(bot_id.to_string(), reason);
+            }
+            None => {
+                if self.state.global.is_some() {
+                    return;
+                }
+                error!("halting all trading: {reason}");
+                self.state.global = Some(reason);
+            }
+        }
+
+        self.save();
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                config,
+                state: HaltState::default(),
+                state_path: None,
+                state_stamp: None,
+                consecutive_errors: HashMap::new(),
+                global_consecutive_errors: 0,
+                peak_equity: None,
+                equity_history: VecDeque::new(),
+                divergences: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn set_config(&self, config: BreakerConfig) {
+        self.inner.lock().unwrap().config = config;
+    }
+
+    /// Keep the halt state in this file, so that it survives restarts and
+    /// can be changed by other processes
+    pub fn persist_to(&self, path: PathBuf) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state_path = Some(path);
+        inner.sync_from_disk();
+    }
+
+    /// Why the bot can't trade right now, if it can't
+    pub fn halted(&self, bot_id: &str) -> Option<TripReason> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sync_from_disk();
+
+        inner
+            .state
+            .global
+            .clone()
+            .or(inner.state.bots.get(bot_id).cloned())
+    }
+
+    pub fn state(&self) -> HaltState {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sync_from_disk();
+        inner.state.clone()
+    }
+
+    /// Halt a bot, or everything if bot_id is None
+    pub fn trip(&self, bot_id: Option<&str>, reason: TripReason) {
+        self.inner.lock().unwrap().trip(bot_id, reason);
+    }
+
+    /// Resume a bot, or global trading if bot_id is None. Resuming global
+    /// trading doesn't resume bots that were halted individually.
+    pub fn resume(&self, bot_id: Option<&str>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sync_from_disk();
+
+        match bot_id {
+            Some(bot_id) => {
+                inner.state.bots.remove(bot_id);
+                inner.reset_bot(bot_id);
+                info!("resumed bot {bot_id}");
+            }
+            None => {
+                inner.state.global = None;
+                inner.reset_global();
+                info!("resumed all trading");
+            }
+        }
+
+        inner.save();
+    }
+
+    pub fn record_api_result(&self, bot_id: &str, success: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sync_from_disk();
+
+        if success {
+            inner.consecutive_errors.remove(bot_id);
+            inner.global_consecutive_errors = 0;
+            return;
+        }
+
+        let bot_errors = {
+            let errors = inner
+                .consecutive_errors
+                .entry(bot_id.to_string())
+                .or_insert(0);
+            *errors += 1;
+            *errors
+        };
+        inner.global_consecutive_errors += 1;
+        let global_errors = inner.global_consecutive_errors;
+
+        if bot_errors >= inner.config.max_consecutive_errors {
+            inner.trip(Some(bot_id), TripReason::ConsecutiveErrors(bot_errors));
+        }
+
+        if global_errors >= inner.config.max_global_consecutive_errors {
+            inner.trip(None, TripReason::ConsecutiveErrors(global_errors));
+        }
+    }
+
+    /// Checks the account's equity, i.e. balance plus investment value, for
+    /// drawdown. Betting moves mana from balance into positions, so the
+    /// balance alone would look like losses.
+    pub fn record_equity(&self, equity: f64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sync_from_disk();
+        let now = Instant::now();
+
+        let peak = inner.peak_equity.unwrap_or(equity).max(equity);
+        inner.peak_equity = Some(peak);
+
+        let window = inner.config.balance_drop_window;
+        inner.equity_history.push_back((now, equity));
+        while let Some((t, _)) = inner.equity_history.front() {
+            if now.duration_since(*t) > window {
+                inner.equity_history.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if peak - equity > inner.config.max_drawdown {
+            inner.trip(None, TripReason::Drawdown { peak, equity });
+        }
+
+        let window_max = inner
+            .equity_history
+            .iter()
+            .map(|(_, e)| *e)
+            .fold(equity, f64::max);
+
+        if window_max - equity > inner.config.max_balance_drop {
+            inner.trip(
+                None,
+                TripReason::BalanceDrop {
+                    drop: window_max - equity,
+                    window_secs: window.as_secs(),
+                },
+            );
+        }
+    }
+
+    /// Called by bots when a bet's prob_before isn't the probability they
+    /// had, i.e. their view of the market is off
+    pub fn report_divergence(&self, bot_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sync_from_disk();
+        let now = Instant::now();
+        let window = inner.config.divergence_window;
+
+        let count = {
+            let divergences = inner.divergences.entry(bot_id.to_string()).or_default();
+            divergences.push_back(now);
+            while let Some(t) = divergences.front() {
+                if now.duration_since(*t) > window {
+                    divergences.pop_front();
+                } else {
+                    break;
+                }
+            }
+            divergences.len() as u32
+        };
+
+        warn!("bot {bot_id} reported probability divergence ({count} in window)");
+
+        if count >= inner.config.max_prob_divergences {
+            inner.trip(Some(bot_id), TripReason::ProbDivergence(count));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> BreakerConfig {
+        BreakerConfig {
+            max_consecutive_errors: 2,
+            max_global_consecutive_errors: 3,
+            max_drawdown: 100.0,
+            max_balance_drop: 50.0,
+            balance_drop_window: Duration::from_secs(60),
+            max_prob_divergences: 2,
+            divergence_window: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn test_consecutive_errors() {
+        let cb = CircuitBreaker::new(config());
+
+        cb.record_api_result("a", false);
+        cb.record_api_result("a", true);
+        cb.record_api_result("a", false);
+        assert_eq!(cb.halted("a"), None);
+
+        cb.record_api_result("a", false);
+        assert_eq!(cb.halted("a"), Some(TripReason::ConsecutiveErrors(2)));
+        assert_eq!(cb.halted("b"), None);
+
+        // a third error in a row, from any bot, halts everything
+        cb.record_api_result("b", false);
+        assert_eq!(cb.halted("b"), Some(TripReason::ConsecutiveErrors(3)));
+
+        cb.resume(None);
+        assert_eq!(cb.halted("b"), None);
+        assert!(cb.halted("a").is_some());
+
+        cb.resume(Some("a"));
+        assert_eq!(cb.halted("a"), None);
+    }
+
+    #[test]
+    fn test_equity_drop_and_drawdown() {
+        let cb = CircuitBreaker::new(config());
+
+        cb.record_equity(1000.0);
+        cb.record_equity(960.0);
+        assert_eq!(cb.halted("a"), None);
+
+        cb.record_equity(940.0);
+        assert!(matches!(
+            cb.halted("a"),
+            Some(TripReason::BalanceDrop { .. })
+        ));
+
+        cb.resume(None);
+        cb.record_equity(2000.0);
+        cb.record_equity(1899.0);
+        assert!(matches!(cb.halted("a"), Some(TripReason::Drawdown { .. })));
+    }
+
+    #[test]
+    fn test_divergence() {
+        let cb = CircuitBreaker::new(config());
+
+        cb.report_divergence("a");
+        assert_eq!(cb.halted("a"), None);
+        cb.report_divergence("a");
+        assert_eq!(cb.halted("a"), Some(TripReason::ProbDivergence(2)));
+    }
+
+    #[test]
+    fn test_state_file() {
+        let path = std::env::temp_dir().join(format!("mmm_halt_test_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let cb = CircuitBreaker::new(config());
+        cb.persist_to(path.clone());
+        cb.trip(Some("a"), TripReason::Manual("test".to_string()));
+
+        // another process resumes through the file
+        let mut state = HaltState::load(&path).unwrap();
+        assert!(state.bots.contains_key("a"));
+        state.bots.remove("a");
+        state.save(&path).unwrap();
+
+        assert_eq!(cb.halted("a"), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_resume_from_other_process() {
+        let path =
+            std::env::temp_dir().join(format!("mmm_halt_resume_test_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let cb = CircuitBreaker::new(config());
+        cb.persist_to(path.clone());
+
+        cb.record_equity(1000.0);
+        cb.record_equity(940.0);
+        cb.record_api_result("a", false);
+        cb.record_api_result("a", false);
+        assert!(cb.halted("b").is_some());
+        assert!(cb.halted("a").is_some());
+
+        // `mmm resume`, in another process
+        let other = CircuitBreaker::new(config());
+        other.persist_to(path.clone());
+        other.resume(None);
+        other.resume(Some("a"));
+
+        assert_eq!(cb.halted("a"), None);
+
+        // the counters that tripped the breakers start over
+        cb.record_equity(940.0);
+        cb.record_api_result("a", false);
+        assert_eq!(cb.halted("a"), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,20 +1,102 @@
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
 
+use crate::position_report::{OutputFormat, SortKey};
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Where the circuit breaker keeps which bots are halted
+    #[arg(long, global = true, default_value = ".mmm/halt.json")]
+    pub halt_file: PathBuf,
+
+    /// Where the capital allocator keeps each bot's holdings, so that
+    /// `liquidate --bot` can find them
+    #[arg(long, global = true, default_value = ".mmm/ledgers.json")]
+    pub ledger_file: PathBuf,
+
+    /// Keep recent API requests in this directory, so restarts and other
+    /// mmm processes using it stay within the same rate limits
+    #[arg(long, global = true)]
+    pub rate_limit_dir: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Run the bot
-    Run,
+    Run {
+        /// Stream bets over Manifold's websocket, polling only while it's down
+        #[arg(long)]
+        websocket: bool,
+    },
 
     /// Liquidate all positions
-    Liquidate,
+    Liquidate {
+        /// Only print what would be sold, and the estimated proceeds
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Only liquidate positions in this market id (repeatable)
+        #[arg(long = "market")]
+        markets: Vec<String>,
+
+        /// Only sell this bot's holdings, as recorded in the ledger file
+        #[arg(long)]
+        bot: Option<String>,
+
+        /// Percentage of each position to sell
+        #[arg(long, default_value_t = 100.0)]
+        percent: f64,
+
+        /// Number of times to retry a failed sell
+        #[arg(long, default_value_t = 2)]
+        retries: u32,
+    },
+
+    /// Halt trading for a bot, or for all bots
+    Halt {
+        /// Only halt this bot
+        #[arg(long)]
+        bot: Option<String>,
+
+        #[arg(long, default_value = "halted from the command line")]
+        reason: String,
+    },
+
+    /// Resume trading after a halt, for a bot or globally
+    Resume {
+        /// Only resume this bot
+        #[arg(long)]
+        bot: Option<String>,
+    },
 
     /// Print all positions
-    Positions,
+    Positions {
+        /// Column to sort by
+        #[arg(long, value_enum, default_value_t = SortKey::Market)]
+        sort: SortKey,
+
+        /// Sort in descending order
+        #[arg(long)]
+        desc: bool,
+
+        /// Only show positions whose question or answer contains this
+        #[arg(long)]
+        search: Option<String>,
+
+        /// Only show positions in this outcome (e.g. YES or NO)
+        #[arg(long)]
+        outcome: Option<String>,
+
+        /// Only show positions with at least this mark value
+        #[arg(long)]
+        min_value: Option<f64>,
+
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
 }
@@ -1,154 +1,389 @@
 use std::env;
 
-use log::{debug, error};
+use log::{debug, error, warn};
 
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
 use serde_json::Value;
 
-use tokio::time::Duration;
+use tokio::time::{sleep, Duration};
 
 use crate::rate_limiter;
 
+use crate::bet_stream as bs;
 use crate::errors;
 use crate::internal_packet as ip;
+use crate::manifold_types as mt;
+use crate::retry;
 
-fn get_api_url() -> String {
-    if env::var("MMM_BACKTEST").is_err() {
-        "https://api.manifold.markets/v0".to_string()
-    } else {
-        "http://127.0.0.1:3030/v0".to_string()
-    }
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// e.g. `https://api.manifold.markets/v0`
+    pub base_url: String,
+    /// Without a key, only public endpoints work
+    pub api_key: Option<String>,
+    pub connect_timeout: Duration,
+    /// For the whole request, including reading the response
+    pub request_timeout: Duration,
+    pub retry_policy: retry::RetryPolicy,
 }
 
-fn get_env_key(key: &str) -> Result<String, String> {
-    match env::var(key) {
-        Ok(key) => Ok(format!("Key {key}")),
-        Err(e) => Err(format!("couldn't find Manifold API key: {e}")),
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "https://api.manifold.markets/v0".to_string(),
+            api_key: None,
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            retry_policy: retry::RetryPolicy::default(),
+        }
     }
 }
 
-pub async fn get_endpoint(
-    endpoint: String,
-    query_params: &[(String, String)],
-) -> Result<reqwest::Response, reqwest::Error> {
-    debug!(
-        "get endpoint; endpoint '{endpoint}'; query params '{:?}'",
-        query_params,
-    );
-
-    let client = reqwest::Client::new();
-
-    let req = client
-        .get(format!("{}/{endpoint}", get_api_url()))
-        .query(&query_params)
-        .header("Authorization", get_env_key("MANIFOLD_KEY").unwrap());
-
-    let resp = req.send().await?;
-
-    if resp.status().is_success() {
-        Ok(resp)
-    } else {
-        error!("api error (bad status code) {resp:?} {query_params:?}");
-        Err(resp.error_for_status().unwrap_err())
+impl ClientConfig {
+    /// The API key from `MANIFOLD_KEY`, and the local backtest server
+    /// instead of Manifold if `MMM_BACKTEST` is set
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+
+        if env::var("MMM_BACKTEST").is_ok() {
+            config.base_url = "http://127.0.0.1:3030/v0".to_string();
+        }
+
+        match env::var("MANIFOLD_KEY") {
+            Ok(key) => config.api_key = Some(key),
+            Err(e) => warn!("couldn't find Manifold API key: {e}"),
+        }
+
+        config
     }
 }
 
-pub async fn post_endpoint(
-    endpoint: String,
-    query_params: &[(String, String)],
-    data: Option<Value>,
-) -> Result<reqwest::Response, reqwest::Error> {
-    debug!(
-        "post endpoint; endpoint '{endpoint}'; query params '{:?}'; data '{:?}'",
-        query_params, data
-    );
-
-    let client = reqwest::Client::new();
-    let mut req = client
-        .post(format!("{}/{endpoint}", get_api_url()))
-        .query(&query_params)
-        .header("Authorization", get_env_key("MANIFOLD_KEY").unwrap());
-
-    if let Some(data) = data {
-        req = req.json(&data);
+/// Talks to the Manifold API. Cheap to clone: clones share the connection
+/// pool and rate limits.
+#[derive(Debug, Clone)]
+pub struct ManifoldClient {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    retry_policy: retry::RetryPolicy,
+    rate_limiters: rate_limiter::EndpointLimiters,
+}
+
+/// How long the server wants us to wait, from `Retry-After` or, when the
+/// quota is used up, `X-RateLimit-Reset` (both in seconds)
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = |name: &str| {
+        headers
+            .get(name)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|s| s.is_finite() && *s >= 0.0)
+            .map(Duration::from_secs_f64)
     };
 
-    let resp = req.send().await?;
+    seconds("retry-after").or_else(|| seconds("x-ratelimit-reset"))
+}
+
+fn quota_exhausted(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-ratelimit-remaining")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .is_some_and(|remaining| remaining <= 0.0)
+}
+
+impl ManifoldClient {
+    pub fn new(
+        config: ClientConfig,
+        rate_limiters: rate_limiter::EndpointLimiters,
+    ) -> Result<Self, reqwest::Error> {
+        let http = reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.request_timeout)
+            .build()?;
+
+        Ok(Self {
+            http,
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            api_key: config.api_key,
+            retry_policy: config.retry_policy,
+            rate_limiters,
+        })
+    }
+
+    pub fn from_env() -> Result<Self, reqwest::Error> {
+        Self::new(
+            ClientConfig::from_env(),
+            rate_limiter::EndpointLimiters::default(),
+        )
+    }
+
+    pub fn rate_limiters(&self) -> &rate_limiter::EndpointLimiters {
+        &self.rate_limiters
+    }
+
+    async fn send(
+        &self,
+        method: ip::Method,
+        endpoint: &str,
+        query_params: &[(String, String)],
+        data: Option<&Value>,
+    ) -> Result<reqwest::Response, errors::Error> {
+        debug!(
+            "{method:?} endpoint; endpoint '{endpoint}'; query params '{:?}'; data '{:?}'",
+            query_params, data
+        );
+
+        let url = format!("{}/{endpoint}", self.base_url);
+        let mut req = match method {
+            ip::Method::Get => self.http.get(url),
+            ip::Method::Post => self.http.post(url),
+        }
+        .query(&query_params);
+
+        if let Some(key) = &self.api_key {
+            req = req.header("Authorization", format!("Key {key}"));
+        }
+
+        if let Some(data) = data {
+            req = req.json(data);
+        };
+
+        let resp = req.send().await?;
+
+        if resp.status() == StatusCode::TOO_MANY_REQUESTS || quota_exhausted(resp.headers()) {
+            self.rate_limiters
+                .throttle(method, endpoint, retry_after(resp.headers()));
+        } else if resp.status().is_success() {
+            self.rate_limiters.record_success(method, endpoint);
+        }
+
+        if resp.status().is_success() {
+            return Ok(resp);
+        }
+
+        let status = resp.status();
+        let delay = retry_after(resp.headers());
+        let body = resp.text().await?;
+        error!("api error {status} from {method:?} {endpoint} {query_params:?}: {body}");
+
+        Err(match errors::Error::from_response(status.as_u16(), body) {
+            errors::Error::RateLimited { .. } => errors::Error::RateLimited {
+                endpoint: endpoint.to_string(),
+                retry_after: delay,
+            },
+            e => e,
+        })
+    }
+
+    /// Sends the request, retrying per the client's `RetryPolicy`. Every
+    /// attempt counts against the rate limits.
+    async fn send_with_retry(
+        &self,
+        priority: rate_limiter::Priority,
+        method: ip::Method,
+        endpoint: &str,
+        query_params: &[(String, String)],
+        data: Option<&Value>,
+    ) -> Result<reqwest::Response, errors::Error> {
+        let mut attempt = 1;
+
+        loop {
+            self.rate_limiters.acquire(method, endpoint, priority).await;
+
+            match self.send(method, endpoint, query_params, data).await {
+                Err(e) if self.retry_policy.should_retry(method, &e, attempt) => {
+                    let delay = self.retry_policy.delay(attempt);
+                    warn!("{method:?} {endpoint} failed on attempt {attempt}, retrying in {delay:?}: {e}");
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub async fn get(
+        &self,
+        priority: rate_limiter::Priority,
+        endpoint: &str,
+        query_params: &[(String, String)],
+    ) -> Result<reqwest::Response, errors::Error> {
+        self.send_with_retry(priority, ip::Method::Get, endpoint, query_params, None)
+            .await
+    }
 
-    if resp.status().is_success() {
-        Ok(resp)
-    } else {
-        error!("api error (bad status code) {resp:?} {query_params:?}");
-        Err(resp.error_for_status().unwrap_err())
+    pub async fn post(
+        &self,
+        priority: rate_limiter::Priority,
+        endpoint: &str,
+        query_params: &[(String, String)],
+        data: Option<&Value>,
+    ) -> Result<reqwest::Response, errors::Error> {
+        self.send_with_retry(priority, ip::Method::Post, endpoint, query_params, data)
+            .await
+    }
+
+    pub async fn send_internal_packet(
+        &self,
+        internal_coms_packet: &ip::InternalPacket,
+    ) -> Result<reqwest::Response, errors::Error> {
+        match internal_coms_packet.method {
+            ip::Method::Get => {
+                self.get(
+                    rate_limiter::Priority::Normal,
+                    &internal_coms_packet.endpoint,
+                    &internal_coms_packet.query_params,
+                )
+                .await
+            }
+            ip::Method::Post => {
+                // orders and cancels can't wait behind data collection
+                self.post(
+                    rate_limiter::Priority::Urgent,
+                    &internal_coms_packet.endpoint,
+                    &internal_coms_packet.query_params,
+                    internal_coms_packet.data.as_ref(),
+                )
+                .await
+            }
+        }
+    }
+
+    /// After a `bet` POST with body `order`, sent at `sent_at` (unix ms),
+    /// failed in a way that leaves us unsure whether it went through, looks
+    /// for it among the account's recent bets in the market. `placed` has
+    /// the bets we already know came from other orders.
+    pub async fn reconcile_bet(
+        &self,
+        order: &Value,
+        sent_at: u64,
+        placed: &bs::Seen,
+    ) -> Result<Option<mt::Bet>, errors::Error> {
+        let contract_id = order["contractId"]
+            .as_str()
+            .ok_or_else(|| "order has no contractId".to_string())?;
+
+        let me =
+            response_into::<mt::User>(self.get(rate_limiter::Priority::Urgent, "me", &[]).await?)
+                .await?;
+
+        let bets = response_into::<Vec<mt::Bet>>(
+            self.get(
+                rate_limiter::Priority::Urgent,
+                "bets",
+                &[
+                    ("userId".to_string(), me.lite_user.id),
+                    ("contractId".to_string(), contract_id.to_string()),
+                    ("limit".to_string(), "20".to_string()),
+                ],
+            )
+            .await?,
+        )
+        .await?;
+
+        Ok(retry::matching_bet(order, &bets, sent_at, placed).cloned())
     }
 }
 
 pub async fn response_into<T: serde::de::DeserializeOwned>(
     resp: reqwest::Response,
-) -> Result<T, errors::ReqwestResponseParsing> {
+) -> Result<T, errors::Error> {
     let body = resp.text().await?;
     let from_json = serde_json::from_str::<T>(&body);
     match from_json {
         Ok(t) => Ok(t),
         Err(e) => {
-            error!("Couldn't parse response {body}");
-            Err(errors::ReqwestResponseParsing::SerdeError(e))
+            let e = errors::Error::parse(e, &body);
+            error!("{e}");
+            Err(e)
         }
     }
 }
 
-pub async fn rate_limited_post_endpoint(
-    mut write_rate_limiter: rate_limiter::RateLimiter,
-    endpoint: String,
-    query_params: &[(String, String)],
-    data: Option<Value>,
-) -> Result<reqwest::Response, reqwest::Error> {
-    if write_rate_limiter.block_for_average_pace_then_commit(Duration::from_secs(60)) {
-        post_endpoint(endpoint, query_params, data).await
-    } else {
-        panic!(
-            "rate limiter timed out; this shouldn't be possible, \
-            most likely rate limit is set wrong"
-        );
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-pub async fn rate_limited_get_endpoint(
-    mut read_rate_limiter: rate_limiter::RateLimiter,
-    endpoint: String,
-    query_params: &[(String, String)],
-) -> Result<reqwest::Response, reqwest::Error> {
-    if read_rate_limiter.block_for_average_pace_then_commit(Duration::from_secs(1)) {
-        get_endpoint(endpoint, query_params).await
-    } else {
-        panic!(
-            "rate limiter timed out; this shouldn't be possible, \
-            most likely rate limit is set wrong"
-        );
-    }
-}
+    use serde_json::json;
+
+    use crate::testing::MockServer;
+
+    #[tokio::test]
+    async fn test_client_against_mock() {
+        let server = MockServer::start(vec![("bets", vec![(200, json!({"ok": true}))])]).await;
+
+        let client = ManifoldClient::new(
+            ClientConfig {
+                base_url: server.base_url(),
+                api_key: Some("secret".to_string()),
+                ..Default::default()
+            },
+            rate_limiter::EndpointLimiters::default(),
+        )
+        .unwrap();
 
-pub async fn send_internal_packet(
-    read_rate_limiter: &rate_limiter::RateLimiter,
-    write_rate_limiter: &rate_limiter::RateLimiter,
-    internal_coms_packet: &ip::InternalPacket,
-) -> Result<reqwest::Response, reqwest::Error> {
-    match internal_coms_packet.method {
-        ip::Method::Get => {
-            rate_limited_get_endpoint(
-                read_rate_limiter.clone(),
-                internal_coms_packet.endpoint.clone(),
-                &internal_coms_packet.query_params,
+        let resp = client
+            .get(
+                rate_limiter::Priority::Normal,
+                "bets",
+                &[("limit".to_string(), "1".to_string())],
             )
             .await
-        }
-        ip::Method::Post => {
-            rate_limited_post_endpoint(
-                write_rate_limiter.clone(),
-                internal_coms_packet.endpoint.clone(),
-                &internal_coms_packet.query_params,
-                internal_coms_packet.data.clone(),
-            )
+            .unwrap();
+        let body = response_into::<Value>(resp).await.unwrap();
+        assert_eq!(body["ok"], true);
+
+        let request = server.requests()[0].head.to_lowercase();
+        assert!(request.starts_with("get /v0/bets?limit=1 "), "{request}");
+        assert!(request.contains("authorization: key secret"), "{request}");
+    }
+
+    #[tokio::test]
+    async fn test_error_body() {
+        let server = MockServer::start(vec![(
+            "bet",
+            vec![(403, json!({"message": "Insufficient balance."}))],
+        )])
+        .await;
+
+        let client = ManifoldClient::new(
+            ClientConfig {
+                base_url: server.base_url(),
+                ..Default::default()
+            },
+            rate_limiter::EndpointLimiters::default(),
+        )
+        .unwrap();
+
+        let order = serde_json::json!({"contractId": "m", "outcome": "YES", "amount": 10.0});
+        let err = client
+            .post(rate_limiter::Priority::Urgent, "bet", &[], Some(&order))
             .await
-        }
+            .unwrap_err();
+        assert!(
+            matches!(&err, errors::Error::InsufficientBalance(e) if e.message == "Insufficient balance."),
+            "{err:?}"
+        );
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        assert!(!quota_exhausted(&headers));
+
+        headers.insert("x-ratelimit-remaining", "0".parse().unwrap());
+        headers.insert("x-ratelimit-reset", "30".parse().unwrap());
+        assert!(quota_exhausted(&headers));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(30)));
+
+        headers.insert("retry-after", "2.5".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_millis(2500)));
     }
 }
@@ -1,39 +1,325 @@
-use std::error::Error;
 use std::fmt;
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::risk::RiskRejection;
+
+/// How much of a payload that couldn't be parsed to keep in the error
+const SNIPPET_LEN: usize = 200;
+
+/// What Manifold says, lowercased, when refusing to trade in a market that
+/// has closed or resolved
+const MARKET_CLOSED_MESSAGES: [&str; 5] = [
+    "trading is closed",
+    "market is closed",
+    "market has closed",
+    "market is resolved",
+    "resolved market",
+];
+
+/// Manifold's explanation of a failed request, e.g.
+/// `{"message": "Insufficient balance."}`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiError {
+    pub message: String,
+    /// Validation errors and the like
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
 
 #[derive(Debug)]
-pub enum ReqwestResponseParsing {
-    APIGeneric(String),
-    ReqwestError(reqwest::Error),
-    SerdeError(serde_json::Error),
+pub enum Error {
+    /// The API answered with an error status we don't have a variant for
+    Http {
+        status: u16,
+        body: String,
+    },
+    /// The request didn't get an answer: couldn't connect, timed out, etc.
+    Transport(reqwest::Error),
+    /// Still being rate limited after all our retries
+    RateLimited {
+        endpoint: String,
+        retry_after: Option<Duration>,
+    },
+    InsufficientBalance(ApiError),
+    MarketClosed(ApiError),
+    /// A 400: the request was malformed, e.g. an invalid `answerId`
+    BadRequest(ApiError),
+    /// We refused to send the request
+    Risk(RiskRejection),
+    /// Bad JSON, or not the shape we expected. `snippet` is the start of
+    /// the payload.
+    Parse {
+        error: serde_json::Error,
+        snippet: String,
+    },
+    /// The other end of a channel has gone away
+    ChannelClosed(String),
+    Io(std::io::Error),
+    Other(String),
 }
 
-impl fmt::Display for ReqwestResponseParsing {
+impl Error {
+    /// Classifies an error response from the API by status and Manifold's
+    /// message. Only client errors are classified by message: a 5xx is a
+    /// 5xx whatever it says. Closed markets are a 403 with one of
+    /// Manifold's messages for them.
+    pub fn from_response(status: u16, body: String) -> Self {
+        if status == StatusCode::TOO_MANY_REQUESTS.as_u16() {
+            return Error::RateLimited {
+                endpoint: String::new(),
+                retry_after: None,
+            };
+        }
+
+        let api_error = match status {
+            400..=499 => serde_json::from_str::<ApiError>(&body).ok(),
+            _ => None,
+        };
+        let message = api_error
+            .as_ref()
+            .map(|e| e.message.to_lowercase())
+            .unwrap_or_default();
+
+        match api_error {
+            Some(api_error) if message.contains("insufficient balance") => {
+                Error::InsufficientBalance(api_error)
+            }
+            Some(api_error)
+                if status == StatusCode::FORBIDDEN.as_u16()
+                    && MARKET_CLOSED_MESSAGES.iter().any(|m| message.contains(m)) =>
+            {
+                Error::MarketClosed(api_error)
+            }
+            Some(api_error) if status == StatusCode::BAD_REQUEST.as_u16() => {
+                Error::BadRequest(api_error)
+            }
+            _ => Error::Http { status, body },
+        }
+    }
+
+    /// An error for `payload` not parsing
+    pub fn parse(error: serde_json::Error, payload: &str) -> Self {
+        let snippet = match payload.char_indices().nth(SNIPPET_LEN) {
+            Some((end, _)) => format!("{}...", &payload[..end]),
+            None => payload.to_string(),
+        };
+
+        Error::Parse { error, snippet }
+    }
+
+    /// Manifold's explanation, if the API gave one
+    pub fn api_error(&self) -> Option<ApiError> {
+        match self {
+            Error::InsufficientBalance(e) | Error::MarketClosed(e) | Error::BadRequest(e) => {
+                Some(e.clone())
+            }
+            Error::Http { body, .. } => serde_json::from_str(body).ok(),
+            _ => None,
+        }
+    }
+
+    /// The HTTP status, if the API answered
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::Http { status, .. } => Some(*status),
+            Error::RateLimited { .. } => Some(StatusCode::TOO_MANY_REQUESTS.as_u16()),
+            Error::Transport(e) => e.status().map(|s| s.as_u16()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            ReqwestResponseParsing::APIGeneric(e) => write!(f, "APIGeneric error: {}", e),
-            ReqwestResponseParsing::ReqwestError(e) => write!(f, "Reqwest error: {}", e),
-            ReqwestResponseParsing::SerdeError(e) => write!(f, "Serde JSON error: {}", e),
+            Error::Http { status, body } => write!(f, "API error {status}: {body}"),
+            Error::Transport(e) => write!(f, "request failed: {e}"),
+            Error::RateLimited {
+                endpoint,
+                retry_after,
+            } => {
+                write!(f, "rate limited on {endpoint}")?;
+                match retry_after {
+                    Some(after) => write!(f, ", retry after {after:?}"),
+                    None => Ok(()),
+                }
+            }
+            Error::InsufficientBalance(e) => write!(f, "insufficient balance: {}", e.message),
+            Error::MarketClosed(e) => write!(f, "market closed: {}", e.message),
+            Error::BadRequest(e) => write!(f, "bad request: {}", e.message),
+            Error::Risk(rejection) => write!(f, "rejected: {rejection}"),
+            Error::Parse { error, snippet } => {
+                write!(f, "couldn't parse {snippet:?}: {error}")
+            }
+            Error::ChannelClosed(channel) => write!(f, "{channel} channel closed"),
+            Error::Io(e) => write!(f, "io error: {e}"),
+            Error::Other(e) => write!(f, "{e}"),
         }
     }
 }
 
-impl Error for ReqwestResponseParsing {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(e) => Some(e),
+            Error::Parse { error, .. } => Some(error),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-impl From<String> for ReqwestResponseParsing {
+/// What went wrong with a packet, as sent back to the bot that sent it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ApiFailure {
+    InsufficientBalance(ApiError),
+    MarketClosed(ApiError),
+    BadRequest(ApiError),
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// Any other error status, with Manifold's explanation if it gave one
+    Http {
+        status: u16,
+        error: Option<ApiError>,
+    },
+    /// We didn't hear back, or couldn't make sense of what we heard
+    Other(String),
+}
+
+impl From<&Error> for ApiFailure {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::InsufficientBalance(e) => ApiFailure::InsufficientBalance(e.clone()),
+            Error::MarketClosed(e) => ApiFailure::MarketClosed(e.clone()),
+            Error::BadRequest(e) => ApiFailure::BadRequest(e.clone()),
+            Error::RateLimited { retry_after, .. } => ApiFailure::RateLimited {
+                retry_after: *retry_after,
+            },
+            Error::Http { status, .. } => ApiFailure::Http {
+                status: *status,
+                error: error.api_error(),
+            },
+            e => ApiFailure::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<String> for Error {
     fn from(error: String) -> Self {
-        ReqwestResponseParsing::APIGeneric(error)
+        Error::Other(error)
     }
 }
 
-impl From<reqwest::Error> for ReqwestResponseParsing {
+/// Status errors from reqwest don't have the body, so only the status is
+/// kept
+impl From<reqwest::Error> for Error {
     fn from(error: reqwest::Error) -> Self {
-        ReqwestResponseParsing::ReqwestError(error)
+        let endpoint = error
+            .url()
+            .map(|url| url.path().to_string())
+            .unwrap_or_default();
+
+        match error.status() {
+            Some(StatusCode::TOO_MANY_REQUESTS) => Error::RateLimited {
+                endpoint,
+                retry_after: None,
+            },
+            Some(status) => Error::Http {
+                status: status.as_u16(),
+                body: String::new(),
+            },
+            None => Error::Transport(error),
+        }
     }
 }
 
-impl From<serde_json::Error> for ReqwestResponseParsing {
+impl From<serde_json::Error> for Error {
     fn from(error: serde_json::Error) -> Self {
-        ReqwestResponseParsing::SerdeError(error)
+        Error::Parse {
+            error,
+            snippet: String::new(),
+        }
+    }
+}
+
+impl From<RiskRejection> for Error {
+    fn from(rejection: RiskRejection) -> Self {
+        Error::Risk(rejection)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_response() {
+        let error =
+            Error::from_response(403, r#"{"message": "Insufficient balance."}"#.to_string());
+        assert!(
+            matches!(&error, Error::InsufficientBalance(e) if e.message == "Insufficient balance.")
+        );
+
+        let error = Error::from_response(403, r#"{"message": "Trading is closed."}"#.to_string());
+        assert!(matches!(error, Error::MarketClosed(_)));
+        let body = r#"{"message": "Cannot bet on a resolved market"}"#;
+        let error = Error::from_response(403, body.to_string());
+        assert!(matches!(error, Error::MarketClosed(_)));
+
+        // closed or resolved things that aren't markets
+        let error = Error::from_response(400, r#"{"message": "Connection closed"}"#.to_string());
+        assert!(matches!(error, Error::BadRequest(_)));
+        let error = Error::from_response(403, r#"{"message": "Comment resolved"}"#.to_string());
+        assert!(matches!(error, Error::Http { status: 403, .. }));
+        let error = Error::from_response(404, r#"{"message": "Market is closed"}"#.to_string());
+        assert!(matches!(error, Error::Http { status: 404, .. }));
+
+        let body = r#"{"message": "Invalid answerId", "details": [{"path": ["answerId"]}]}"#;
+        let error = Error::from_response(400, body.to_string());
+        assert!(matches!(error, Error::BadRequest(_)));
+        assert_eq!(error.api_error().unwrap().message, "Invalid answerId");
+
+        let error = Error::from_response(404, r#"{"message": "Contract not found"}"#.to_string());
+        assert!(matches!(error, Error::Http { status: 404, .. }));
+        assert_eq!(
+            ApiFailure::from(&error),
+            ApiFailure::Http {
+                status: 404,
+                error: Some(ApiError {
+                    message: "Contract not found".to_string(),
+                    details: None
+                })
+            }
+        );
+
+        // a 5xx is worth retrying, whatever it says
+        let error = Error::from_response(503, r#"{"message": "Connection closed"}"#.to_string());
+        assert!(matches!(error, Error::Http { status: 503, .. }));
+
+        let error = Error::from_response(502, "<html>Bad Gateway</html>".to_string());
+        assert!(error.api_error().is_none());
+        assert_eq!(error.status(), Some(502));
+    }
+
+    #[test]
+    fn test_parse_snippet() {
+        let payload = "x".repeat(500);
+        let error = serde_json::from_str::<Value>(&payload).unwrap_err();
+
+        match Error::parse(error, &payload) {
+            Error::Parse { snippet, .. } => assert_eq!(snippet.len(), SNIPPET_LEN + 3),
+            e => panic!("unexpected {e:?}"),
+        }
     }
 }
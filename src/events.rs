@@ -0,0 +1,592 @@
+/// One bus for everything the MarketHandler hears about: bets from every
+/// stream, market updates from the websocket, responses to bots' orders
+/// and balance changes. Bots subscribe with a filter for the markets,
+/// users, groups and kinds of event they care about.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "websocket")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use serde_json::Value;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::bet_stream as bs;
+use crate::internal_packet as ip;
+use crate::manifold_types as mt;
+#[cfg(feature = "websocket")]
+use crate::websocket as ws;
+
+/// How many limit orders to remember the owners of
+const ORDER_OWNERS_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    NewBet,
+    LimitOrderFilled,
+    MarketUpdated,
+    MarketResolved,
+    MarketClosed,
+    AnswerAdded,
+    OwnOrderAck,
+    BalanceChanged,
+    Gap,
+    Reconnected,
+}
+
+// NewBet is most of the traffic, so keep it unboxed
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum Event {
+    NewBet(mt::Bet),
+    /// A new bet matched against the limit order `order_id`. `amount` and
+    /// `shares` are the new bet's side of the fill.
+    LimitOrderFilled {
+        market_id: String,
+        order_id: String,
+        /// Who placed the order, if we saw it being placed
+        user_id: Option<String>,
+        bet_id: String,
+        amount: f64,
+        shares: f64,
+    },
+    /// Changed fields of the market, or of its answers
+    MarketUpdated {
+        market_id: String,
+        data: Value,
+    },
+    MarketResolved {
+        market_id: String,
+        resolution: Option<String>,
+    },
+    MarketClosed {
+        market_id: String,
+    },
+    AnswerAdded {
+        market_id: String,
+        data: Value,
+    },
+    /// The MarketHandler's response to a packet a bot sent
+    OwnOrderAck(ip::InternalPacket),
+    BalanceChanged {
+        user_id: String,
+        balance: f64,
+        previous: Option<f64>,
+    },
+    /// Events may have been missed, for one market or for everything
+    Gap {
+        market_id: Option<String>,
+        missed: Option<u64>,
+    },
+    /// A source of events recovered without missing any
+    Reconnected {
+        market_id: Option<String>,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::NewBet(_) => EventKind::NewBet,
+            Event::LimitOrderFilled { .. } => EventKind::LimitOrderFilled,
+            Event::MarketUpdated { .. } => EventKind::MarketUpdated,
+            Event::MarketResolved { .. } => EventKind::MarketResolved,
+            Event::MarketClosed { .. } => EventKind::MarketClosed,
+            Event::AnswerAdded { .. } => EventKind::AnswerAdded,
+            Event::OwnOrderAck(_) => EventKind::OwnOrderAck,
+            Event::BalanceChanged { .. } => EventKind::BalanceChanged,
+            Event::Gap { .. } => EventKind::Gap,
+            Event::Reconnected { .. } => EventKind::Reconnected,
+        }
+    }
+
+    pub fn market_id(&self) -> Option<String> {
+        match self {
+            Event::NewBet(bet) => Some(bet.contract_id.clone()),
+            Event::LimitOrderFilled { market_id, .. }
+            | Event::MarketUpdated { market_id, .. }
+            | Event::MarketResolved { market_id, .. }
+            | Event::MarketClosed { market_id }
+            | Event::AnswerAdded { market_id, .. } => Some(market_id.clone()),
+            Event::OwnOrderAck(packet) => packet.market_id(),
+            Event::BalanceChanged { .. } => None,
+            Event::Gap { market_id, .. } | Event::Reconnected { market_id } => market_id.clone(),
+        }
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        match self {
+            Event::NewBet(bet) => Some(&bet.user_id),
+            Event::LimitOrderFilled { user_id, .. } => user_id.as_deref(),
+            Event::BalanceChanged { user_id, .. } => Some(user_id),
+            _ => None,
+        }
+    }
+
+    /// Gaps and reconnects, which get past kind and user filters
+    fn is_stream_health(&self) -> bool {
+        matches!(self, Event::Gap { .. } | Event::Reconnected { .. })
+    }
+
+    /// The events a websocket update amounts to. `closed` has the markets
+    /// we've already said are closed, so that's only said once for each.
+    #[cfg(feature = "websocket")]
+    fn from_market_update(update: ws::MarketUpdate, closed: &mut HashSet<String>) -> Vec<Event> {
+        match update {
+            ws::MarketUpdate::Market { market_id, data } => {
+                let mut events = vec![];
+
+                if data["isResolved"] == true {
+                    events.push(Event::MarketResolved {
+                        market_id: market_id.clone(),
+                        resolution: data["resolution"].as_str().map(str::to_string),
+                    });
+                }
+
+                let now_ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("time went backwards")
+                    .as_millis() as i64;
+                let close_time = data["closeTime"].as_i64();
+                if close_time.is_some_and(|t| t > now_ms) {
+                    // reopened, so it can close again
+                    closed.remove(&market_id);
+                } else if close_time.is_some() && closed.insert(market_id.clone()) {
+                    events.push(Event::MarketClosed {
+                        market_id: market_id.clone(),
+                    });
+                }
+
+                events.push(Event::MarketUpdated { market_id, data });
+                events
+            }
+            ws::MarketUpdate::NewAnswer { market_id, data } => {
+                vec![Event::AnswerAdded { market_id, data }]
+            }
+            ws::MarketUpdate::UpdatedAnswers { market_id, data } => {
+                vec![Event::MarketUpdated { market_id, data }]
+            }
+        }
+    }
+}
+
+/// Which events a subscription gets. Each kind of condition that's set has
+/// to match; an empty filter matches everything.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub markets: HashSet<String>,
+    /// Matches markets in these groups, by slug
+    pub groups: HashSet<String>,
+    pub users: HashSet<String>,
+    /// Only acks for orders from these bots
+    pub bots: HashSet<String>,
+    pub kinds: HashSet<EventKind>,
+}
+
+impl EventFilter {
+    pub fn market(mut self, market_id: impl Into<String>) -> Self {
+        self.markets.insert(market_id.into());
+        self
+    }
+
+    pub fn group(mut self, slug: impl Into<String>) -> Self {
+        self.groups.insert(slug.into());
+        self
+    }
+
+    pub fn user(mut self, user_id: impl Into<String>) -> Self {
+        self.users.insert(user_id.into());
+        self
+    }
+
+    pub fn bot(mut self, bot_id: impl Into<String>) -> Self {
+        self.bots.insert(bot_id.into());
+        self
+    }
+
+    pub fn kind(mut self, kind: EventKind) -> Self {
+        self.kinds.insert(kind);
+        self
+    }
+
+    /// `market_groups` has the group slugs of each market we know about
+    pub fn matches(&self, event: &Event, market_groups: &HashMap<String, Vec<String>>) -> bool {
+        let health = event.is_stream_health();
+
+        if !self.markets.is_empty() || !self.groups.is_empty() {
+            let in_scope = match event.market_id() {
+                Some(market_id) => {
+                    self.markets.contains(&market_id)
+                        || market_groups
+                            .get(&market_id)
+                            .is_some_and(|groups| groups.iter().any(|g| self.groups.contains(g)))
+                }
+                // a gap in everything is a gap in every market
+                None => health,
+            };
+
+            if !in_scope {
+                return false;
+            }
+        }
+
+        if health {
+            return true;
+        }
+
+        if !self.kinds.is_empty() && !self.kinds.contains(&event.kind()) {
+            return false;
+        }
+
+        if !self.users.is_empty() && !event.user_id().is_some_and(|u| self.users.contains(u)) {
+            return false;
+        }
+
+        match event {
+            Event::OwnOrderAck(packet) if !self.bots.is_empty() => {
+                self.bots.contains(&packet.bot_id)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Who placed the limit orders we've seen, so their fills can go to users'
+/// subscriptions. Only the most recent are kept.
+#[derive(Default)]
+struct OrderOwners {
+    owners: HashMap<String, String>,
+    order: VecDeque<String>,
+}
+
+impl OrderOwners {
+    fn insert(&mut self, order_id: &str, user_id: &str) {
+        if self.owners.contains_key(order_id) {
+            return;
+        }
+
+        if self.order.len() >= ORDER_OWNERS_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.owners.remove(&oldest);
+            }
+        }
+
+        self.owners
+            .insert(order_id.to_string(), user_id.to_string());
+        self.order.push_back(order_id.to_string());
+    }
+
+    fn get(&self, order_id: &str) -> Option<String> {
+        self.owners.get(order_id).cloned()
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+    market_groups: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    /// Bets already published, as a bet can come from several streams
+    seen_bets: Arc<Mutex<bs::Seen>>,
+    balances: Arc<Mutex<HashMap<String, f64>>>,
+    order_owners: Arc<Mutex<OrderOwners>>,
+    /// Markets we've published `MarketClosed` for
+    #[cfg(feature = "websocket")]
+    closed_markets: Arc<Mutex<HashSet<String>>>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+
+        Self {
+            tx,
+            market_groups: Arc::new(Mutex::new(HashMap::new())),
+            seen_bets: Arc::new(Mutex::new(bs::Seen::default())),
+            balances: Arc::new(Mutex::new(HashMap::new())),
+            order_owners: Arc::new(Mutex::new(OrderOwners::default())),
+            #[cfg(feature = "websocket")]
+            closed_markets: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn subscribe(&self, filter: EventFilter) -> Subscription {
+        Subscription {
+            rx: self.tx.subscribe(),
+            filter,
+            market_groups: self.market_groups.clone(),
+        }
+    }
+
+    /// Lets group filters match the market
+    pub fn set_market_groups(&self, market_id: String, groups: Vec<String>) {
+        self.market_groups.lock().unwrap().insert(market_id, groups);
+    }
+
+    pub fn publish(&self, event: Event) {
+        // our own limit orders, which the market's stream may not have had
+        // yet when they fill
+        if let Event::OwnOrderAck(packet) = &event {
+            let order = packet
+                .response
+                .as_deref()
+                .and_then(|response| serde_json::from_str::<Value>(response).ok());
+            if let Some(order) = order.filter(|order| order["limitProb"].is_number()) {
+                if let (Some(id), Some(user_id)) = (order["id"].as_str(), order["userId"].as_str())
+                {
+                    self.order_owners.lock().unwrap().insert(id, user_id);
+                }
+            }
+        }
+
+        if self.tx.send(event).is_err() {
+            debug!("no subscribers for event");
+        }
+    }
+
+    /// Publishes a bet unless it's been published already, along with the
+    /// limit orders it filled
+    pub fn publish_bet(&self, bet: mt::Bet) {
+        if !self.seen_bets.lock().unwrap().insert(&bet.id) {
+            return;
+        }
+
+        if bet.limit_props.is_some() {
+            self.order_owners
+                .lock()
+                .unwrap()
+                .insert(&bet.id, &bet.user_id);
+        }
+
+        let fills = bet.limit_props.iter().flat_map(|props| props.fills.iter());
+        for fill in fills {
+            if let Some(order_id) = &fill.matched_bet_id {
+                let user_id = self.order_owners.lock().unwrap().get(order_id);
+                self.publish(Event::LimitOrderFilled {
+                    market_id: bet.contract_id.clone(),
+                    order_id: order_id.clone(),
+                    user_id,
+                    bet_id: bet.id.clone(),
+                    amount: fill.amount,
+                    shares: fill.shares,
+                });
+            }
+        }
+
+        self.publish(Event::NewBet(bet));
+    }
+
+    /// Publishes `balance` if it's changed since the last one for the user
+    pub fn publish_balance(&self, user_id: &str, balance: f64) {
+        let previous = self
+            .balances
+            .lock()
+            .unwrap()
+            .insert(user_id.to_string(), balance);
+
+        if previous != Some(balance) {
+            self.publish(Event::BalanceChanged {
+                user_id: user_id.to_string(),
+                balance,
+                previous,
+            });
+        }
+    }
+
+    /// Publishes everything from a bet stream, until it closes. `market_id`
+    /// is the stream's market, if it's a single market's bets.
+    pub fn forward_bets(&self, market_id: Option<String>, mut stream: bs::BetStream) {
+        let bus = self.clone();
+
+        tokio::spawn(async move {
+            while let Some(event) = stream.recv().await {
+                match event {
+                    bs::BetStreamEvent::Bet(bet) => bus.publish_bet(bet),
+                    bs::BetStreamEvent::Gap { missed } => bus.publish(Event::Gap {
+                        market_id: market_id.clone(),
+                        missed,
+                    }),
+                    bs::BetStreamEvent::Reconnected => bus.publish(Event::Reconnected {
+                        market_id: market_id.clone(),
+                    }),
+                }
+            }
+        });
+    }
+
+    /// Publishes market and answer updates from the websocket
+    #[cfg(feature = "websocket")]
+    pub fn forward_market_updates(&self, mut updates: broadcast::Receiver<ws::MarketUpdate>) {
+        let bus = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match updates.recv().await {
+                    Ok(update) => {
+                        let events = Event::from_market_update(
+                            update,
+                            &mut bus.closed_markets.lock().unwrap(),
+                        );
+                        for event in events {
+                            bus.publish(event);
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => bus.publish(Event::Gap {
+                        market_id: None,
+                        missed: Some(missed),
+                    }),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+/// Events from the bus that match a filter
+pub struct Subscription {
+    rx: broadcast::Receiver<Event>,
+    filter: EventFilter,
+    market_groups: Arc<Mutex<HashMap<String, Vec<String>>>>,
+}
+
+impl Subscription {
+    /// The next matching event, or None once the bus is gone. Falling too
+    /// far behind skips the oldest events, which shows up as a `Gap`.
+    pub async fn recv(&mut self) -> Option<Event> {
+        loop {
+            let event = match self.rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    return Some(Event::Gap {
+                        market_id: None,
+                        missed: Some(missed),
+                    })
+                }
+                Err(RecvError::Closed) => return None,
+            };
+
+            if self
+                .filter
+                .matches(&event, &self.market_groups.lock().unwrap())
+            {
+                return Some(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn bet(id: &str, market_id: &str, user_id: &str) -> mt::Bet {
+        testing::bet(id).market(market_id).user(user_id).build()
+    }
+
+    #[test]
+    fn test_filter() {
+        let groups = HashMap::from([("m1".to_string(), vec!["politics".to_string()])]);
+        let new_bet = Event::NewBet(bet("b", "m1", "u1"));
+        let gap = Event::Gap {
+            market_id: Some("m1".to_string()),
+            missed: None,
+        };
+        let balance = Event::BalanceChanged {
+            user_id: "u1".to_string(),
+            balance: 10.0,
+            previous: None,
+        };
+
+        assert!(EventFilter::default().matches(&new_bet, &groups));
+        assert!(EventFilter::default()
+            .market("m1")
+            .matches(&new_bet, &groups));
+        assert!(!EventFilter::default()
+            .market("m2")
+            .matches(&new_bet, &groups));
+        assert!(EventFilter::default()
+            .group("politics")
+            .matches(&new_bet, &groups));
+        assert!(!EventFilter::default()
+            .market("m1")
+            .matches(&balance, &groups));
+        assert!(EventFilter::default().user("u1").matches(&balance, &groups));
+        assert!(!EventFilter::default().user("u2").matches(&new_bet, &groups));
+
+        let resolutions = EventFilter::default()
+            .market("m1")
+            .kind(EventKind::MarketResolved);
+        assert!(!resolutions.matches(&new_bet, &groups));
+        // gaps get through kind filters, so subscribers know to resync
+        assert!(resolutions.matches(&gap, &groups));
+
+        // and a gap in everything gets through market filters too
+        let gap_everywhere = Event::Gap {
+            market_id: None,
+            missed: Some(3),
+        };
+        assert!(resolutions.matches(&gap_everywhere, &groups));
+        assert!(EventFilter::default()
+            .group("politics")
+            .matches(&gap_everywhere, &groups));
+        assert!(!EventFilter::default().market("m2").matches(&gap, &groups));
+    }
+
+    #[tokio::test]
+    async fn test_bus() {
+        let bus = EventBus::new(16);
+        let mut bets = bus.subscribe(EventFilter::default().kind(EventKind::NewBet));
+        let mut fills = bus.subscribe(
+            EventFilter::default()
+                .kind(EventKind::LimitOrderFilled)
+                .user("maker"),
+        );
+
+        let order = testing::bet("order").user("maker").limit(20.0, 0.4);
+        bus.publish_bet(order.build());
+
+        let taker = testing::bet("b1")
+            .limit(10.0, 0.6)
+            .fill(Some("order"), 4.0, 8.0)
+            .fill(None, 6.0, 12.0);
+
+        bus.publish_bet(taker.build());
+        bus.publish_bet(bet("b1", "m", "u"));
+        bus.publish_bet(bet("b2", "m", "u"));
+
+        assert!(matches!(bets.recv().await, Some(Event::NewBet(b)) if b.id == "order"));
+        assert!(matches!(bets.recv().await, Some(Event::NewBet(b)) if b.id == "b1"));
+        assert!(matches!(bets.recv().await, Some(Event::NewBet(b)) if b.id == "b2"));
+        assert!(matches!(
+            fills.recv().await,
+            Some(Event::LimitOrderFilled { order_id, amount, .. }) if order_id == "order" && amount == 4.0
+        ));
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn test_market_closed_once() {
+        let update = |close_time: i64| ws::MarketUpdate::Market {
+            market_id: "m".to_string(),
+            data: serde_json::json!({"closeTime": close_time}),
+        };
+        let closes = |events: Vec<Event>| {
+            events
+                .iter()
+                .filter(|e| e.kind() == EventKind::MarketClosed)
+                .count()
+        };
+        let mut closed = HashSet::new();
+
+        assert_eq!(closes(Event::from_market_update(update(1), &mut closed)), 1);
+        assert_eq!(closes(Event::from_market_update(update(1), &mut closed)), 0);
+
+        // reopened, then closed again
+        let later = i64::MAX;
+        assert_eq!(
+            closes(Event::from_market_update(update(later), &mut closed)),
+            0
+        );
+        assert_eq!(closes(Event::from_market_update(update(2), &mut closed)), 1);
+    }
+}
@@ -1,7 +1,14 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+use crate::errors::ApiFailure;
+use crate::risk::RiskRejection;
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum Method {
     Get,
     Post,
@@ -9,12 +16,19 @@ pub enum Method {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InternalPacket {
+    /// Unique to each new packet, and echoed in the response to it
+    #[serde(default)]
+    pub request_id: u64,
     pub bot_id: String,
     pub method: Method,
     pub endpoint: String,
     pub query_params: Vec<(String, String)>,
     pub data: Option<Value>,
     pub response: Option<String>,
+    /// Set instead of `response` when the MarketHandler refused to send the packet
+    pub rejection: Option<RiskRejection>,
+    /// Set instead of `response` when the API call failed
+    pub error: Option<ApiFailure>,
 }
 
 impl InternalPacket {
@@ -26,23 +40,78 @@ impl InternalPacket {
         data: Option<Value>,
     ) -> Self {
         Self {
+            request_id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
             bot_id,
             method,
             endpoint,
             query_params,
             data,
             response: None,
+            rejection: None,
+            error: None,
         }
     }
 
     pub fn response_from_existing(packet: &InternalPacket, response: String) -> Self {
         Self {
+            request_id: packet.request_id,
             bot_id: packet.bot_id.clone(),
-            method: packet.method.clone(),
+            method: packet.method,
             endpoint: packet.endpoint.clone(),
             query_params: packet.query_params.clone(),
             data: packet.data.clone(),
             response: Some(response),
+            rejection: None,
+            error: None,
+        }
+    }
+
+    pub fn rejection_from_existing(packet: &InternalPacket, rejection: RiskRejection) -> Self {
+        Self {
+            request_id: packet.request_id,
+            bot_id: packet.bot_id.clone(),
+            method: packet.method,
+            endpoint: packet.endpoint.clone(),
+            query_params: packet.query_params.clone(),
+            data: packet.data.clone(),
+            response: None,
+            rejection: Some(rejection),
+            error: None,
+        }
+    }
+
+    pub fn error_from_existing(packet: &InternalPacket, error: ApiFailure) -> Self {
+        Self {
+            request_id: packet.request_id,
+            bot_id: packet.bot_id.clone(),
+            method: packet.method,
+            endpoint: packet.endpoint.clone(),
+            query_params: packet.query_params.clone(),
+            data: packet.data.clone(),
+            response: None,
+            rejection: None,
+            error: Some(error),
+        }
+    }
+}
+
+impl InternalPacket {
+    /// The market this packet trades in, if any - either the `contractId`
+    /// of a bet, or the id in a `market/{id}/...` endpoint
+    pub fn market_id(&self) -> Option<String> {
+        if let Some(contract_id) = self
+            .data
+            .as_ref()
+            .and_then(|data| data.get("contractId"))
+            .and_then(|id| id.as_str())
+        {
+            return Some(contract_id.to_string());
+        }
+
+        let mut parts = self.endpoint.split('/');
+        match (parts.next(), parts.next()) {
+            (Some("market"), Some(id)) => Some(id.to_string()),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,28 @@
+//! Talking to Manifold and trading on it: API types, a rate limited client,
+//! the `MarketHandler` that bots trade through, and, depending on features,
+//! bet streams, the bots themselves and helpers for testing them. The `mmm`
+//! binary is a CLI on top.
+pub mod allocator;
+pub mod api;
+pub mod bet_stream;
+#[cfg(feature = "bots")]
+pub mod bots;
+pub mod circuit_breaker;
+pub mod coms;
+pub mod errors;
+pub mod events;
+pub mod internal_packet;
+pub mod liquidation;
+pub mod manifold_types;
+pub mod market_handler;
+#[cfg(feature = "polling")]
+pub mod poll_scheduler;
+pub mod rate_limiter;
+mod retry;
+pub mod risk;
+#[cfg(feature = "bots")]
+pub mod supervisor;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "websocket")]
+pub mod websocket;
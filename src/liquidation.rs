@@ -0,0 +1,186 @@
+use std::fmt;
+
+use crate::manifold_types as mt;
+
+/// Shares below this are treated as dust left over from float summation
+pub const MIN_SHARES: f64 = 1e-6;
+
+/// Which positions to liquidate, and how much of each
+#[derive(Debug, Clone)]
+pub struct LiquidationFilter {
+    /// Only liquidate positions in these markets. None means all markets
+    pub market_ids: Option<Vec<String>>,
+
+    /// Only sell this bot's holdings, as recorded by the allocator, rather
+    /// than the account's whole position
+    pub bot_id: Option<String>,
+
+    /// Fraction of each position to sell, in (0, 1]
+    pub fraction: f64,
+}
+
+impl Default for LiquidationFilter {
+    fn default() -> Self {
+        Self {
+            market_ids: None,
+            bot_id: None,
+            fraction: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LiquidationOptions {
+    /// Don't sell anything, just report what would be sold
+    pub dry_run: bool,
+
+    /// Number of times a sell that failed transiently (timeouts, 5xx, rate
+    /// limits) is retried before giving up
+    pub max_retries: u32,
+}
+
+impl Default for LiquidationOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            max_retries: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LiquidationOutcome {
+    DryRun,
+    Sold {
+        attempts: u32,
+        /// Mana received, as reported by the sell response
+        proceeds: Option<f64>,
+    },
+    Failed {
+        attempts: u32,
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct LiquidationEntry {
+    pub position: mt::Position,
+    pub shares_to_sell: f64,
+    /// shares * current probability of the outcome; ignores slippage
+    pub estimated_proceeds: Option<f64>,
+    pub outcome: LiquidationOutcome,
+}
+
+#[derive(Debug, Default)]
+pub struct LiquidationReport {
+    pub entries: Vec<LiquidationEntry>,
+}
+
+impl LiquidationReport {
+    pub fn estimated_proceeds(&self) -> f64 {
+        self.entries
+            .iter()
+            .filter_map(|e| e.estimated_proceeds)
+            .sum()
+    }
+
+    pub fn proceeds(&self) -> f64 {
+        self.entries
+            .iter()
+            .filter_map(|e| match e.outcome {
+                LiquidationOutcome::Sold { proceeds, .. } => proceeds,
+                _ => None,
+            })
+            .sum()
+    }
+
+    pub fn failed(&self) -> Vec<&LiquidationEntry> {
+        self.entries
+            .iter()
+            .filter(|e| matches!(e.outcome, LiquidationOutcome::Failed { .. }))
+            .collect()
+    }
+}
+
+impl fmt::Display for LiquidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            let estimate = entry
+                .estimated_proceeds
+                .map(|p| format!("{p:.2}"))
+                .unwrap_or("?".to_string());
+
+            let outcome = match &entry.outcome {
+                LiquidationOutcome::DryRun => "dry run".to_string(),
+                LiquidationOutcome::Sold { attempts, proceeds } => format!(
+                    "sold for {} ({attempts} attempts)",
+                    proceeds
+                        .map(|p| format!("{p:.2}"))
+                        .unwrap_or("?".to_string())
+                ),
+                LiquidationOutcome::Failed { attempts, error } => {
+                    format!("FAILED after {attempts} attempts: {error}")
+                }
+            };
+
+            writeln!(
+                f,
+                "sell {:.4} of {} | est. {estimate} | {outcome}",
+                entry.shares_to_sell, entry.position
+            )?;
+        }
+
+        write!(
+            f,
+            "{} positions | est. proceeds {:.2} | proceeds {:.2} | {} failed",
+            self.entries.len(),
+            self.estimated_proceeds(),
+            self.proceeds(),
+            self.failed().len()
+        )
+    }
+}
+
+/// The JSON body for `market/{id}/sell`. When selling the account's whole
+/// position (`sell_all`) we leave out `shares` so the API sells exactly
+/// what we hold, rather than failing on float dust from summing bets.
+pub fn sell_body(position: &mt::Position, shares: f64, sell_all: bool) -> serde_json::Value {
+    let mut body = serde_json::json!({ "outcome": position.outcome });
+
+    if let Some(answer_id) = &position.answer_id {
+        body["answerId"] = serde_json::json!(answer_id);
+    }
+
+    if !sell_all {
+        body["shares"] = serde_json::json!(shares);
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(outcome: &str, answer_id: Option<&str>) -> mt::Position {
+        mt::Position {
+            outcome: outcome.to_string(),
+            contract_id: "c".to_string(),
+            answer_id: answer_id.map(|s| s.to_string()),
+            amount: 10.0,
+            shares: 20.0,
+        }
+    }
+
+    #[test]
+    fn test_sell_body_full() {
+        let body = sell_body(&position("NO", Some("a")), 20.0, true);
+        assert_eq!(body, serde_json::json!({"outcome": "NO", "answerId": "a"}));
+    }
+
+    #[test]
+    fn test_sell_body_partial() {
+        let body = sell_body(&position("YES", None), 5.0, false);
+        assert_eq!(body, serde_json::json!({"outcome": "YES", "shares": 5.0}));
+    }
+}
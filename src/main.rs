@@ -1,31 +1,56 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 use clap::Parser;
-use log::{error, info};
+use log::{error, info, warn};
+
+use mmm::bots::arb_bot::ArbitrageBot;
+use mmm::bots::ewma_bot::EWMABot;
+use mmm::{allocator, circuit_breaker, liquidation, manifold_types, market_handler, supervisor};
 
 use crate::cli::{Args, Commands};
 
-mod bots;
 mod cli;
-mod coms;
-mod errors;
-mod internal_packet;
-mod manifold_types;
-mod market_handler;
-mod rate_limiter;
-
-use crate::bots::arb_bot::ArbitrageBot;
-use crate::bots::ewma_bot::EWMABot;
-use crate::bots::Bot;
-
-async fn run() {
+mod position_report;
+
+fn new_market_handler(rate_limit_dir: Option<&Path>) -> market_handler::MarketHandler {
+    let market_handler = market_handler::MarketHandler::new();
+
+    if let Some(dir) = rate_limit_dir {
+        market_handler.persist_rate_limits(dir);
+    }
+
+    market_handler
+}
+
+async fn run(
+    halt_file: PathBuf,
+    ledger_file: PathBuf,
+    rate_limit_dir: Option<&Path>,
+    websocket: bool,
+) {
     info!("Starting!");
 
-    let mut market_handler = market_handler::MarketHandler::new();
+    let mut market_handler = new_market_handler(rate_limit_dir);
+    market_handler.persist_ledgers(ledger_file);
+    if websocket {
+        #[cfg(feature = "websocket")]
+        market_handler.use_websocket(mmm::websocket::WsConfig::default());
+        #[cfg(not(feature = "websocket"))]
+        warn!("built without websocket support, polling instead");
+    }
+    market_handler.circuit_breaker().persist_to(halt_file);
+    market_handler.log_equity_reports(Duration::from_secs(15 * 60));
 
     assert!(market_handler.check_alive().await, "Manifold API is down");
 
     let me = market_handler.whoami().await.expect("Failed to get me");
 
-    info!("Logged in as {} (balance {})", me.name, me.balance);
+    info!(
+        "Logged in as {} (balance {})",
+        me.lite_user.name, me.lite_user.balance
+    );
 
     let arb_market = {
         let market = market_handler.market_search(
@@ -62,40 +87,91 @@ async fn run() {
     info!("Found market {}", arb_market.lite_market.question);
     info!("Found market {}", sudoku_market.lite_market.question);
 
-    let (bot_to_mh_tx, arb_rx_bot) = market_handler
-        .internal_coms_init("bawt".to_string())
-        .await
-        .unwrap();
+    market_handler.set_budget("bawt".to_string(), allocator::Budget::Fixed(600.0));
+    market_handler.set_budget(
+        "ewma_bawt".to_string(),
+        allocator::Budget::FractionOfEquity(0.1),
+    );
 
-    let (_, ewma_rx_bot) = market_handler
-        .internal_coms_init("ewma_bawt".to_string())
-        .await
-        .unwrap();
+    for market_id in [&arb_market.lite_market.id, &sudoku_market.lite_market.id] {
+        if let Err(e) = market_handler.watch_market(market_id.clone()).await {
+            error!("couldn't watch market {market_id} {e}");
+            return;
+        }
+    }
 
-    let mut arb_bot = ArbitrageBot::new(
+    let mut supervisor = supervisor::Supervisor::new();
+    supervisor.add_bot(Box::new(ArbitrageBot::new(
         "bawt".to_string(),
         arb_market.clone(),
-        bot_to_mh_tx.clone(),
-        arb_rx_bot,
-    );
-    let mut ewma_bot = EWMABot::new(
+        market_handler.circuit_breaker(),
+    )));
+    supervisor.add_bot(Box::new(EWMABot::new(
         "ewma_bawt".to_string(),
-        bot_to_mh_tx.clone(),
-        ewma_rx_bot,
+        sudoku_market.lite_market.id.clone(),
+        market_handler.circuit_breaker(),
         0.4,
         0.7,
-    );
+    )));
+
+    let shutdown = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("couldn't listen for ctrl-c {e}");
+        }
+    };
+
+    for (bot_id, report) in supervisor.run(&mut market_handler, shutdown).await {
+        info!("flattened {bot_id}:\n{report}");
+    }
+
+    for equity in market_handler.bot_equity_report().await {
+        info!("{equity}");
+    }
+}
+
+async fn positions(
+    sort: position_report::SortKey,
+    descending: bool,
+    filter: position_report::RowFilter,
+    format: position_report::OutputFormat,
+    rate_limit_dir: Option<&Path>,
+) {
+    let market_handler = new_market_handler(rate_limit_dir);
+    let active_positions = market_handler
+        .get_positions()
+        .await
+        .expect("couldn't get positions");
+
+    if active_positions.is_empty() {
+        println!("no positions found");
+        return;
+    }
 
-    let arb_rx = market_handler
-        .get_bet_stream_for_market_id(arb_market.lite_market.id)
-        .await;
+    let mut markets = HashMap::new();
+    for position in &active_positions {
+        if markets.contains_key(&position.contract_id) {
+            continue;
+        }
+
+        let market = market_handler.get_market(&position.contract_id).await;
+        if let Err(e) = &market {
+            warn!("couldn't get market {}: {e}", position.contract_id);
+        }
+        markets.insert(position.contract_id.clone(), market.ok());
+    }
+
+    let mut rows = active_positions
+        .iter()
+        .map(|position| {
+            let market = markets[&position.contract_id].as_ref();
+            position_report::PositionRow::new(position, market)
+        })
+        .filter(|row| filter.matches(row))
+        .collect::<Vec<position_report::PositionRow>>();
 
-    let ewma_rx = market_handler
-        .get_bet_stream_for_market_id(sudoku_market.lite_market.id)
-        .await;
+    position_report::sort_rows(&mut rows, sort, descending);
 
-    arb_bot.run(arb_rx).await;
-    ewma_bot.run(ewma_rx).await;
+    println!("{}", position_report::render(&rows, format));
 }
 
 #[tokio::main]
@@ -105,36 +181,79 @@ async fn main() {
     let args = Args::parse();
 
     match args.command {
-        Commands::Run => run().await,
-        Commands::Liquidate => {
-            let market_handler = market_handler::MarketHandler::new();
-            match market_handler.liquidate_all_positions().await {
-                Ok(_) => info!("All positions liquidated"),
-                Err(e) => error!("{e}"),
-            };
+        Commands::Run { websocket } => {
+            run(
+                args.halt_file,
+                args.ledger_file,
+                args.rate_limit_dir.as_deref(),
+                websocket,
+            )
+            .await
         }
-        Commands::Positions => {
-            let market_handler = market_handler::MarketHandler::new();
-            let all_my_bets = market_handler
-                .get_all_my_positions()
-                .await
-                .expect("couldn't get positions");
-
-            if all_my_bets.is_empty() {
-                println!("no positions found");
-                return;
+        Commands::Halt { bot, reason } => {
+            let breaker = circuit_breaker::CircuitBreaker::new(Default::default());
+            breaker.persist_to(args.halt_file);
+            breaker.trip(bot.as_deref(), circuit_breaker::TripReason::Manual(reason));
+            println!("{:?}", breaker.state());
+        }
+        Commands::Resume { bot } => {
+            let breaker = circuit_breaker::CircuitBreaker::new(Default::default());
+            breaker.persist_to(args.halt_file);
+            breaker.resume(bot.as_deref());
+            println!("{:?}", breaker.state());
+        }
+        Commands::Liquidate {
+            dry_run,
+            markets,
+            bot,
+            percent,
+            retries,
+        } => {
+            let market_handler = new_market_handler(args.rate_limit_dir.as_deref());
+            if bot.is_some() {
+                market_handler.persist_ledgers(args.ledger_file);
             }
 
-            let mut active_positions =
-                market_handler::MarketHandler::get_active_positions(all_my_bets).await;
-
-            active_positions.sort_unstable_by_key(|position| {
-                (position.contract_id.clone(), position.answer_id.clone())
-            });
+            let filter = liquidation::LiquidationFilter {
+                market_ids: if markets.is_empty() {
+                    None
+                } else {
+                    Some(markets)
+                },
+                bot_id: bot,
+                fraction: percent / 100.0,
+            };
+            let options = liquidation::LiquidationOptions {
+                dry_run,
+                max_retries: retries,
+            };
 
-            for position in active_positions {
-                println!("{position}");
-            }
+            match market_handler.liquidate(&filter, &options).await {
+                Ok(report) => {
+                    println!("{report}");
+                    if report.failed().is_empty() {
+                        info!("Positions liquidated");
+                    } else {
+                        error!("{} positions failed to liquidate", report.failed().len());
+                    }
+                }
+                Err(e) => error!("{e}"),
+            };
+        }
+        Commands::Positions {
+            sort,
+            desc,
+            search,
+            outcome,
+            min_value,
+            format,
+        } => {
+            let filter = position_report::RowFilter {
+                search,
+                outcome,
+                min_value,
+            };
+            positions(sort, desc, filter, format, args.rate_limit_dir.as_deref()).await;
         }
     }
 }
@@ -1,11 +1,75 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Display;
 use std::hash::Hash;
 
+/// Keeps parsing working when the API adds things. Unknown fields go in the
+/// `extra` map of the struct they're found in (or of the struct it
+/// flattens), unknown enum values in the enum's `Unknown` variant, and
+/// each type with anything unknown gets one warning.
+mod drift {
+    use std::any::type_name;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use log::{debug, warn};
+    use serde::{Deserialize, Deserializer};
+    use serde_json::Value;
+
+    /// Types we've warned about
+    static WARNED: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    #[cfg(test)]
+    thread_local! {
+        /// Everything noticed on this thread, for tests to check
+        pub static NOTICED: std::cell::RefCell<Vec<String>> =
+            const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    /// Warns about `what` unless `T` has been warned about already. Returns
+    /// whether it warned.
+    pub fn notice<T>(what: String) -> bool {
+        let name = type_name::<T>().rsplit("::").next().unwrap_or_default();
+
+        #[cfg(test)]
+        NOTICED.with(|noticed| noticed.borrow_mut().push(format!("{name}: {what}")));
+
+        let mut warned = WARNED.lock().unwrap();
+        if warned.contains(&name) {
+            debug!("API drift in {name}: {what}");
+            return false;
+        }
+
+        warned.push(name);
+        warn!("API drift in {name}: {what}. Further drift in {name} is only logged at debug.");
+        true
+    }
+
+    pub fn fields<'de, T, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<String, Value>, D::Error> {
+        let extra = HashMap::<String, Value>::deserialize(deserializer)?;
+
+        if !extra.is_empty() {
+            let mut keys = extra.keys().cloned().collect::<Vec<String>>();
+            keys.sort();
+            notice::<T>(format!("unknown fields {}", keys.join(", ")));
+        }
+
+        Ok(extra)
+    }
+
+    pub fn variant<'de, T, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        notice::<T>(format!("unknown value {value}"));
+        Ok(value)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
-enum TimePeriod {
+pub enum TimePeriod {
     #[serde(rename = "daily")]
     Daily,
     #[serde(rename = "weekly")]
@@ -14,6 +78,8 @@ enum TimePeriod {
     Monthly,
     #[serde(rename = "allTime")]
     AllTime,
+    #[serde(untagged)]
+    Unknown(#[serde(deserialize_with = "drift::variant::<TimePeriod, _>")] String),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
@@ -45,8 +111,9 @@ pub enum Side {
     NoOp,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, Default)]
 pub enum MarketMechanism {
+    #[default]
     #[serde(rename = "cpmm-1")]
     Cpmm,
     #[serde(rename = "cpmm-multi-1")]
@@ -55,10 +122,13 @@ pub enum MarketMechanism {
     Dpm,
     #[serde(rename = "none")]
     None,
+    #[serde(untagged)]
+    Unknown(#[serde(deserialize_with = "drift::variant::<MarketMechanism, _>")] String),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq, Default)]
 pub enum MarketOutcomeType {
+    #[default]
     #[serde(rename = "BINARY")]
     Binary,
     #[serde(rename = "FREE_RESPONSE")]
@@ -75,86 +145,112 @@ pub enum MarketOutcomeType {
     Poll,
     #[serde(rename = "BOUNTIED_QUESTION")]
     BountiedQuestion,
+    #[serde(untagged)]
+    Unknown(#[serde(deserialize_with = "drift::variant::<MarketOutcomeType, _>")] String),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct User {
+/// A user, as returned by `users` and `user/[username]`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LiteUser {
     /// from <https://docs.manifold.markets/api#get-v0users>
     pub id: String,
 
     #[serde(rename = "createdTime")]
-    created_time: u64,
+    pub created_time: u64,
 
     pub name: String,
-    username: String,
+    pub username: String,
 
-    url: Option<String>,
+    pub url: Option<String>,
 
     #[serde(rename = "avatarUrl")]
-    avatar_url: String,
+    pub avatar_url: Option<String>,
 
-    bio: Option<String>,
+    pub bio: Option<String>,
 
     #[serde(rename = "bannerUrl")]
-    banner_url: Option<String>,
+    pub banner_url: Option<String>,
 
-    website: Option<String>,
+    pub website: Option<String>,
 
     #[serde(rename = "twitterHandle")]
-    twitter_handle: Option<String>,
+    pub twitter_handle: Option<String>,
 
     #[serde(rename = "discordHandle")]
-    discord_handle: Option<String>,
+    pub discord_handle: Option<String>,
 
     #[serde(rename = "isBot")]
-    is_bot: Option<bool>,
+    pub is_bot: Option<bool>,
 
     /// is in manifold team
     #[serde(rename = "isAdmin")]
-    is_admin: Option<bool>,
+    pub is_admin: Option<bool>,
 
     /// is trustworthy
     #[serde(rename = "isTrustworthy")]
-    is_trustworthy: Option<bool>,
+    pub is_trustworthy: Option<bool>,
 
     #[serde(rename = "isBannedFromPosting")]
-    is_banned_from_posting: Option<bool>,
+    pub is_banned_from_posting: Option<bool>,
 
     #[serde(rename = "userDeleted")]
-    user_deleted: Option<bool>,
+    pub user_deleted: Option<bool>,
 
     pub balance: f64,
 
     #[serde(rename = "totalDeposits")]
-    total_deposits: f64,
+    pub total_deposits: f64,
 
     #[serde(rename = "lastBetTime")]
-    last_bet_time: Option<u64>,
+    pub last_bet_time: Option<u64>,
 
+    /// Days in a row with a bet
     #[serde(rename = "currentBettingStreak")]
-    current_betting_streak: Option<u64>, // guessing here
+    pub current_betting_streak: Option<u64>,
+
+    /// Fields not listed above
+    #[serde(flatten, deserialize_with = "drift::fields::<LiteUser, _>")]
+    pub extra: HashMap<String, Value>,
+}
+
+/// The authenticated user, as returned by `me`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct User {
+    /// Also has the user's unknown fields in `extra`
+    #[serde(flatten)]
+    pub lite_user: LiteUser,
 
     #[serde(rename = "profitCached")]
-    profit_cached: HashMap<TimePeriod, f64>,
+    pub profit_cached: HashMap<TimePeriod, f64>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl User {
+    /// Profit over the last day, as cached by Manifold
+    pub fn daily_profit(&self) -> Option<f64> {
+        self.profit_cached.get(&TimePeriod::Daily).copied()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct LiteMarket {
     /// from <https://docs.manifold.markets/api#get-v0markets>
 
     /// Unique identifer for this market
     pub id: String,
 
+    #[serde(rename = "creatorId")]
+    pub creator_id: String,
+
     /// Attributes about the creator
     #[serde(rename = "creatorUsername")]
-    creator_username: String,
+    pub creator_username: String,
 
     /// The name of the creator
     #[serde(rename = "creatorName")]
-    creator_name: String,
+    pub creator_name: String,
 
     #[serde(rename = "creatorAvatarUrl")]
-    creator_avatar_url: Option<String>,
+    pub creator_avatar_url: Option<String>,
 
     /// Market attributes. All times are in milliseconds since epoch
 
@@ -163,11 +259,11 @@ pub struct LiteMarket {
     /// see https://manifold.markets/PlasmaBallin/will-the-trinity-test-ignite-the-at
     /// Just leave as Option<i64>
     #[serde(rename = "closeTime")]
-    close_time: Option<i64>,
+    pub close_time: Option<i64>,
 
     /// milliseconds since epoch
     #[serde(rename = "createdTime")]
-    created_time: u64,
+    pub created_time: u64,
 
     /// The question!
     pub question: String,
@@ -175,206 +271,383 @@ pub struct LiteMarket {
     /// Note: This url always points to <https://manifold.markets>, regardless of what instance the api is running on.
     /// This url includes the creator's username, but this doesn't need to be correct when constructing valid URLs.
     ///   i.e. <https://manifold.markets/Austin/test-market> is the same as <https://manifold.markets/foo/test-market>
-    url: String,
+    pub url: String,
+
+    pub slug: String,
 
     /// BINARY, FREE_RESPONSE, MULTIPLE_CHOICE, NUMERIC, or PSEUDO_NUMERIC
     #[serde(rename = "outcomeType")]
-    outcome_type: MarketOutcomeType,
+    pub outcome_type: MarketOutcomeType,
 
     /// dpm-2 or cpmm-1 or cpmm-multi-1
-    mechanism: MarketMechanism,
+    pub mechanism: MarketMechanism,
 
     /// current probability of the market
-    probability: Option<f64>,
+    pub probability: Option<f64>,
 
     /// For CPMM markets, the number of shares in the liquidity pool. For DPM markets,
     /// the amount of mana invested in each answer.
     // pool: Option<HashMap<MarketOutcome, f64>>,
     // ^^^^ MarketOutcome can be YES, NO, and 0..\d
     // Therefore we just do String, and we'll have to deal w/ decoding YES / NO at runtime :(
-    pool: Option<HashMap<String, f64>>,
+    pub pool: Option<HashMap<String, f64>>,
 
     /// CPMM markets only, probability constant in y^p * n^(1-p) = k
-    p: Option<f64>,
+    pub p: Option<f64>,
 
     /// CPMM markets only, the amount of mana deposited into the liquidity pool
-    #[serde(rename = "total_liquidity")]
-    total_liquidity: Option<f64>,
+    #[serde(rename = "totalLiquidity")]
+    pub total_liquidity: Option<f64>,
 
     /// PSEUDO_NUMERIC markets only, the current market value, which is mapped from
     /// probability using min, max, and isLogScale.
-    value: Option<f64>,
+    pub value: Option<f64>,
 
     /// PSEUDO_NUMERIC markets only, the minimum resolvable value
-    min: Option<f64>,
+    pub min: Option<f64>,
 
     /// PSEUDO_NUMERIC markets only, the maximum resolvable value
-    max: Option<f64>,
+    pub max: Option<f64>,
 
     /// PSEUDO_NUMERIC markets only, if true `number = (max - min + 1)^probability + minstart - 1`,
     /// otherwise `number = min + (max - min) * probability`
     #[serde(rename = "isLogScale")]
-    is_log_scale: Option<bool>,
+    pub is_log_scale: Option<bool>,
 
-    volume: f64,
+    pub volume: f64,
 
     #[serde(rename = "volume24Hours")]
-    volume_24_hours: f64,
+    pub volume_24_hours: f64,
 
     #[serde(rename = "isResolved")]
-    is_resolved: bool,
+    pub is_resolved: bool,
 
     #[serde(rename = "resolutionTime")]
-    resolution_time: Option<u64>,
+    pub resolution_time: Option<u64>,
 
-    resolution: Option<String>,
+    pub resolution: Option<String>,
 
     /// Used for BINARY markets resolved to MKT
     #[serde(rename = "resolutionProbability")]
-    resolution_probability: Option<f64>,
+    pub resolution_probability: Option<f64>,
 
     #[serde(rename = "uniqueBettorCount")]
-    unique_bettor_count: u64,
+    pub unique_bettor_count: u64,
 
     #[serde(rename = "lastUpdatedTime")]
-    last_updated_time: Option<u64>,
+    pub last_updated_time: Option<u64>,
 
     #[serde(rename = "lastBetTime")]
-    last_bet_time: Option<u64>,
+    pub last_bet_time: Option<u64>,
+
+    #[serde(rename = "lastCommentTime")]
+    pub last_comment_time: Option<u64>,
+
+    /// Fields not listed above
+    #[serde(flatten, deserialize_with = "drift::fields::<LiteMarket, _>")]
+    pub extra: HashMap<String, Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// An answer of a multiple choice or free response market
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Answer {
-    /// Guessing on this one
+    /// From https://github.com/manifoldmarkets/manifold/blob/main/common/src/answer.ts
     pub id: String,
 
     #[serde(rename = "createdTime")]
-    created_time: u64,
-
-    #[serde(rename = "avatarURL")]
-    avatar_url: Option<String>,
-
-    username: Option<String>,
-    number: Option<u32>,
-    name: Option<String>,
+    pub created_time: u64,
 
     #[serde(rename = "contractId")]
-    contract_id: String,
+    pub contract_id: String,
 
     pub text: String,
 
     #[serde(rename = "userId")]
-    user_id: String,
+    pub user_id: String,
+
     pub probability: f64,
+
+    /// cpmm-multi-1 only: position in the market's list of answers
+    pub index: Option<u32>,
+
+    /// cpmm-multi-1 only: shares in this answer's own YES/NO pool
+    #[serde(rename = "poolYes")]
+    pub pool_yes: Option<f64>,
+
+    #[serde(rename = "poolNo")]
+    pub pool_no: Option<f64>,
+
+    #[serde(rename = "totalLiquidity")]
+    pub total_liquidity: Option<f64>,
+
+    #[serde(rename = "subsidyPool")]
+    pub subsidy_pool: Option<f64>,
+
+    /// The "Other" answer, which takes the probability of answers added later
+    #[serde(rename = "isOther")]
+    pub is_other: Option<bool>,
+
+    pub color: Option<String>,
+
+    pub resolution: Option<String>,
+
+    #[serde(rename = "resolutionTime")]
+    pub resolution_time: Option<u64>,
+
+    #[serde(rename = "resolutionProbability")]
+    pub resolution_probability: Option<f64>,
+
+    #[serde(rename = "resolverId")]
+    pub resolver_id: Option<String>,
+
+    #[serde(rename = "probChanges")]
+    pub prob_changes: Option<ProbChanges>,
+
+    /// dpm-2 only
+    #[serde(rename = "avatarURL")]
+    pub avatar_url: Option<String>,
+
+    /// dpm-2 only
+    pub username: Option<String>,
+
+    /// dpm-2 only
+    pub number: Option<u32>,
+
+    /// dpm-2 only
+    pub name: Option<String>,
+
+    /// Fields not listed above
+    #[serde(flatten, deserialize_with = "drift::fields::<Answer, _>")]
+    pub extra: HashMap<String, Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// How much a probability moved over the last day, week and month
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProbChanges {
+    pub day: f64,
+    pub week: f64,
+    pub month: f64,
+}
+
+/// Rich text, as a tiptap document. See
+/// <https://tiptap.dev/guide/output#option-1-json>
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct JSONContent {
-    // Not dealing w/ this for now
-    // I don't even think it's useful
+    /// The node type, e.g. "doc", "paragraph" or "text"
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+
+    pub content: Option<Vec<JSONContent>>,
+
+    /// Text nodes only
+    pub text: Option<String>,
+
+    pub attrs: Option<Map<String, Value>>,
+
+    /// Formatting of a text node, e.g. bold or a link
+    pub marks: Option<Vec<JSONContent>>,
 }
 
+impl JSONContent {
+    /// The text of the document, without formatting. Block nodes are
+    /// separated by newlines.
+    pub fn plain_text(&self) -> String {
+        let mut text = self.text.clone().unwrap_or_default();
+
+        for node in self.content.iter().flatten() {
+            let is_block = node.text.is_none() && !text.is_empty();
+            if is_block {
+                text.push('\n');
+            }
+            text.push_str(&node.plain_text());
+        }
+
+        text
+    }
+}
+
+/// A market description: rich text, or plain text for some old markets
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Description {
+    Plain(String),
+    Rich(JSONContent),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct FullMarket {
+    /// Also has the full market's unknown fields in `extra`
     #[serde(flatten)]
     pub lite_market: LiteMarket,
 
-    /// dpm-2 markets only
+    /// Multiple choice and free response markets only
     pub answers: Option<Vec<Answer>>,
 
-    /// Rich text content. See https://tiptap.dev/guide/output#option-1-json
-    #[serde(skip_deserializing)]
-    description: Option<JSONContent>,
+    pub description: Option<Description>,
 
     /// string description without formatting, images, or embeds
     #[serde(rename = "textDescription")]
-    text_description: String,
+    pub text_description: String,
 
     /// groups which the market is a part of
     #[serde(rename = "groupSlugs")]
-    group_slugs: Option<Vec<String>>,
+    pub group_slugs: Option<Vec<String>>,
+
+    #[serde(rename = "coverImageUrl")]
+    pub cover_image_url: Option<String>,
 }
 
 /// A single position in a market
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ContractMetric {
     /// From Here https://docs.manifold.markets/api#get-v0marketmarketidpositions
 
     /// The contract ID
     #[serde(rename = "contractId")]
-    contract_id: String,
+    pub contract_id: String,
+
+    /// Set for multiple choice markets. A multiple choice market also has
+    /// a summary metric for the whole market with no answer id.
+    #[serde(rename = "answerId")]
+    pub answer_id: Option<String>,
 
     /// Includes day, week, month. Can be undefined.
-    from: Option<HashMap<String, PeriodMetric>>,
+    pub from: Option<HashMap<String, PeriodMetric>>,
 
     /// Indicates if there are no shares
     #[serde(rename = "hasNoShares")]
-    has_no_shares: bool,
+    pub has_no_shares: bool,
 
     /// Indicates if there are shares
     #[serde(rename = "hasShares")]
-    has_shares: bool,
+    pub has_shares: bool,
 
     /// Indicates if there are yes shares
     #[serde(rename = "hasYesShares")]
-    has_yes_shares: bool,
+    pub has_yes_shares: bool,
 
     /// Invested amount
-    invested: f64,
+    pub invested: f64,
 
     /// Loan amount
-    loan: f64,
+    pub loan: f64,
 
     /// Maximum shares outcome, can be null
     #[serde(rename = "maxSharesOutcome")]
-    max_shares_outcome: Option<String>,
+    pub max_shares_outcome: Option<String>,
 
     /// Payout amount
-    payout: f64,
+    pub payout: f64,
 
     /// Profit amount
-    profit: f64,
+    pub profit: f64,
 
     /// Profit percentage
     #[serde(rename = "profitPercent")]
-    profit_percent: f64,
+    pub profit_percent: f64,
 
     /// Total shares
     #[serde(rename = "totalShares")]
-    total_shares: HashMap<MarketOutcome, f64>,
+    pub total_shares: HashMap<MarketOutcome, f64>,
 
     /// User ID
     #[serde(rename = "userId")]
-    user_id: String,
+    pub user_id: String,
 
     /// User name
     #[serde(rename = "userName")]
-    user_name: String,
+    pub user_name: Option<String>,
 
     /// User avatar URL
     #[serde(rename = "userAvatarUrl")]
-    user_avatar_url: String,
+    pub user_avatar_url: Option<String>,
 
     /// Last bet time
     #[serde(rename = "lastBetTime")]
-    last_bet_time: u64,
+    pub last_bet_time: u64,
+
+    /// Fields not listed above
+    #[serde(flatten, deserialize_with = "drift::fields::<ContractMetric, _>")]
+    pub extra: HashMap<String, Value>,
 }
 
-/// Metrics for a specific period
+impl ContractMetric {
+    /// One position per outcome we hold shares in. The invested amount is
+    /// split between outcomes by share count, which only matters in the
+    /// rare case that shares in both outcomes haven't been redeemed yet.
+    pub fn positions(&self) -> Vec<Position> {
+        let total_shares: f64 = self.total_shares.values().filter(|s| **s > 0.0).sum();
+
+        self.total_shares
+            .iter()
+            .filter(|(_, shares)| **shares > 0.0)
+            .map(|(outcome, shares)| Position {
+                outcome: outcome.to_string(),
+                contract_id: self.contract_id.clone(),
+                answer_id: self.answer_id.clone(),
+                amount: self.invested * shares / total_shares,
+                shares: *shares,
+            })
+            .collect()
+    }
+}
+
+/// Response of `get-user-contract-metrics-with-contracts`. The contracts
+/// that come along with the metrics are in the internal (not API) format,
+/// so we don't parse them.
 #[derive(Serialize, Deserialize, Debug)]
+pub struct UserContractMetrics {
+    #[serde(rename = "metricsByContract")]
+    pub metrics_by_contract: HashMap<String, Vec<ContractMetric>>,
+}
+
+/// Response of `get-user-portfolio`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Portfolio {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+
+    /// Mark to market value of all open positions
+    #[serde(rename = "investmentValue")]
+    pub investment_value: f64,
+
+    pub balance: f64,
+
+    #[serde(rename = "totalDeposits")]
+    pub total_deposits: f64,
+
+    #[serde(rename = "loanTotal")]
+    pub loan_total: f64,
+
+    #[serde(rename = "dailyProfit")]
+    pub daily_profit: Option<f64>,
+
+    /// All time profit
+    pub profit: Option<f64>,
+
+    /// milliseconds since epoch
+    pub timestamp: u64,
+
+    /// Fields not listed above
+    #[serde(flatten, deserialize_with = "drift::fields::<Portfolio, _>")]
+    pub extra: HashMap<String, Value>,
+}
+
+/// Metrics for a specific period
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct PeriodMetric {
     /// Profit amount
-    profit: f64,
+    pub profit: f64,
     /// Profit percentage
     #[serde(rename = "profitPercent")]
-    profit_percent: f64,
+    pub profit_percent: f64,
     /// Invested amount
-    invested: f64,
+    pub invested: f64,
     /// Previous value
     #[serde(rename = "prevValue")]
-    prev_value: f64,
+    pub prev_value: f64,
     /// Current value
-    value: f64,
+    pub value: f64,
 }
 
 /// https://docs.manifold.markets/api#post-v0bet
@@ -401,23 +674,23 @@ pub struct BotBet {
 }
 
 /// Represents a bet
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Bet {
     /// From https://github.com/manifoldmarkets/manifold/blob/main/common/src/bet.ts
     pub id: String,
 
     #[serde(rename = "userId")]
-    user_id: String,
+    pub user_id: String,
 
     // denormalized for bet lists (whatever that means)
     #[serde(rename = "userAvatarUrl", skip_serializing_if = "Option::is_none")]
-    user_avatar_url: Option<String>,
+    pub user_avatar_url: Option<String>,
 
     #[serde(rename = "userName", skip_serializing_if = "Option::is_none")]
-    user_name: Option<String>,
+    pub user_name: Option<String>,
 
     #[serde(rename = "userUsername", skip_serializing_if = "Option::is_none")]
-    user_username: Option<String>,
+    pub user_username: Option<String>,
 
     #[serde(rename = "contractId")]
     pub contract_id: String,
@@ -427,24 +700,24 @@ pub struct Bet {
     pub answer_id: Option<String>,
 
     #[serde(rename = "createdTime")]
-    created_time: u64,
+    pub created_time: u64,
 
     /// Bet size; negative if SELL bet
     pub amount: f64,
 
     /// Optional loan amount
     #[serde(rename = "loanAmount", skip_serializing_if = "Option::is_none")]
-    loan_amount: Option<f64>,
+    pub loan_amount: Option<f64>,
 
     pub outcome: String,
 
     /// Dynamic parimutuel pool weight or fixed; negative if SELL bet
-    shares: f64,
+    pub shares: f64,
 
     /// Deprecated: Gain shares in multiple outcomes. Part of cpmm-2 multiple choice.
     #[deprecated(note = "Use alternative field")]
     #[serde(rename = "sharesByOutcome", skip_serializing_if = "Option::is_none")]
-    shares_by_outcome: Option<HashMap<String, f64>>,
+    pub shares_by_outcome: Option<HashMap<String, f64>>,
 
     #[serde(rename = "probBefore")]
     pub prob_before: f64,
@@ -452,33 +725,37 @@ pub struct Bet {
     #[serde(rename = "probAfter")]
     pub prob_after: f64,
 
-    fees: Fees,
+    pub fees: Fees,
 
     /// True if bet was placed via API. Optional.
     #[serde(rename = "isApi", skip_serializing_if = "Option::is_none")]
-    is_api: Option<bool>,
+    pub is_api: Option<bool>,
 
     #[serde(rename = "isAnte")]
-    is_ante: bool,
+    pub is_ante: bool,
 
     #[serde(rename = "isRedemption")]
-    is_redemption: bool,
+    pub is_redemption: bool,
 
     #[serde(rename = "isChallenge")]
-    is_challenge: bool,
+    pub is_challenge: bool,
 
-    visibility: Visibility,
+    pub visibility: Visibility,
 
     /// Optional challenge slug
     #[serde(rename = "challengeSlug", skip_serializing_if = "Option::is_none")]
-    challenge_slug: Option<String>,
+    pub challenge_slug: Option<String>,
 
     /// Optional reply to comment ID
     #[serde(rename = "replyToCommentId", skip_serializing_if = "Option::is_none")]
-    reply_to_comment_id: Option<String>,
+    pub reply_to_comment_id: Option<String>,
 
     #[serde(flatten)]
-    limit_props: Option<LimitProps>,
+    pub limit_props: Option<LimitProps>,
+
+    /// Fields not listed above
+    #[serde(flatten, deserialize_with = "drift::fields::<Bet, _>")]
+    pub extra: HashMap<String, Value>,
 }
 
 impl Display for Bet {
@@ -496,12 +773,36 @@ impl Display for Bet {
     }
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Position {
     pub outcome: String,
     pub contract_id: String,
     pub answer_id: Option<String>,
     pub amount: f64,
+    pub shares: f64,
+}
+
+impl Position {
+    /// Current probability of this position's outcome in the given market,
+    /// if we can figure it out. For multiple choice markets, this uses the
+    /// answer's probability; for binary markets the market's.
+    pub fn outcome_probability(&self, market: &FullMarket) -> Option<f64> {
+        let yes_prob = match &self.answer_id {
+            Some(answer_id) => market
+                .answers
+                .as_ref()?
+                .iter()
+                .find(|a| &a.id == answer_id)
+                .map(|a| a.probability)?,
+            None => market.lite_market.probability?,
+        };
+
+        match self.outcome.as_str() {
+            "YES" => Some(yes_prob),
+            "NO" => Some(1.0 - yes_prob),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Position {
@@ -514,90 +815,371 @@ impl Display for Position {
 
         write!(
             f,
-            "bet: {:.4} {} | contract id: {} {answer_id_str}",
+            "bet: {:.4} {} ({:.4} shares) | contract id: {} {answer_id_str}",
             self.amount.abs(),
             self.outcome,
+            self.shares,
             self.contract_id,
         )
     }
 }
 
-/// NumericBet extends Bet with additional fields
-#[derive(Serialize, Deserialize, Debug)]
+/// A bet on a NUMERIC market, which buys shares in a range of buckets
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NumericBet {
+    /// Also has the numeric bet's unknown fields in `extra`
     #[serde(flatten)]
-    bet: Bet,
-    value: f64,
+    pub bet: Bet,
+    pub value: f64,
     #[serde(rename = "allOutcomeShares")]
-    all_outcome_shares: HashMap<String, f64>,
+    pub all_outcome_shares: HashMap<String, f64>,
     #[serde(rename = "allBetAmounts")]
-    all_bet_amounts: HashMap<String, f64>,
+    pub all_bet_amounts: HashMap<String, f64>,
 }
 
-/// LimitBet is a Bet with LimitProps flattened into it
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LimitBet {
-    #[serde(flatten)]
-    bet: Bet,
-    #[serde(flatten)]
-    limit_props: LimitProps,
-}
-
-/// Properties specific to a limit bet
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Properties specific to a limit bet, which are flattened into its `Bet`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct LimitProps {
     /// Amount of mana in the order
     #[serde(rename = "orderAmount")]
-    order_amount: f64,
+    pub order_amount: f64,
     /// [0, 1]. Bet to this probability.
     #[serde(rename = "limitProb")]
-    limit_prob: f64,
+    pub limit_prob: f64,
     /// Whether all of the bet amount has been filled.
     #[serde(rename = "isFilled")]
-    is_filled: bool,
+    pub is_filled: bool,
     /// Whether to prevent any further fills.
     #[serde(rename = "isCancelled")]
-    is_cancelled: bool,
+    pub is_cancelled: bool,
     /// A record of each transaction that partially (or fully) fills the order amount.
-    fills: Vec<Fill>,
+    pub fills: Vec<Fill>,
     /// ms since epoch. Optional.
     #[serde(rename = "expiresAt", skip_serializing_if = "Option::is_none")]
-    expires_at: Option<u64>,
+    pub expires_at: Option<u64>,
 }
 
 /// Represents a fill in a bet
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Fill {
     /// The id the bet matched against, or null if the bet was matched by the pool.
     #[serde(rename = "matchedBetId")]
-    matched_bet_id: Option<String>,
+    pub matched_bet_id: Option<String>,
     /// Amount involved in the fill
-    amount: f64,
+    pub amount: f64,
     /// Shares involved in the fill
-    shares: f64,
+    pub shares: f64,
     /// Timestamp of the fill
-    timestamp: u64,
+    pub timestamp: u64,
+
+    /// Fields not listed above
+    #[serde(flatten, deserialize_with = "drift::fields::<Fill, _>")]
+    pub extra: HashMap<String, Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Fees {
     /// Fee for the creator
     #[serde(rename = "creatorFee")]
-    creator_fee: f64,
+    pub creator_fee: f64,
 
     /// Fee for the platform
     #[serde(rename = "platformFee")]
-    platform_fee: f64,
+    pub platform_fee: f64,
 
     /// Fee for liquidity
     #[serde(rename = "liquidityFee")]
-    liquidity_fee: f64,
+    pub liquidity_fee: f64,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum Visibility {
+    #[default]
     Public,
     Unlisted,
     Private,
+    #[serde(untagged)]
+    Unknown(#[serde(deserialize_with = "drift::variant::<Visibility, _>")] String),
+}
+
+/// A comment on a market
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Comment {
+    /// From https://github.com/manifoldmarkets/manifold/blob/main/common/src/comment.ts
+    pub id: String,
+
+    #[serde(rename = "contractId")]
+    pub contract_id: String,
+
+    #[serde(rename = "userId")]
+    pub user_id: String,
+
+    #[serde(rename = "userUsername")]
+    pub user_username: String,
+
+    #[serde(rename = "userName")]
+    pub user_name: String,
+
+    #[serde(rename = "userAvatarUrl", skip_serializing_if = "Option::is_none")]
+    pub user_avatar_url: Option<String>,
+
+    #[serde(rename = "createdTime")]
+    pub created_time: u64,
+
+    pub content: Option<JSONContent>,
+
+    #[serde(rename = "replyToCommentId", skip_serializing_if = "Option::is_none")]
+    pub reply_to_comment_id: Option<String>,
+
+    /// Set when the comment was made along with a bet
+    #[serde(rename = "betId", skip_serializing_if = "Option::is_none")]
+    pub bet_id: Option<String>,
+
+    #[serde(rename = "betAmount", skip_serializing_if = "Option::is_none")]
+    pub bet_amount: Option<f64>,
+
+    #[serde(rename = "betOutcome", skip_serializing_if = "Option::is_none")]
+    pub bet_outcome: Option<String>,
+
+    /// The answer commented on, in multiple choice markets
+    #[serde(rename = "answerOutcome", skip_serializing_if = "Option::is_none")]
+    pub answer_outcome: Option<String>,
+
+    #[serde(rename = "contractSlug", skip_serializing_if = "Option::is_none")]
+    pub contract_slug: Option<String>,
+
+    #[serde(rename = "contractQuestion", skip_serializing_if = "Option::is_none")]
+    pub contract_question: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub likes: Option<u64>,
+
+    #[serde(rename = "editedTime", skip_serializing_if = "Option::is_none")]
+    pub edited_time: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hidden: Option<bool>,
+
+    /// Fields not listed above
+    #[serde(flatten, deserialize_with = "drift::fields::<Comment, _>")]
+    pub extra: HashMap<String, Value>,
+}
+
+/// A group (topic) of markets
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Group {
+    /// From https://github.com/manifoldmarkets/manifold/blob/main/common/src/group.ts
+    pub id: String,
+
+    pub slug: String,
+
+    pub name: String,
+
+    #[serde(rename = "creatorId")]
+    pub creator_id: String,
+
+    #[serde(rename = "createdTime")]
+    pub created_time: u64,
+
+    #[serde(rename = "totalMembers", skip_serializing_if = "Option::is_none")]
+    pub total_members: Option<u64>,
+
+    /// public, curated or private
+    #[serde(rename = "privacyStatus", skip_serializing_if = "Option::is_none")]
+    pub privacy_status: Option<String>,
+
+    #[serde(rename = "importanceScore", skip_serializing_if = "Option::is_none")]
+    pub importance_score: Option<f64>,
+
+    #[serde(rename = "bannerUrl", skip_serializing_if = "Option::is_none")]
+    pub banner_url: Option<String>,
+
+    /// Fields not listed above
+    #[serde(flatten, deserialize_with = "drift::fields::<Group, _>")]
+    pub extra: HashMap<String, Value>,
+}
+
+/// Who or what sent or received a txn
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TxnParty {
+    User,
+    Contract,
+    Charity,
+    Bank,
+    Ad,
+    League,
+    #[serde(untagged)]
+    Unknown(#[serde(deserialize_with = "drift::variant::<TxnParty, _>")] String),
+}
+
+/// A transfer of mana, e.g. a managram, a market subsidy or a bonus
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Txn {
+    /// From https://github.com/manifoldmarkets/manifold/blob/main/common/src/txn.ts
+    pub id: String,
+
+    #[serde(rename = "createdTime")]
+    pub created_time: u64,
+
+    #[serde(rename = "fromId")]
+    pub from_id: String,
+
+    #[serde(rename = "fromType")]
+    pub from_type: TxnParty,
+
+    #[serde(rename = "toId")]
+    pub to_id: String,
+
+    #[serde(rename = "toType")]
+    pub to_type: TxnParty,
+
+    pub amount: f64,
+
+    /// "M$" for mana
+    pub token: String,
+
+    /// e.g. MANA_PAYMENT, ADD_SUBSIDY, BETTING_STREAK_BONUS
+    pub category: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// Depends on the category, e.g. the message of a managram
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+
+    /// Fields not listed above
+    #[serde(flatten, deserialize_with = "drift::fields::<Txn, _>")]
+    pub extra: HashMap<String, Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::DeserializeOwned;
+    use std::path::Path;
+
+    fn fixture(name: &str) -> Value {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name);
+        let text = std::fs::read_to_string(&path).expect("missing fixture");
+        serde_json::from_str(&text).expect("fixture isn't json")
+    }
+
+    /// Drops nulls and makes every number a float, since neither survives a
+    /// round trip
+    fn normalize(value: Value) -> Value {
+        match value {
+            Value::Object(map) => Value::Object(
+                map.into_iter()
+                    .filter(|(_, v)| !v.is_null())
+                    .map(|(k, v)| (k, normalize(v)))
+                    .collect(),
+            ),
+            Value::Array(values) => Value::Array(values.into_iter().map(normalize).collect()),
+            Value::Number(n) => serde_json::json!(n.as_f64()),
+            value => value,
+        }
+    }
+
+    /// Parses the fixture as a `T` and checks that nothing was lost or
+    /// unknown
+    fn round_trip<T: Serialize + DeserializeOwned>(name: &str) -> T {
+        let json = fixture(name);
+        drift::NOTICED.with(|noticed| noticed.borrow_mut().clear());
+        let parsed = serde_json::from_value::<T>(json.clone())
+            .unwrap_or_else(|e| panic!("couldn't parse {name}: {e}"));
+        drift::NOTICED.with(|noticed| assert_eq!(*noticed.borrow(), Vec::<String>::new()));
+
+        let back = serde_json::to_value(&parsed).unwrap();
+        assert_eq!(
+            normalize(back),
+            normalize(json),
+            "{name} changed in a round trip"
+        );
+
+        parsed
+    }
+
+    #[test]
+    fn test_fixtures_round_trip() {
+        let me = round_trip::<User>("me.json");
+        assert_eq!(me.daily_profit(), Some(14.2));
+        round_trip::<LiteUser>("user.json");
+
+        round_trip::<LiteMarket>("lite_market.json");
+        let binary = round_trip::<FullMarket>("market_binary.json");
+        assert_eq!(binary.lite_market.resolution.as_deref(), Some("NO"));
+
+        let multi = round_trip::<FullMarket>("market_multiple_choice.json");
+        let answers = multi.answers.unwrap();
+        assert_eq!(answers.len(), 3);
+        assert_eq!(answers[0].pool_yes, Some(120.4));
+        assert_eq!(answers[2].is_other, Some(true));
+
+        let numeric = round_trip::<FullMarket>("market_pseudo_numeric.json");
+        assert_eq!(
+            numeric.lite_market.outcome_type,
+            MarketOutcomeType::PseudoNumeric
+        );
+        assert_eq!(numeric.lite_market.is_log_scale, Some(true));
+
+        assert!(round_trip::<Bet>("bet.json").limit_props.is_none());
+        let limit = round_trip::<Bet>("limit_bet.json").limit_props.unwrap();
+        assert_eq!(limit.fills.len(), 2);
+        round_trip::<NumericBet>("numeric_bet.json");
+
+        round_trip::<Comment>("comment.json");
+        round_trip::<Group>("group.json");
+        let txn = round_trip::<Txn>("txn.json");
+        assert_eq!(txn.from_type, TxnParty::User);
+        round_trip::<Portfolio>("portfolio.json");
+        let metric = round_trip::<ContractMetric>("contract_metric.json");
+        assert_eq!(metric.positions()[0].outcome, "NO");
+    }
+
+    #[test]
+    fn test_descriptions() {
+        let binary = serde_json::from_value::<FullMarket>(fixture("market_binary.json")).unwrap();
+        match binary.description {
+            Some(Description::Rich(doc)) => assert_eq!(
+                doc.plain_text(),
+                "Resolves per the Met Office.\nA single flake counts."
+            ),
+            d => panic!("expected rich text, got {d:?}"),
+        }
+
+        let multi =
+            serde_json::from_value::<FullMarket>(fixture("market_multiple_choice.json")).unwrap();
+        assert!(matches!(multi.description, Some(Description::Plain(_))));
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut json = fixture("limit_bet.json");
+        json["visibility"] = serde_json::json!("friends");
+        json["betGroupId"] = serde_json::json!("g1");
+        json["fills"][0]["isSale"] = serde_json::json!(false);
+
+        drift::NOTICED.with(|noticed| noticed.borrow_mut().clear());
+        let bet = serde_json::from_value::<Bet>(json.clone()).unwrap();
+
+        assert!(matches!(&bet.visibility, Visibility::Unknown(v) if v == "friends"));
+        assert_eq!(bet.extra.keys().collect::<Vec<_>>(), vec!["betGroupId"]);
+        let fill = &bet.limit_props.as_ref().unwrap().fills[0];
+        assert_eq!(fill.extra["isSale"], serde_json::json!(false));
+        drift::NOTICED.with(|noticed| assert_eq!(noticed.borrow().len(), 3));
+
+        // unknown things are kept when sending it back
+        let back = serde_json::to_value(&bet).unwrap();
+        assert_eq!(normalize(back), normalize(json));
+
+        // one warning per type
+        struct Probe;
+        assert!(drift::notice::<Probe>("a".to_string()));
+        assert!(!drift::notice::<Probe>("b".to_string()));
+    }
 }
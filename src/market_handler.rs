@@ -1,19 +1,35 @@
 use std::collections::HashMap;
+#[cfg(any(feature = "polling", feature = "websocket"))]
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc, Mutex,
 };
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use log::{debug, error, info, warn};
 
 use tokio::sync::{broadcast, mpsc};
 use tokio::time::{sleep, Duration};
 
+use crate::allocator as al;
+use crate::api;
+use crate::bet_stream as bs;
+use crate::circuit_breaker as cb;
 use crate::coms;
 use crate::errors;
+use crate::events as ev;
 use crate::internal_packet as ip;
+use crate::liquidation as lq;
 use crate::manifold_types as mt;
+#[cfg(feature = "polling")]
+use crate::poll_scheduler as ps;
 use crate::rate_limiter as rl;
+use crate::retry;
+use crate::risk;
+#[cfg(feature = "websocket")]
+use crate::websocket as ws;
 
 pub struct MarketHandler {
     halt_flag: Arc<AtomicBool>,
@@ -21,67 +37,152 @@ pub struct MarketHandler {
     bots_to_mh_tx: mpsc::Sender<ip::InternalPacket>,
     bot_out_channel: Arc<Mutex<HashMap<String, broadcast::Sender<ip::InternalPacket>>>>,
 
-    read_rate_limiter: rl::RateLimiter,
-    write_rate_limiter: rl::RateLimiter,
+    client: coms::ManifoldClient,
+
+    #[cfg(any(feature = "polling", feature = "websocket"))]
+    bet_feeds: HashMap<String, bs::BetFeed>,
+    /// Everything bots can subscribe to
+    events: ev::EventBus,
+    /// Streams whose bets are published on `events`
+    #[cfg(any(feature = "polling", feature = "websocket"))]
+    watched_streams: HashSet<String>,
+    #[cfg(feature = "polling")]
+    poll_scheduler: ps::PollScheduler,
+    /// Streams market bets over the websocket when enabled, polling only
+    /// while it's disconnected
+    #[cfg(feature = "websocket")]
+    websocket: Option<ws::WsTransport>,
+
+    risk_manager: Arc<Mutex<risk::RiskManager>>,
+    circuit_breaker: cb::CircuitBreaker,
+    allocator: Arc<Mutex<al::CapitalAllocator>>,
+}
 
-    bet_channels: HashMap<String, broadcast::Sender<mt::Bet>>,
+impl Default for MarketHandler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[allow(dead_code)]
 impl MarketHandler {
     pub fn new() -> Self {
+        Self::with_client(coms::ManifoldClient::from_env().expect("couldn't build http client"))
+    }
+
+    /// Uses `client` for all API calls, e.g. one pointed at a mock server
+    pub fn with_client(client: coms::ManifoldClient) -> Self {
         let halt_flag = Arc::new(AtomicBool::new(false));
 
         let (bots_to_mh_tx, bots_to_mh_rx) = mpsc::channel::<ip::InternalPacket>(256);
         let bot_out_channel: Arc<Mutex<HashMap<String, broadcast::Sender<ip::InternalPacket>>>> =
             Arc::new(Mutex::new(HashMap::new()));
 
+        let risk_manager = Arc::new(Mutex::new(risk::RiskManager::new(
+            risk::RiskLimits::default(),
+        )));
+
+        let circuit_breaker = cb::CircuitBreaker::new(cb::BreakerConfig::default());
+        let allocator = Arc::new(Mutex::new(al::CapitalAllocator::new()));
+
+        let events = ev::EventBus::new(1024);
+
         let halt_flag_clone = halt_flag.clone();
         let bot_out_channel_clone = bot_out_channel.clone();
-
-        // set the rate limits slightly lower than the true value
-        let read_rate_limiter = rl::RateLimiter::new(90, Duration::from_secs(1));
-        let write_rate_limiter = rl::RateLimiter::new(9, Duration::from_secs(60));
+        let risk_manager_clone = risk_manager.clone();
 
         tokio::spawn(Self::handle_bot_messages(
-            write_rate_limiter.clone(),
-            read_rate_limiter.clone(),
+            client.clone(),
             halt_flag_clone,
             bots_to_mh_rx,
             bot_out_channel_clone,
+            risk_manager_clone,
+            circuit_breaker.clone(),
+            allocator.clone(),
+            events.clone(),
         ));
+        tokio::spawn(Self::track_order_fills(
+            events.subscribe(
+                ev::EventFilter::default()
+                    .kind(ev::EventKind::LimitOrderFilled)
+                    .kind(ev::EventKind::Gap),
+            ),
+            client.clone(),
+            allocator.clone(),
+        ));
+
+        #[cfg(feature = "polling")]
+        let poll_scheduler = ps::PollScheduler::new(ps::PollConfig::default());
+        #[cfg(feature = "polling")]
+        tokio::spawn(
+            poll_scheduler
+                .clone()
+                .run(client.clone(), halt_flag.clone()),
+        );
 
         Self {
             halt_flag,
             bots_to_mh_tx,
             bot_out_channel,
-            read_rate_limiter,
-            write_rate_limiter,
-            bet_channels: HashMap::new(),
+            client,
+            #[cfg(any(feature = "polling", feature = "websocket"))]
+            bet_feeds: HashMap::new(),
+            events,
+            #[cfg(any(feature = "polling", feature = "websocket"))]
+            watched_streams: HashSet::new(),
+            #[cfg(feature = "polling")]
+            poll_scheduler,
+            #[cfg(feature = "websocket")]
+            websocket: None,
+            risk_manager,
+            circuit_breaker,
+            allocator,
         }
     }
 
+    /// Sends a response to the bot, and publishes it as an ack
     pub fn send_to_bots(
         bot_out_channel: &Arc<Mutex<HashMap<String, broadcast::Sender<ip::InternalPacket>>>>,
+        events: &ev::EventBus,
         bot_id: &String,
         packet: ip::InternalPacket,
-    ) {
-        bot_out_channel
-            .lock()
-            .unwrap()
+    ) -> Result<(), errors::Error> {
+        events.publish(ev::Event::OwnOrderAck(packet.clone()));
+
+        let channels = bot_out_channel.lock().unwrap();
+        let channel = channels
             .get(bot_id)
-            .unwrap()
+            .ok_or_else(|| errors::Error::ChannelClosed(format!("{bot_id} response")))?;
+
+        channel
             .send(packet)
-            .expect("couldn't send internal_coms packet");
+            .map(|_| ())
+            .map_err(|_| errors::Error::ChannelClosed(format!("{bot_id} response")))
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn handle_bot_messages(
-        write_rate_limiter: rl::RateLimiter,
-        read_rate_limiter: rl::RateLimiter,
+        client: coms::ManifoldClient,
         halt_flag: Arc<AtomicBool>,
         mut bots_to_mh_rx: mpsc::Receiver<ip::InternalPacket>,
         bot_out_channel: Arc<Mutex<HashMap<String, broadcast::Sender<ip::InternalPacket>>>>,
+        risk_manager: Arc<Mutex<risk::RiskManager>>,
+        circuit_breaker: cb::CircuitBreaker,
+        allocator: Arc<Mutex<al::CapitalAllocator>>,
+        events: ev::EventBus,
     ) {
+        Self::refresh_risk_account(
+            &client,
+            &risk_manager,
+            &circuit_breaker,
+            &allocator,
+            &events,
+        )
+        .await;
+
+        // bets our orders placed, which can't be what a later order placed
+        let mut placed = bs::Seen::default();
+
         while !halt_flag.load(Ordering::SeqCst) {
             let internal_coms_packet = match bots_to_mh_rx.recv().await {
                 Some(packet) => packet,
@@ -90,106 +191,458 @@ impl MarketHandler {
 
             debug!("got internal_coms packet {:?}", internal_coms_packet);
 
-            let maybe_res = coms::send_internal_packet(
-                &read_rate_limiter,
-                &write_rate_limiter,
-                &internal_coms_packet,
-            )
-            .await;
+            let halted = match circuit_breaker.halted(&internal_coms_packet.bot_id) {
+                Some(reason) if !risk::RiskManager::is_cancel(&internal_coms_packet) => {
+                    Err(risk::RiskRejection::Halted(reason))
+                }
+                _ => Ok(()),
+            };
+
+            let checked = halted
+                .and_then(|_| risk_manager.lock().unwrap().check(&internal_coms_packet))
+                .and_then(|_| allocator.lock().unwrap().check(&internal_coms_packet));
+
+            if let Err(rejection) = checked {
+                warn!(
+                    "rejected packet from {}: {rejection}",
+                    internal_coms_packet.bot_id
+                );
+                let packet =
+                    ip::InternalPacket::rejection_from_existing(&internal_coms_packet, rejection);
+                if let Err(e) = Self::send_to_bots(
+                    &bot_out_channel,
+                    &events,
+                    &internal_coms_packet.bot_id,
+                    packet,
+                ) {
+                    error!("{e}");
+                }
+                continue;
+            }
+
+            risk_manager
+                .lock()
+                .unwrap()
+                .record_sent(&internal_coms_packet);
+
+            let sent_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("time went backwards")
+                .as_millis() as u64;
+
+            let maybe_res = match client.send_internal_packet(&internal_coms_packet).await {
+                Ok(resp) => resp.text().await.map_err(errors::Error::from),
+                Err(e) => {
+                    Self::reconcile(&client, &internal_coms_packet, e, sent_at, &placed).await
+                }
+            };
+
+            circuit_breaker.record_api_result(&internal_coms_packet.bot_id, maybe_res.is_ok());
 
             let res = match maybe_res {
                 Ok(res) => res,
                 Err(e) => {
                     error!("api error {e}");
-                    let packet = ip::InternalPacket::response_from_existing(
+                    let packet = ip::InternalPacket::error_from_existing(
                         &internal_coms_packet,
-                        format!("api error {e}"),
+                        errors::ApiFailure::from(&e),
                     );
 
-                    Self::send_to_bots(&bot_out_channel, &internal_coms_packet.bot_id, packet);
+                    if let Err(e) = Self::send_to_bots(
+                        &bot_out_channel,
+                        &events,
+                        &internal_coms_packet.bot_id,
+                        packet,
+                    ) {
+                        error!("{e}");
+                    }
 
                     continue;
                 }
+            };
+
+            if let ip::Method::Post = internal_coms_packet.method {
+                if internal_coms_packet.endpoint == "bet" {
+                    let bet = serde_json::from_str::<serde_json::Value>(&res).ok();
+                    if let Some(id) = bet.as_ref().and_then(|bet| bet["id"].as_str()) {
+                        placed.insert(id);
+                    }
+                }
+
+                risk_manager
+                    .lock()
+                    .unwrap()
+                    .record_fill(&internal_coms_packet, &res);
+                allocator
+                    .lock()
+                    .unwrap()
+                    .record_fill(&internal_coms_packet, &res);
+                Self::refresh_risk_account(
+                    &client,
+                    &risk_manager,
+                    &circuit_breaker,
+                    &allocator,
+                    &events,
+                )
+                .await;
             }
-            .text()
-            .await
-            .unwrap();
 
             let packet = ip::InternalPacket::response_from_existing(&internal_coms_packet, res);
-            Self::send_to_bots(&bot_out_channel, &internal_coms_packet.bot_id, packet);
+            if let Err(e) = Self::send_to_bots(
+                &bot_out_channel,
+                &events,
+                &internal_coms_packet.bot_id,
+                packet,
+            ) {
+                error!("{e}");
+            }
+        }
+    }
+
+    /// Books later fills of bots' limit orders to their ledgers. The event
+    /// has the taker's side of the fill: the order bought as many shares of
+    /// the other outcome, for the rest of a mana a share. After a gap, the
+    /// open orders are caught up from the API, as fills may have been missed.
+    async fn track_order_fills(
+        mut fills: ev::Subscription,
+        client: coms::ManifoldClient,
+        allocator: Arc<Mutex<al::CapitalAllocator>>,
+    ) {
+        while let Some(event) = fills.recv().await {
+            match event {
+                ev::Event::LimitOrderFilled {
+                    order_id,
+                    amount,
+                    shares,
+                    ..
+                } => {
+                    allocator
+                        .lock()
+                        .unwrap()
+                        .record_order_fill(&order_id, shares - amount, shares);
+                }
+                ev::Event::Gap { market_id, .. } => {
+                    warn!(
+                        "may have missed fills of open orders in {}, resyncing",
+                        market_id.as_deref().unwrap_or("any market")
+                    );
+                    Self::resync_open_orders(&client, &allocator, market_id.as_deref()).await;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Catches open orders in `market_id`, or every market, up with our
+    /// bets as the API has them
+    async fn resync_open_orders(
+        client: &coms::ManifoldClient,
+        allocator: &Arc<Mutex<al::CapitalAllocator>>,
+        market_id: Option<&str>,
+    ) {
+        let markets = allocator
+            .lock()
+            .unwrap()
+            .open_order_markets()
+            .into_iter()
+            .filter(|market| market_id.is_none_or(|id| id == market))
+            .collect::<Vec<String>>();
+        if markets.is_empty() {
+            return;
+        }
+
+        let me = match client.me().await {
+            Ok(me) => me,
+            Err(e) => {
+                warn!("couldn't resync open orders: {e}");
+                return;
+            }
+        };
+
+        for market in markets {
+            let query = api::BetQuery {
+                user_id: Some(me.lite_user.id.clone()),
+                contract_id: Some(market.clone()),
+                limit: Some(1000),
+                ..Default::default()
+            };
+
+            match client.bets(&query).await {
+                Ok(bets) => {
+                    let mut allocator = allocator.lock().unwrap();
+                    for bet in &bets {
+                        allocator.sync_order(bet);
+                    }
+                }
+                Err(e) => warn!("couldn't resync open orders in {market}: {e}"),
+            }
+        }
+    }
+
+    /// A `bet` that failed without us knowing whether it was placed is
+    /// looked for among our recent bets. If it was placed, that bet is the
+    /// response; any other failure stays an error.
+    async fn reconcile(
+        client: &coms::ManifoldClient,
+        packet: &ip::InternalPacket,
+        err: errors::Error,
+        sent_at: u64,
+        placed: &bs::Seen,
+    ) -> Result<String, errors::Error> {
+        let order = match (&packet.method, packet.endpoint.as_str(), &packet.data) {
+            (ip::Method::Post, "bet", Some(order)) if retry::outcome_unknown(&err) => order,
+            _ => return Err(err),
+        };
+
+        warn!(
+            "bet from {} may or may not have been placed ({err}), checking",
+            packet.bot_id
+        );
+
+        match client.reconcile_bet(order, sent_at, placed).await {
+            Ok(Some(bet)) => {
+                info!("bet from {} was placed as {}", packet.bot_id, bet.id);
+                Ok(serde_json::to_string(&bet)?)
+            }
+            Ok(None) => {
+                info!("bet from {} was not placed", packet.bot_id);
+                Err(err)
+            }
+            Err(e) => {
+                warn!(
+                    "couldn't check whether bet from {} was placed: {e}",
+                    packet.bot_id
+                );
+                Err(err)
+            }
+        }
+    }
+
+    /// Gives the risk manager our latest balance, and the circuit breaker
+    /// and allocator our latest equity. Balance changes are published too.
+    async fn refresh_risk_account(
+        client: &coms::ManifoldClient,
+        risk_manager: &Arc<Mutex<risk::RiskManager>>,
+        circuit_breaker: &cb::CircuitBreaker,
+        allocator: &Arc<Mutex<al::CapitalAllocator>>,
+        events: &ev::EventBus,
+    ) {
+        match client.me().await {
+            Ok(me) => {
+                risk_manager
+                    .lock()
+                    .unwrap()
+                    .update_account(me.lite_user.balance, me.daily_profit());
+                events.publish_balance(&me.lite_user.id, me.lite_user.balance);
+
+                match client.user_portfolio(&me.lite_user.id).await {
+                    Ok(portfolio) => {
+                        let equity = portfolio.balance + portfolio.investment_value;
+                        circuit_breaker.record_equity(equity);
+                        allocator.lock().unwrap().update_account_equity(equity);
+                    }
+                    Err(e) => warn!("couldn't refresh equity: {e}"),
+                }
+            }
+            Err(e) => warn!("couldn't refresh account for risk checks: {e}"),
         }
     }
 
+    /// Shared handle to the circuit breaker, for bots to report into and
+    /// for resuming trading
+    pub fn circuit_breaker(&self) -> cb::CircuitBreaker {
+        self.circuit_breaker.clone()
+    }
+
+    pub fn set_breaker_config(&self, config: cb::BreakerConfig) {
+        self.circuit_breaker.set_config(config);
+    }
+
+    /// Give a bot a budget. Bots without one can't place bets.
+    pub fn set_budget(&self, bot_id: String, budget: al::Budget) {
+        self.allocator.lock().unwrap().set_budget(bot_id, budget);
+    }
+
+    /// Each bot's equity, with its holdings marked at the markets' current
+    /// probabilities
+    pub async fn bot_equity_report(&self) -> Vec<al::BotEquity> {
+        Self::equity_report(&self.client, &self.allocator).await
+    }
+
+    async fn equity_report(
+        client: &coms::ManifoldClient,
+        allocator: &Arc<Mutex<al::CapitalAllocator>>,
+    ) -> Vec<al::BotEquity> {
+        let held_markets = allocator.lock().unwrap().held_markets();
+        for market_id in held_markets {
+            match client.market(&market_id).await {
+                Ok(market) => allocator.lock().unwrap().mark(&market),
+                Err(e) => warn!("couldn't mark {market_id}, keeping its last mark: {e}"),
+            }
+        }
+
+        allocator.lock().unwrap().report()
+    }
+
+    /// Logs the bots' equity every `interval`, until we're halted
+    pub fn log_equity_reports(&self, interval: Duration) {
+        let client = self.client.clone();
+        let allocator = self.allocator.clone();
+        let halt_flag = self.halt_flag.clone();
+
+        tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+                if halt_flag.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                for equity in Self::equity_report(&client, &allocator).await {
+                    info!("{equity}");
+                }
+            }
+        });
+    }
+
+    /// Keeps the bots' ledgers in `path`, see `CapitalAllocator::persist_to`
+    pub fn persist_ledgers(&self, path: PathBuf) {
+        self.allocator.lock().unwrap().persist_to(path);
+    }
+
+    pub fn set_risk_limits(&self, limits: risk::RiskLimits) {
+        self.risk_manager.lock().unwrap().set_limits(limits);
+    }
+
+    /// Shares rate limits through files in `dir`, see
+    /// `EndpointLimiters::persist_to`
+    pub fn persist_rate_limits(&self, dir: &Path) {
+        self.client.rate_limiters().persist_to(dir);
+    }
+
+    /// Gives endpoints matching `pattern` (e.g. `bet`, `market/*/sell`) a
+    /// quota of their own, on top of the read or write limit
+    pub fn set_rate_limit(&self, method: ip::Method, pattern: &str, limit: rl::Limit) {
+        self.client
+            .rate_limiters()
+            .set_limit(method, pattern, limit);
+    }
+
     pub fn halt(&self) {
         self.halt_flag.store(true, Ordering::SeqCst);
     }
 
     pub async fn check_alive(&self) -> bool {
-        let resp =
-            coms::rate_limited_get_endpoint(self.read_rate_limiter.clone(), "me".to_string(), &[])
-                .await
-                .unwrap();
-
-        resp.json::<mt::User>().await.is_ok()
+        self.whoami().await.is_ok()
     }
 
-    pub async fn whoami(&self) -> Result<mt::User, reqwest::Error> {
-        let resp =
-            coms::rate_limited_get_endpoint(self.read_rate_limiter.clone(), "me".to_string(), &[])
-                .await
-                .unwrap();
-
-        resp.json::<mt::User>().await
+    pub async fn whoami(&self) -> Result<mt::User, errors::Error> {
+        self.client.me().await
     }
 
-    pub async fn get_all_my_positions(
-        &self,
-    ) -> Result<Vec<mt::Bet>, errors::ReqwestResponseParsing> {
+    pub async fn get_all_my_positions(&self) -> Result<Vec<mt::Bet>, errors::Error> {
         let me = match self.whoami().await {
             Ok(me) => me,
             Err(e) => {
                 error!("couldn't get me: {e}");
-                return Err(format!("couldn't get me: {e}").into());
+                return Err(e);
             }
         };
 
-        let mut bet_before_id: String = "".to_string();
+        let mut query = api::BetQuery {
+            user_id: Some(me.lite_user.id),
+            limit: Some(1000),
+            ..Default::default()
+        };
         let mut all_bets: Vec<mt::Bet> = vec![];
 
         loop {
-            let params = [
-                ("userId".to_string(), me.id.clone()),
-                ("before".to_string(), bet_before_id),
-                ("limit".to_string(), "1000".to_string()),
-            ];
-
-            let bets_response = coms::rate_limited_get_endpoint(
-                self.read_rate_limiter.clone(),
-                "bets".to_string(),
-                &params,
-            )
-            .await;
-
-            let bets = match bets_response {
-                Ok(bets_response) => bets_response.json::<Vec<mt::Bet>>().await?,
+            let bets = match self.client.bets(&query).await {
+                Ok(bets) => bets,
                 Err(e) => {
                     error!("couldn't get bets: {e}");
-                    return Err(format!("couldn't get bets: {e}").into());
+                    return Err(e);
                 }
             };
 
             all_bets.extend(bets.clone());
 
-            if bets.len() < 1000 {
-                break;
-            } else {
-                debug!("found {} bets", bets.len());
-                bet_before_id = bets.last().unwrap().id.clone();
+            match bets.last() {
+                Some(last) if bets.len() >= 1000 => {
+                    debug!("found {} bets", bets.len());
+                    query.before = Some(last.id.clone());
+                }
+                _ => break,
             }
         }
         Ok(all_bets)
     }
 
+    /// Open positions of the logged in user. Uses the contract metrics
+    /// endpoint, which is one request per 1000 markets traded, and falls
+    /// back to replaying every bet we've made if that fails.
+    pub async fn get_positions(&self) -> Result<Vec<mt::Position>, errors::Error> {
+        let me = match self.whoami().await {
+            Ok(me) => me,
+            Err(e) => {
+                error!("couldn't get me: {e}");
+                return Err(e);
+            }
+        };
+
+        match self
+            .get_positions_from_contract_metrics(&me.lite_user.id)
+            .await
+        {
+            Ok(positions) => Ok(positions),
+            Err(e) => {
+                warn!("couldn't get positions from contract metrics, replaying bets: {e}");
+                let all_bets = self.get_all_my_positions().await?;
+                Ok(Self::get_active_positions(all_bets).await)
+            }
+        }
+    }
+
+    async fn get_positions_from_contract_metrics(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<mt::Position>, errors::Error> {
+        const PAGE_SIZE: usize = 1000;
+
+        let mut positions: Vec<mt::Position> = vec![];
+        let mut offset = 0;
+
+        loop {
+            let page = self
+                .client
+                .user_contract_metrics(user_id, PAGE_SIZE as u32, offset as u32)
+                .await?;
+            let num_contracts = page.metrics_by_contract.len();
+
+            for metrics in page.metrics_by_contract.into_values() {
+                // multiple choice markets have a summary metric (no answer id)
+                // on top of the per-answer ones; skip it so we don't double count
+                let has_answers = metrics.iter().any(|m| m.answer_id.is_some());
+
+                positions.extend(
+                    metrics
+                        .iter()
+                        .filter(|m| m.has_shares && (m.answer_id.is_some() || !has_answers))
+                        .flat_map(|m| m.positions())
+                        .filter(|pos| pos.shares > lq::MIN_SHARES),
+                );
+            }
+
+            if num_contracts < PAGE_SIZE {
+                break;
+            }
+
+            debug!("found metrics for {num_contracts} contracts");
+            offset += num_contracts;
+        }
+
+        Ok(positions)
+    }
+
     pub async fn get_active_positions(all_bets: Vec<mt::Bet>) -> Vec<mt::Position> {
         #[derive(Hash, Eq, PartialEq)]
         struct PositionKey {
@@ -198,7 +651,9 @@ impl MarketHandler {
             answer_id: Option<String>,
         }
 
-        let mut all_positions: HashMap<PositionKey, f64> = HashMap::new();
+        // (amount, shares) summed over all bets; sells and redemptions
+        // have negative shares, so the share sum is what we currently hold
+        let mut all_positions: HashMap<PositionKey, (f64, f64)> = HashMap::new();
 
         for bet in all_bets {
             let position = PositionKey {
@@ -206,96 +661,267 @@ impl MarketHandler {
                 contract_id: bet.contract_id,
                 answer_id: bet.answer_id,
             };
-            *all_positions.entry(position).or_insert(0.0) += bet.amount;
+            let entry = all_positions.entry(position).or_insert((0.0, 0.0));
+            entry.0 += bet.amount;
+            entry.1 += bet.shares;
         }
 
         all_positions
             .into_iter()
-            .filter(|(_, total_amount_sum)| *total_amount_sum > 1e-10)
-            .map(|(position, total_amount_sum)| mt::Position {
-                outcome: position.outcome,
-                contract_id: position.contract_id,
-                answer_id: position.answer_id,
-                amount: total_amount_sum,
-            })
+            .filter(|(_, (_, total_shares))| *total_shares > lq::MIN_SHARES)
+            .map(
+                |(position, (total_amount_sum, total_shares))| mt::Position {
+                    outcome: position.outcome,
+                    contract_id: position.contract_id,
+                    answer_id: position.answer_id,
+                    amount: total_amount_sum,
+                    shares: total_shares,
+                },
+            )
             .collect::<Vec<mt::Position>>()
     }
 
-    pub async fn liquidate_all_positions(&self) -> Result<(), errors::ReqwestResponseParsing> {
-        let all_bets = self.get_all_my_positions().await?;
-        let open_positions = Self::get_active_positions(all_bets.clone()).await;
+    pub async fn liquidate_all_positions(&self) -> Result<lq::LiquidationReport, errors::Error> {
+        self.liquidate(
+            &lq::LiquidationFilter::default(),
+            &lq::LiquidationOptions::default(),
+        )
+        .await
+    }
 
-        for pos in open_positions {
-            let data = Some(serde_json::json!({
-                "answerId": pos.answer_id,
-            }));
+    /// Sell the positions matched by `filter`. With `options.dry_run` nothing
+    /// is sold, and the report only holds estimated proceeds. Sells that fail
+    /// transiently are retried up to `options.max_retries` times; failed
+    /// sells are reported rather than aborting the rest of the liquidation.
+    pub async fn liquidate(
+        &self,
+        filter: &lq::LiquidationFilter,
+        options: &lq::LiquidationOptions,
+    ) -> Result<lq::LiquidationReport, errors::Error> {
+        if !(filter.fraction > 0.0 && filter.fraction <= 1.0) {
+            return Err(format!("fraction must be in (0, 1], got {}", filter.fraction).into());
+        }
 
-            let sell_response = coms::rate_limited_post_endpoint(
-                self.write_rate_limiter.clone(),
-                format!("market/{}/sell", pos.contract_id),
-                &[],
-                data,
-            )
-            .await;
+        let account_positions = self
+            .get_positions()
+            .await?
+            .into_iter()
+            .filter(|pos| match &filter.market_ids {
+                Some(market_ids) => market_ids.contains(&pos.contract_id),
+                None => true,
+            })
+            .collect::<Vec<mt::Position>>();
+
+        // what to sell from, and the account's position it's part of
+        let open_positions = match &filter.bot_id {
+            None => account_positions
+                .into_iter()
+                .map(|pos| (pos.clone(), pos))
+                .collect::<Vec<(mt::Position, mt::Position)>>(),
+            Some(bot_id) => {
+                let holdings = self.allocator.lock().unwrap().holdings(bot_id);
+
+                holdings
+                    .into_iter()
+                    .filter_map(|mut held| {
+                        let account = account_positions.iter().find(|pos| {
+                            pos.contract_id == held.contract_id
+                                && pos.answer_id == held.answer_id
+                                && pos.outcome == held.outcome
+                        })?;
+                        // the ledger may be stale; never sell more than we have
+                        held.shares = held.shares.min(account.shares);
+                        Some((held, account.clone()))
+                    })
+                    .collect()
+            }
+        };
 
-            match sell_response {
-                Ok(resp) => {
-                    info!(
-                        "successfully sold {} {} shares for contract id {} answer id {:?}",
-                        pos.amount, pos.outcome, pos.contract_id, pos.answer_id
-                    );
-                    debug!(
-                        "full response {:?} for contract {} answer {:?}",
-                        resp, pos.contract_id, pos.answer_id
-                    );
+        let mut markets: HashMap<String, Option<mt::FullMarket>> = HashMap::new();
+        let mut report = lq::LiquidationReport::default();
+
+        for (pos, account_pos) in open_positions {
+            if !markets.contains_key(&pos.contract_id) {
+                let market = self.get_market(&pos.contract_id).await;
+                if let Err(e) = &market {
+                    warn!("couldn't get market {} for estimate: {e}", pos.contract_id);
                 }
-                Err(e) => error!("couldn't sell shares: {e}"),
+                markets.insert(pos.contract_id.clone(), market.ok());
+            }
+
+            let shares_to_sell = pos.shares * filter.fraction;
+            let estimated_proceeds = markets[&pos.contract_id]
+                .as_ref()
+                .and_then(|market| pos.outcome_probability(market))
+                .map(|prob| prob * shares_to_sell);
+
+            let outcome = if options.dry_run {
+                lq::LiquidationOutcome::DryRun
+            } else {
+                self.sell_position(
+                    &account_pos,
+                    shares_to_sell,
+                    filter.bot_id.as_deref(),
+                    options.max_retries,
+                )
+                .await
             };
+
+            report.entries.push(lq::LiquidationEntry {
+                position: pos,
+                shares_to_sell,
+                estimated_proceeds,
+                outcome,
+            });
         }
 
-        Ok(())
+        Ok(report)
     }
 
-    pub async fn market_search(
+    /// Sells `shares` of the account's position `pos`, and records the sale
+    /// in `bot_id`'s ledger if it's a bot's. Only transient failures are
+    /// retried, and after one that may have sold anyway, what's still held
+    /// is checked so the retry doesn't sell twice.
+    async fn sell_position(
         &self,
-        term: String,
-    ) -> Result<mt::FullMarket, errors::ReqwestResponseParsing> {
-        let resp = coms::rate_limited_get_endpoint(
-            self.read_rate_limiter.clone(),
-            "search-markets".to_string(),
-            &[
-                ("term".to_string(), term.clone()),
-                ("limit".to_string(), "1".to_string()),
-            ],
-        )
-        .await
-        .unwrap();
+        pos: &mt::Position,
+        shares: f64,
+        bot_id: Option<&str>,
+        max_retries: u32,
+    ) -> lq::LiquidationOutcome {
+        // what we should hold once the sale is done
+        let target = pos.shares - shares;
+        let sell_all = target <= lq::MIN_SHARES;
+        let endpoint = format!("market/{}/sell", pos.contract_id);
+        let mut shares = shares;
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+
+            let body = lq::sell_body(pos, shares, sell_all);
+            let sell_response = self
+                .client
+                .post(rl::Priority::Urgent, &endpoint, &[], Some(&body))
+                .await;
+
+            let error = match sell_response {
+                // it sold, even if we can't make sense of the response
+                Ok(resp) => {
+                    let proceeds = match coms::response_into::<serde_json::Value>(resp).await {
+                        Ok(sale) => {
+                            debug!(
+                                "full response {:?} for contract {} answer {:?}",
+                                sale, pos.contract_id, pos.answer_id
+                            );
+                            if let Some(bot_id) = bot_id {
+                                let packet = ip::InternalPacket::new(
+                                    bot_id.to_string(),
+                                    ip::Method::Post,
+                                    endpoint.clone(),
+                                    vec![],
+                                    Some(body),
+                                );
+                                self.allocator
+                                    .lock()
+                                    .unwrap()
+                                    .record_fill(&packet, &sale.to_string());
+                            }
+                            // the sale is recorded as a bet with a negative amount
+                            sale["amount"].as_f64().map(|amount| -amount)
+                        }
+                        Err(e) => {
+                            warn!("couldn't read the sell response: {e}");
+                            None
+                        }
+                    };
+                    info!(
+                        "successfully sold {shares:.4} {} shares for contract id {} answer id {:?}",
+                        pos.outcome, pos.contract_id, pos.answer_id
+                    );
+                    return lq::LiquidationOutcome::Sold { attempts, proceeds };
+                }
+                Err(e) => e,
+            };
+
+            error!(
+                "couldn't sell shares for contract {} answer {:?} (attempt {attempts}): {error}",
+                pos.contract_id, pos.answer_id
+            );
+
+            if attempts > max_retries || !retry::is_transient(&error) {
+                return lq::LiquidationOutcome::Failed {
+                    attempts,
+                    error: error.to_string(),
+                };
+            }
 
-        let lite_market_req = coms::response_into::<Vec<mt::LiteMarket>>(resp).await;
-        let lite_market = match lite_market_req {
-            Ok(mut markets) => {
-                if markets.len() == 1 {
-                    Ok(markets.pop())
-                } else {
-                    error!("no markets found for term {}", &term);
-                    Err(errors::ReqwestResponseParsing::APIGeneric(format!(
-                        "no markets found for term {}",
-                        &term
-                    )))
+            if retry::outcome_unknown(&error) {
+                match self.held_shares(pos).await {
+                    Ok(held) if held - target <= lq::MIN_SHARES => {
+                        info!(
+                            "sale for contract {} answer {:?} went through after all",
+                            pos.contract_id, pos.answer_id
+                        );
+                        return lq::LiquidationOutcome::Sold {
+                            attempts,
+                            proceeds: None,
+                        };
+                    }
+                    Ok(held) => shares = held - target,
+                    Err(e) => {
+                        return lq::LiquidationOutcome::Failed {
+                            attempts,
+                            error: format!("{error}, and couldn't check whether it sold: {e}"),
+                        }
+                    }
                 }
             }
-            Err(e) => Err(e),
-        }?;
 
-        let full_market = coms::rate_limited_get_endpoint(
-            self.read_rate_limiter.clone(),
-            format!("market/{}", lite_market.as_ref().unwrap().id),
-            &[],
-        )
-        .await
-        .unwrap();
+            sleep(Duration::from_secs(attempts as u64)).await;
+        }
+    }
+
+    /// How many shares of `pos`'s outcome we hold right now
+    async fn held_shares(&self, pos: &mt::Position) -> Result<f64, errors::Error> {
+        let me = self.whoami().await?;
+        let metrics = self
+            .client
+            .market_positions(&pos.contract_id, Some(&me.lite_user.id))
+            .await?;
+
+        Ok(metrics
+            .iter()
+            .filter(|m| m.answer_id == pos.answer_id)
+            .flat_map(|m| m.positions())
+            .filter(|held| held.outcome == pos.outcome)
+            .map(|held| held.shares)
+            .sum())
+    }
 
-        coms::response_into::<mt::FullMarket>(full_market).await
+    pub async fn get_market(&self, market_id: &str) -> Result<mt::FullMarket, errors::Error> {
+        self.client.market(market_id).await
+    }
+
+    pub async fn market_search(&self, term: String) -> Result<mt::FullMarket, errors::Error> {
+        let search = api::MarketSearch {
+            term: term.clone(),
+            limit: Some(1),
+            ..Default::default()
+        };
+
+        let lite_market = match self.client.search_markets(&search).await?.pop() {
+            Some(market) => market,
+            None => {
+                error!("no markets found for term {}", &term);
+                return Err(errors::Error::Other(format!(
+                    "no markets found for term {}",
+                    &term
+                )));
+            }
+        };
+
+        self.client.market(&lite_market.id).await
     }
 
     /// Initializes a tx, rx pair for the bot. The tx channel is used by the
@@ -310,11 +936,11 @@ impl MarketHandler {
             mpsc::Sender<ip::InternalPacket>,
             broadcast::Receiver<ip::InternalPacket>,
         ),
-        String,
+        errors::Error,
     > {
         // if id is in hashmap, bail
         if self.bot_out_channel.lock().unwrap().contains_key(&bot_id) {
-            return Err(format!("Bot {bot_id} already exists"));
+            return Err(errors::Error::Other(format!("Bot {bot_id} already exists")));
         }
 
         let bot_to_mh_tx = self.bots_to_mh_tx.clone();
@@ -325,106 +951,411 @@ impl MarketHandler {
         Ok((bot_to_mh_tx, rx_bot))
     }
 
+    /// Get market bets from the websocket feed instead of polling for them.
+    /// Markets with existing bet streams carry on being polled.
+    #[cfg(feature = "websocket")]
+    pub fn use_websocket(&mut self, config: ws::WsConfig) {
+        #[cfg(feature = "polling")]
+        let websocket = ws::WsTransport::spawn_with_polling(
+            config,
+            self.poll_scheduler.clone(),
+            self.halt_flag.clone(),
+        );
+        #[cfg(not(feature = "polling"))]
+        let websocket = ws::WsTransport::spawn(config, self.halt_flag.clone());
+        self.events
+            .forward_market_updates(websocket.market_updates());
+        self.websocket = Some(websocket);
+    }
+
+    /// Events matching `filter`, from the markets and users being watched
+    pub fn subscribe(&self, filter: ev::EventFilter) -> ev::Subscription {
+        self.events.subscribe(filter)
+    }
+
+    /// Publishes the market's bets, and its updates if the websocket is in
+    /// use, on the event bus
+    #[cfg(any(feature = "polling", feature = "websocket"))]
+    pub async fn watch_market(&mut self, market_id: String) -> Result<(), errors::Error> {
+        if self.watched_streams.contains(&market_id) {
+            return Ok(());
+        }
+
+        let market = self.client.market(&market_id).await?;
+        self.events
+            .set_market_groups(market_id.clone(), market.group_slugs.unwrap_or_default());
+
+        let stream = self.get_bet_stream_for_market_id(market_id.clone()).await?;
+        self.events.forward_bets(Some(market_id.clone()), stream);
+        self.watched_streams.insert(market_id);
+
+        Ok(())
+    }
+
+    /// Publishes the user's bets, in every market, on the event bus
+    #[cfg(feature = "polling")]
+    pub async fn watch_user(&mut self, user_id: String) -> Result<(), errors::Error> {
+        let stream_key = format!("user/{user_id}");
+        if self.watched_streams.contains(&stream_key) {
+            return Ok(());
+        }
+
+        let stream = self
+            .get_bet_stream(
+                stream_key.clone(),
+                api::BetQuery {
+                    user_id: Some(user_id),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        self.events.forward_bets(None, stream);
+        self.watched_streams.insert(stream_key);
+
+        Ok(())
+    }
+
+    /// Market and answer updates from the websocket, for markets with a bet
+    /// stream. None if the websocket isn't in use.
+    #[cfg(feature = "websocket")]
+    pub fn market_updates(&self) -> Option<broadcast::Receiver<ws::MarketUpdate>> {
+        self.websocket.as_ref().map(|ws| ws.market_updates())
+    }
+
+    /// Polling stats for each bet stream, keyed by stream key
+    #[cfg(feature = "polling")]
+    pub fn stream_stats(&self) -> HashMap<String, ps::StreamStats> {
+        self.poll_scheduler.stats()
+    }
+
+    #[cfg(any(feature = "polling", feature = "websocket"))]
     pub async fn get_bet_stream_for_market_id(
         &mut self,
         market_id: String,
-    ) -> broadcast::Receiver<mt::Bet> {
-        self.get_bet_stream(
-            market_id.clone(),
-            vec![("contractId".to_string(), market_id)],
-        )
-        .await
+    ) -> Result<bs::BetStream, errors::Error> {
+        if let Some(feed) = self.bet_feeds.get(&market_id) {
+            return Ok(feed.subscribe());
+        }
+
+        #[cfg(feature = "polling")]
+        let stream = self
+            .get_bet_stream(
+                market_id.clone(),
+                api::BetQuery {
+                    contract_id: Some(market_id.clone()),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        #[cfg(not(feature = "polling"))]
+        let stream = {
+            let feed = bs::BetFeed::new(128);
+            let stream = feed.subscribe();
+            self.bet_feeds.insert(market_id.clone(), feed);
+            stream
+        };
+
+        #[cfg(feature = "websocket")]
+        match &self.websocket {
+            Some(websocket) => {
+                websocket.subscribe_market(market_id.clone(), self.bet_feeds[&market_id].clone())
+            }
+            None if cfg!(not(feature = "polling")) => {
+                warn!("no websocket or polling, so no bets will arrive for {market_id}")
+            }
+            None => {}
+        }
+
+        Ok(stream)
     }
 
+    #[cfg(feature = "polling")]
     pub async fn get_bet_stream(
         &mut self,
         stream_key: String,
-        query_params: Vec<(String, String)>,
-    ) -> broadcast::Receiver<mt::Bet> {
-        info!(
-            "Getting bet stream for {stream_key} params {:?}",
-            query_params
-        );
+        query: api::BetQuery,
+    ) -> Result<bs::BetStream, errors::Error> {
+        info!("Getting bet stream for {stream_key} query {query:?}");
 
-        let rx = if self.bet_channels.contains_key(&stream_key) {
-            self.bet_channels[&stream_key].subscribe()
-        } else {
-            let (tx, rx) = broadcast::channel::<mt::Bet>(128);
-            self.bet_channels
-                .entry(stream_key.to_string())
-                .or_insert(tx);
-            rx
+        // already polling this stream, so just subscribe
+        if let Some(feed) = self.bet_feeds.get(&stream_key) {
+            return Ok(feed.subscribe());
+        }
+
+        let latest = api::BetQuery {
+            limit: Some(1),
+            ..query.clone()
         };
 
-        let mut base_query = query_params.to_vec();
-        base_query.push(("limit".to_string(), "1".to_string()));
+        // None if there are no bets yet, so the first poll sends them all
+        let most_recent_id = self.client.bets(&latest).await?.pop().map(|bet| bet.id);
 
-        let response = coms::rate_limited_get_endpoint(
-            self.read_rate_limiter.clone(),
-            "bets".to_string(),
-            &base_query,
-        )
-        .await
-        .expect("Couldn't get most recent bet from api");
+        let feed = bs::BetFeed::new(128);
+        let stream = feed.subscribe();
 
-        let mut most_recent_id = coms::response_into::<Vec<mt::Bet>>(response)
-            .await
-            .expect("Couldn't convert json into Bet")
-            .pop()
-            .expect("no bets placed yet")
-            .id;
+        self.poll_scheduler.add_stream(
+            stream_key.clone(),
+            query.query(),
+            most_recent_id,
+            feed.clone(),
+        );
+        self.bet_feeds.insert(stream_key, feed);
 
-        // Spawn the task that gets messages from the api and
-        // sends them to the channel
-        let tx_clone = self.bet_channels[&stream_key].clone();
-        let halt_flag_clone = self.halt_flag.clone();
-        let mut read_rate_limiter_clone = self.read_rate_limiter.clone();
+        Ok(stream)
+    }
+}
 
-        tokio::spawn(async move {
-            while !halt_flag_clone.load(Ordering::SeqCst) {
-                let mut params = query_params.clone();
-                params.push(("after".to_string(), most_recent_id.clone()));
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                let committed = read_rate_limiter_clone
-                    .block_for_average_pace_then_commit(Duration::from_millis(500));
+    use serde_json::{json, Value};
 
-                if !committed {
-                    warn!("continuing... couldn't get most recent bet due to rate limit - we timed out");
-                    continue;
-                }
+    use crate::testing::MockServer;
 
-                let maybe_resp = coms::rate_limited_get_endpoint(
-                    read_rate_limiter_clone.clone(),
-                    "bets".to_string(),
-                    &params,
-                )
-                .await;
+    fn handler(base_url: String) -> MarketHandler {
+        MarketHandler::with_client(
+            coms::ManifoldClient::new(
+                coms::ClientConfig {
+                    base_url,
+                    ..Default::default()
+                },
+                rl::EndpointLimiters::default(),
+            )
+            .unwrap(),
+        )
+    }
 
-                let resp = match maybe_resp {
-                    Ok(resp) => resp,
-                    Err(e) => {
-                        warn!("continuing... couldn't get most recent bet due to api error: {e}");
-                        continue;
-                    }
-                };
+    fn position(shares: f64) -> mt::Position {
+        mt::Position {
+            outcome: "NO".to_string(),
+            contract_id: "m".to_string(),
+            answer_id: None,
+            amount: shares / 2.0,
+            shares,
+        }
+    }
 
-                let bets = coms::response_into::<Vec<mt::Bet>>(resp)
-                    .await
-                    .expect("Couldn't convert json into Bet");
+    #[tokio::test]
+    async fn test_sell_stops_on_closed_market() {
+        let server = MockServer::start(vec![(
+            "market/m/sell",
+            vec![(403, json!({"message": "Trading is closed."}))],
+        )])
+        .await;
 
-                for bet in bets.iter() {
-                    tx_clone.send(bet.clone()).expect("Couldn't send bet");
-                }
+        let outcome = handler(server.base_url())
+            .sell_position(&position(100.0), 50.0, None, 2)
+            .await;
 
-                if !bets.is_empty() {
-                    most_recent_id = bets.last().unwrap().id.clone();
-                }
+        assert!(
+            matches!(&outcome, lq::LiquidationOutcome::Failed { attempts: 1, .. }),
+            "{outcome:?}"
+        );
+        assert_eq!(server.bodies("market/m/sell").len(), 1);
+    }
 
-                // TODO remove this! We have our own rate limiter
-                sleep(Duration::from_millis(500)).await;
-            }
+    fn me() -> Value {
+        serde_json::from_str(include_str!("../tests/fixtures/me.json")).unwrap()
+    }
+
+    /// Our metric in market `m`, holding `shares` of NO
+    fn metric(shares: f64) -> Value {
+        let mut metric: Value =
+            serde_json::from_str(include_str!("../tests/fixtures/contract_metric.json")).unwrap();
+        metric["contractId"] = json!("m");
+        metric["answerId"] = Value::Null;
+        metric["totalShares"] = json!({"NO": shares});
+        metric
+    }
+
+    #[tokio::test]
+    async fn test_buying_positions_keeps_breaker() {
+        let account = |balance: f64, investment_value: f64| {
+            let mut me = me();
+            me["balance"] = json!(balance);
+            let mut portfolio: Value =
+                serde_json::from_str(include_str!("../tests/fixtures/portfolio.json")).unwrap();
+            portfolio["balance"] = json!(balance);
+            portfolio["investmentValue"] = json!(investment_value);
+            (me, portfolio)
+        };
+        // 600 of the balance goes into positions
+        let (me_before, portfolio_before) = account(1000.0, 0.0);
+        let (me_after, portfolio_after) = account(400.0, 600.0);
+        let server = MockServer::start(vec![
+            ("me", vec![(200, me_before), (200, me_after)]),
+            (
+                "get-user-portfolio",
+                vec![(200, portfolio_before), (200, portfolio_after)],
+            ),
+        ])
+        .await;
+
+        let client = coms::ManifoldClient::new(
+            coms::ClientConfig {
+                base_url: server.base_url(),
+                ..Default::default()
+            },
+            rl::EndpointLimiters::default(),
+        )
+        .unwrap();
+        let risk_manager = Arc::new(Mutex::new(risk::RiskManager::new(Default::default())));
+        let circuit_breaker = cb::CircuitBreaker::new(cb::BreakerConfig {
+            max_balance_drop: 500.0,
+            ..Default::default()
         });
+        let allocator = Arc::new(Mutex::new(al::CapitalAllocator::new()));
+        let events = ev::EventBus::new(16);
+
+        for _ in 0..2 {
+            MarketHandler::refresh_risk_account(
+                &client,
+                &risk_manager,
+                &circuit_breaker,
+                &allocator,
+                &events,
+            )
+            .await;
+        }
 
-        rx
+        assert_eq!(circuit_breaker.halted("a"), None);
+    }
+
+    #[tokio::test]
+    async fn test_resync_open_orders() {
+        // the order had filled 40 when placed, and 60 by the time we look
+        let mut order: Value =
+            serde_json::from_str(include_str!("../tests/fixtures/limit_bet.json")).unwrap();
+        order["contractId"] = json!("m");
+        order["expiresAt"] = Value::Null;
+        let placed = order.to_string();
+        order["amount"] = json!(60.0);
+        order["shares"] = json!(200.0);
+        let server = MockServer::start(vec![
+            ("me", vec![(200, me())]),
+            ("bets", vec![(200, json!([order]))]),
+        ])
+        .await;
+
+        let handler = handler(server.base_url());
+        handler.set_budget("a".to_string(), al::Budget::Fixed(1000.0));
+        let bet = ip::InternalPacket::new(
+            "a".to_string(),
+            ip::Method::Post,
+            "bet".to_string(),
+            vec![],
+            Some(json!({"contractId": "m", "outcome": "NO", "amount": 100.0, "limitProb": 0.7})),
+        );
+        handler.allocator.lock().unwrap().record_fill(&bet, &placed);
+        assert_eq!(
+            handler
+                .allocator
+                .lock()
+                .unwrap()
+                .bot_equity("a")
+                .unwrap()
+                .reserved,
+            60.0
+        );
+
+        MarketHandler::resync_open_orders(&handler.client, &handler.allocator, None).await;
+
+        let equity = handler.allocator.lock().unwrap().bot_equity("a").unwrap();
+        assert_eq!(equity.open_cost, 60.0);
+        assert_eq!(equity.reserved, 40.0);
+    }
+
+    #[tokio::test]
+    async fn test_liquidate_bot_holdings() {
+        let server = MockServer::start(vec![
+            ("me", vec![(200, me())]),
+            (
+                "get-user-contract-metrics-with-contracts",
+                vec![(
+                    200,
+                    json!({"metricsByContract": {"m": [metric(100.0)]}, "contracts": []}),
+                )],
+            ),
+            (
+                "market/m/sell",
+                vec![(
+                    200,
+                    json!({"contractId": "m", "outcome": "NO", "amount": -12.0, "shares": -30.0}),
+                )],
+            ),
+        ])
+        .await;
+
+        // the account holds 100 NO shares, 30 of them bought by bot "a"
+        let handler = handler(server.base_url());
+        let bet = ip::InternalPacket::new(
+            "a".to_string(),
+            ip::Method::Post,
+            "bet".to_string(),
+            vec![],
+            Some(json!({"contractId": "m", "outcome": "NO", "amount": 10.0})),
+        );
+        handler.allocator.lock().unwrap().record_fill(
+            &bet,
+            &json!({"contractId": "m", "outcome": "NO", "amount": 10.0, "shares": 30.0})
+                .to_string(),
+        );
+
+        let filter = lq::LiquidationFilter {
+            bot_id: Some("a".to_string()),
+            ..Default::default()
+        };
+        let report = handler
+            .liquidate(&filter, &lq::LiquidationOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(report.entries.len(), 1);
+        assert_eq!(report.entries[0].shares_to_sell, 30.0);
+        assert_eq!(
+            server.bodies("market/m/sell"),
+            vec![json!({"outcome": "NO", "shares": 30.0})]
+        );
+        assert!(handler.allocator.lock().unwrap().holdings("a").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sell_resized_after_unknown_outcome() {
+        let me = me();
+        // 30 of the 50 shares were sold before the error
+        let metric = metric(70.0);
+
+        let server = MockServer::start(vec![
+            ("me", vec![(200, me)]),
+            ("market/m/positions", vec![(200, json!([metric]))]),
+            (
+                "market/m/sell",
+                vec![
+                    (503, json!({"message": "upstream timed out"})),
+                    (200, json!({"amount": -8.0})),
+                ],
+            ),
+        ])
+        .await;
+
+        let outcome = handler(server.base_url())
+            .sell_position(&position(100.0), 50.0, None, 2)
+            .await;
+
+        assert!(
+            matches!(
+                outcome,
+                lq::LiquidationOutcome::Sold {
+                    attempts: 2,
+                    proceeds: Some(8.0)
+                }
+            ),
+            "{outcome:?}"
+        );
+        let sells = server.bodies("market/m/sell");
+        assert_eq!(sells[0]["shares"], 50.0);
+        assert_eq!(sells[1]["shares"], 20.0);
     }
 }
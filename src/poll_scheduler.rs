@@ -0,0 +1,426 @@
+/// Polls `bets` for every bet stream from a single task, so all streams
+/// share one read budget. Each stream's poll interval adapts to how busy
+/// its market is: polls that find bets shorten the interval, empty polls
+/// lengthen it. Each poll pages back to the stream's cursor, so bursts of
+/// bets between polls aren't missed.
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+use serde::Serialize;
+use tokio::sync::Notify;
+use tokio::time::sleep;
+
+use crate::bet_stream as bs;
+use crate::coms;
+use crate::errors;
+use crate::manifold_types as mt;
+use crate::rate_limiter as rl;
+
+#[derive(Debug, Clone)]
+pub struct PollConfig {
+    /// Busiest a stream can be polled
+    pub min_interval: Duration,
+    /// Quietest a stream can be polled
+    pub max_interval: Duration,
+    /// Polls per second across all streams; the rest of the read budget
+    /// is left for bots and everything else
+    pub max_polls_per_second: u32,
+    /// Bets per request
+    pub page_size: u32,
+    /// Most requests in one poll; a stream further behind than this skips
+    /// the older bets, and its subscribers get a gap
+    pub max_pages: u32,
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_millis(250),
+            max_interval: Duration::from_secs(10),
+            max_polls_per_second: 20,
+            page_size: 1000,
+            max_pages: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StreamStats {
+    pub polls: u64,
+    pub bets: u64,
+    pub errors: u64,
+    /// current time between polls
+    pub interval: Duration,
+    /// round trip of the last poll
+    pub last_poll_latency: Option<Duration>,
+    /// exponentially weighted
+    pub avg_poll_latency: Option<Duration>,
+    pub max_poll_latency: Duration,
+    /// exponentially weighted time from a bet being placed to us sending it on
+    pub avg_bet_delay: Option<Duration>,
+}
+
+fn ewma(prev: Option<Duration>, x: Duration) -> Duration {
+    match prev {
+        Some(prev) => prev.mul_f64(0.8) + x.mul_f64(0.2),
+        None => x,
+    }
+}
+
+struct PolledStream {
+    query_params: Vec<(String, String)>,
+    /// None if the market had no bets when the stream started
+    most_recent_id: Option<String>,
+    feed: bs::BetFeed,
+    next_poll: Instant,
+    /// Not polled while another transport (the websocket) delivers its bets
+    paused: bool,
+    /// Polled once more after pausing, for bets placed before the other
+    /// transport took over
+    catch_up: bool,
+    /// Whether the last poll failed
+    failing: bool,
+    stats: StreamStats,
+}
+
+impl PolledStream {
+    fn due(&self) -> bool {
+        !self.paused || self.catch_up
+    }
+}
+
+#[derive(Clone)]
+pub struct PollScheduler {
+    config: PollConfig,
+    streams: Arc<Mutex<HashMap<String, PolledStream>>>,
+    /// Wakes the polling task when a stream is added
+    notify: Arc<Notify>,
+}
+
+#[allow(dead_code)]
+impl PollScheduler {
+    pub fn new(config: PollConfig) -> Self {
+        Self {
+            config,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Start polling `bets` with `query_params` for bets after
+    /// `most_recent_id` (or any bets, if None), sending them to `feed`
+    pub fn add_stream(
+        &self,
+        stream_key: String,
+        query_params: Vec<(String, String)>,
+        most_recent_id: Option<String>,
+        feed: bs::BetFeed,
+    ) {
+        let stream = PolledStream {
+            query_params,
+            most_recent_id,
+            feed,
+            next_poll: Instant::now(),
+            paused: false,
+            catch_up: false,
+            failing: false,
+            stats: StreamStats {
+                interval: self.config.min_interval,
+                ..Default::default()
+            },
+        };
+
+        self.streams.lock().unwrap().insert(stream_key, stream);
+        self.notify.notify_one();
+    }
+
+    /// Stops or restarts polling a stream. Either way the stream is polled
+    /// straight away, to pick up anything the other transport missed.
+    pub fn set_paused(&self, stream_key: &str, paused: bool) {
+        if let Some(stream) = self.streams.lock().unwrap().get_mut(stream_key) {
+            stream.catch_up = paused && !stream.paused;
+            stream.paused = paused;
+            stream.next_poll = Instant::now();
+        }
+        self.notify.notify_one();
+    }
+
+    /// Moves a stream's cursor past a bet delivered some other way, so
+    /// polling doesn't send it again
+    pub fn advance(&self, stream_key: &str, bet_id: String) {
+        if let Some(stream) = self.streams.lock().unwrap().get_mut(stream_key) {
+            stream.most_recent_id = Some(bet_id);
+        }
+    }
+
+    pub fn is_paused(&self, stream_key: &str) -> Option<bool> {
+        self.streams
+            .lock()
+            .unwrap()
+            .get(stream_key)
+            .map(|stream| stream.paused)
+    }
+
+    pub fn stats(&self) -> HashMap<String, StreamStats> {
+        self.streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, stream)| (key.clone(), stream.stats.clone()))
+            .collect()
+    }
+
+    /// Busy streams get polled more often, quiet ones less
+    fn next_interval(&self, interval: Duration, found_bets: bool) -> Duration {
+        if found_bets {
+            (interval / 2).max(self.config.min_interval)
+        } else {
+            interval.mul_f64(1.5).min(self.config.max_interval)
+        }
+    }
+
+    /// The stream that's been due the longest, and when it's due
+    fn next_due(&self) -> Option<(String, Instant)> {
+        self.streams
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, stream)| stream.due())
+            .min_by_key(|(_, stream)| stream.next_poll)
+            .map(|(key, stream)| (key.clone(), stream.next_poll))
+    }
+
+    pub async fn run(self, client: coms::ManifoldClient, halt_flag: Arc<AtomicBool>) {
+        let min_gap = Duration::from_secs(1) / self.config.max_polls_per_second.max(1);
+        let mut last_poll = Instant::now() - min_gap;
+
+        while !halt_flag.load(Ordering::SeqCst) {
+            let notified = self.notify.notified();
+
+            let (stream_key, due) = match self.next_due() {
+                Some(next) => next,
+                None => {
+                    notified.await;
+                    continue;
+                }
+            };
+
+            let start = due.max(last_poll + min_gap);
+            if start > Instant::now() {
+                // a new stream might be due before this one
+                tokio::select! {
+                    _ = sleep(start - Instant::now()) => {}
+                    _ = notified => continue,
+                }
+            }
+
+            last_poll = Instant::now();
+            self.poll(&client, &stream_key).await;
+        }
+    }
+
+    /// Bets matching `query_params` placed after `cursor`, oldest first,
+    /// and whether that's all of them
+    async fn fetch_since(
+        &self,
+        client: &coms::ManifoldClient,
+        query_params: &[(String, String)],
+        cursor: Option<&str>,
+    ) -> Result<(Vec<mt::Bet>, bool), errors::Error> {
+        let mut bets: Vec<mt::Bet> = vec![];
+
+        for _ in 0..self.config.max_pages {
+            let mut params = query_params.to_vec();
+            params.push(("limit".to_string(), self.config.page_size.to_string()));
+            if let Some(cursor) = cursor {
+                params.push(("after".to_string(), cursor.to_string()));
+            }
+            // pages come newest first, so the next one is before this one
+            if let Some(oldest) = bets.iter().min_by_key(|bet| bet.created_time) {
+                params.push(("before".to_string(), oldest.id.clone()));
+            }
+
+            let resp = client.get(rl::Priority::Normal, "bets", &params).await?;
+            let page = coms::response_into::<Vec<mt::Bet>>(resp).await?;
+
+            let last_page = page.len() < self.config.page_size as usize;
+            bets.extend(page);
+
+            if last_page {
+                bets.sort_by_key(|bet| bet.created_time);
+                return Ok((bets, true));
+            }
+        }
+
+        bets.sort_by_key(|bet| bet.created_time);
+        Ok((bets, false))
+    }
+
+    async fn poll(&self, client: &coms::ManifoldClient, stream_key: &str) {
+        let (query_params, cursor) = {
+            let mut streams = self.streams.lock().unwrap();
+            let stream = match streams.get_mut(stream_key) {
+                Some(stream) if stream.due() => stream,
+                _ => return,
+            };
+
+            stream.catch_up = false;
+            (stream.query_params.clone(), stream.most_recent_id.clone())
+        };
+
+        let start = Instant::now();
+        let bets = self
+            .fetch_since(client, &query_params, cursor.as_deref())
+            .await;
+        let latency = start.elapsed();
+
+        let mut streams = self.streams.lock().unwrap();
+        let stream = match streams.get_mut(stream_key) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        stream.stats.polls += 1;
+        stream.stats.last_poll_latency = Some(latency);
+        stream.stats.avg_poll_latency = Some(ewma(stream.stats.avg_poll_latency, latency));
+        stream.stats.max_poll_latency = stream.stats.max_poll_latency.max(latency);
+
+        let (bets, complete) = match bets {
+            Ok(bets) => bets,
+            Err(e) => {
+                warn!("continuing... couldn't get bets for stream {stream_key}: {e}");
+                stream.stats.errors += 1;
+                stream.failing = true;
+                stream.next_poll = Instant::now() + stream.stats.interval;
+                return;
+            }
+        };
+
+        if stream.failing {
+            // this poll picked up everything since the last good one
+            stream.failing = false;
+            stream.feed.send(bs::BetStreamEvent::Reconnected);
+        }
+
+        if !complete {
+            warn!("stream {stream_key} fell more than a poll behind, skipping older bets");
+            stream.feed.send(bs::BetStreamEvent::Gap { missed: None });
+        }
+
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_millis() as u64;
+
+        let mut new_bets = 0;
+        for bet in bets.iter() {
+            let delay = Duration::from_millis(now_ms.saturating_sub(bet.created_time));
+
+            if stream.feed.send_bet(bet.clone()) {
+                stream.stats.avg_bet_delay = Some(ewma(stream.stats.avg_bet_delay, delay));
+                new_bets += 1;
+            } else {
+                debug!("stream {stream_key} already sent bet {}", bet.id);
+            }
+        }
+
+        stream.stats.bets += new_bets;
+
+        stream.stats.interval = self.next_interval(stream.stats.interval, new_bets > 0);
+        stream.next_poll = Instant::now() + stream.stats.interval;
+
+        if let Some(bet) = bets.last() {
+            stream.most_recent_id = Some(bet.id.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::rate_limiter::EndpointLimiters;
+    use crate::testing::{self, MockServer};
+
+    fn bet(id: &str, created_time: u64) -> serde_json::Value {
+        testing::bet(id).created_time(created_time).json()
+    }
+
+    #[tokio::test]
+    async fn test_poll_pages_and_dedupes() {
+        // newest first, like the API; "b2" was already sent some other way
+        let server = MockServer::start(vec![(
+            "bets",
+            vec![
+                (200, json!([bet("b4", 4), bet("b3", 3)])),
+                (200, json!([bet("b2", 2)])),
+            ],
+        )])
+        .await;
+        let client = coms::ManifoldClient::new(
+            coms::ClientConfig {
+                base_url: server.base_url(),
+                ..Default::default()
+            },
+            EndpointLimiters::default(),
+        )
+        .unwrap();
+
+        let scheduler = PollScheduler::new(PollConfig {
+            page_size: 2,
+            ..Default::default()
+        });
+        let feed = bs::BetFeed::new(8);
+        let mut stream = feed.subscribe();
+        feed.send_bet(serde_json::from_value(bet("b2", 2)).unwrap());
+        scheduler.add_stream("m".to_string(), vec![], Some("b1".to_string()), feed);
+
+        scheduler.poll(&client, "m").await;
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].query.contains("after=b1"), "{requests:?}");
+        assert!(requests[1].query.contains("before=b3"), "{requests:?}");
+
+        let mut ids = vec![];
+        for _ in 0..3 {
+            match stream.recv().await {
+                Some(bs::BetStreamEvent::Bet(bet)) => ids.push(bet.id),
+                e => panic!("unexpected {e:?}"),
+            }
+        }
+        assert_eq!(ids, vec!["b2", "b3", "b4"]);
+        assert_eq!(scheduler.stats()["m"].bets, 2);
+    }
+
+    #[test]
+    fn test_next_interval() {
+        let scheduler = PollScheduler::new(PollConfig {
+            min_interval: Duration::from_millis(100),
+            max_interval: Duration::from_millis(1000),
+            max_polls_per_second: 10,
+            ..Default::default()
+        });
+
+        let mut interval = Duration::from_millis(100);
+        for _ in 0..10 {
+            interval = scheduler.next_interval(interval, false);
+        }
+        assert_eq!(interval, Duration::from_millis(1000));
+
+        interval = scheduler.next_interval(interval, true);
+        assert_eq!(interval, Duration::from_millis(500));
+
+        for _ in 0..10 {
+            interval = scheduler.next_interval(interval, true);
+        }
+        assert_eq!(interval, Duration::from_millis(100));
+    }
+}
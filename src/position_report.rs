@@ -0,0 +1,317 @@
+use std::cmp::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::manifold_types as mt;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum SortKey {
+    Market,
+    Shares,
+    Cost,
+    Value,
+    Pnl,
+    Close,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+/// A position along with what we know about its market. Fields we couldn't
+/// resolve (e.g. the market fetch failed) are None.
+#[derive(Serialize, Debug, Clone)]
+pub struct PositionRow {
+    pub contract_id: String,
+    pub answer_id: Option<String>,
+    pub question: Option<String>,
+    pub answer: Option<String>,
+    pub outcome: String,
+    pub shares: f64,
+    pub cost_basis: f64,
+    /// probability of the outcome we hold, not necessarily of YES
+    pub probability: Option<f64>,
+    pub mark_value: Option<f64>,
+    pub unrealized_pnl: Option<f64>,
+    /// milliseconds since epoch
+    pub close_time: Option<i64>,
+}
+
+impl PositionRow {
+    pub fn new(position: &mt::Position, market: Option<&mt::FullMarket>) -> Self {
+        let probability = market.and_then(|m| position.outcome_probability(m));
+        let mark_value = probability.map(|p| p * position.shares);
+
+        let answer = match (&position.answer_id, market.and_then(|m| m.answers.as_ref())) {
+            (Some(answer_id), Some(answers)) => answers
+                .iter()
+                .find(|a| &a.id == answer_id)
+                .map(|a| a.text.clone()),
+            _ => None,
+        };
+
+        Self {
+            contract_id: position.contract_id.clone(),
+            answer_id: position.answer_id.clone(),
+            question: market.map(|m| m.lite_market.question.clone()),
+            answer,
+            outcome: position.outcome.clone(),
+            shares: position.shares,
+            cost_basis: position.amount,
+            probability,
+            mark_value,
+            unrealized_pnl: mark_value.map(|v| v - position.amount),
+            close_time: market.and_then(|m| m.lite_market.close_time),
+        }
+    }
+
+    fn market_label(&self) -> String {
+        let question = self.question.clone().unwrap_or(self.contract_id.clone());
+        match (&self.answer, &self.answer_id) {
+            (Some(answer), _) => format!("{question} / {answer}"),
+            (None, Some(answer_id)) => format!("{question} / {answer_id}"),
+            (None, None) => question,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RowFilter {
+    /// case insensitive substring of the question or answer
+    pub search: Option<String>,
+    pub outcome: Option<String>,
+    pub min_value: Option<f64>,
+}
+
+impl RowFilter {
+    pub fn matches(&self, row: &PositionRow) -> bool {
+        if let Some(search) = &self.search {
+            let search = search.to_lowercase();
+            if !row.market_label().to_lowercase().contains(&search) {
+                return false;
+            }
+        }
+
+        if let Some(outcome) = &self.outcome {
+            if !row.outcome.eq_ignore_ascii_case(outcome) {
+                return false;
+            }
+        }
+
+        if let Some(min_value) = self.min_value {
+            if row.mark_value.unwrap_or(0.0) < min_value {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Sorts so that unknown values always end up last
+pub fn sort_rows(rows: &mut [PositionRow], key: SortKey, descending: bool) {
+    fn cmp_option<T: PartialOrd>(a: Option<T>, b: Option<T>, descending: bool) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) => {
+                let ord = a.partial_cmp(&b).unwrap_or(Ordering::Equal);
+                if descending {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            }
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+
+    rows.sort_by(|a, b| match key {
+        SortKey::Market => cmp_option(
+            Some(a.market_label().to_lowercase()),
+            Some(b.market_label().to_lowercase()),
+            descending,
+        ),
+        SortKey::Shares => cmp_option(Some(a.shares), Some(b.shares), descending),
+        SortKey::Cost => cmp_option(Some(a.cost_basis), Some(b.cost_basis), descending),
+        SortKey::Value => cmp_option(a.mark_value, b.mark_value, descending),
+        SortKey::Pnl => cmp_option(a.unrealized_pnl, b.unrealized_pnl, descending),
+        SortKey::Close => cmp_option(a.close_time, b.close_time, descending),
+    });
+}
+
+pub fn render(rows: &[PositionRow], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Table => render_table(rows),
+        OutputFormat::Json => serde_json::to_string_pretty(rows).expect("rows are serializable"),
+        OutputFormat::Csv => render_csv(rows),
+    }
+}
+
+fn fmt_f64(value: Option<f64>, precision: usize) -> String {
+    match value {
+        Some(value) => format!("{value:.precision$}"),
+        None => "?".to_string(),
+    }
+}
+
+/// e.g. "in 3d 4h", or "closed 2d 1h ago"
+fn fmt_close_time(close_time: Option<i64>) -> String {
+    let close_time = match close_time {
+        Some(close_time) => close_time,
+        None => return "-".to_string(),
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis() as i64;
+
+    let delta_hours = (close_time - now).abs() / (1000 * 60 * 60);
+    let span = format!("{}d {}h", delta_hours / 24, delta_hours % 24);
+
+    if close_time >= now {
+        format!("in {span}")
+    } else {
+        format!("closed {span} ago")
+    }
+}
+
+fn truncate(s: &str, max_chars: usize) -> String {
+    if s.chars().count() <= max_chars {
+        s.to_string()
+    } else {
+        let truncated: String = s.chars().take(max_chars - 1).collect();
+        format!("{truncated}…")
+    }
+}
+
+fn render_table(rows: &[PositionRow]) -> String {
+    let mut out = format!(
+        "{:<50} {:<5} {:>10} {:>10} {:>6} {:>10} {:>10} {:>16}\n",
+        "market", "out", "shares", "cost", "prob", "value", "pnl", "close"
+    );
+
+    for row in rows {
+        out += &format!(
+            "{:<50} {:<5} {:>10.2} {:>10.2} {:>6} {:>10} {:>10} {:>16}\n",
+            truncate(&row.market_label(), 50),
+            row.outcome,
+            row.shares,
+            row.cost_basis,
+            fmt_f64(row.probability, 3),
+            fmt_f64(row.mark_value, 2),
+            fmt_f64(row.unrealized_pnl, 2),
+            fmt_close_time(row.close_time),
+        );
+    }
+
+    let total_value: f64 = rows.iter().filter_map(|r| r.mark_value).sum();
+    let total_pnl: f64 = rows.iter().filter_map(|r| r.unrealized_pnl).sum();
+    out += &format!(
+        "{} positions | value {total_value:.2} | unrealized pnl {total_pnl:.2}",
+        rows.len()
+    );
+
+    out
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn render_csv(rows: &[PositionRow]) -> String {
+    let mut out = "contract_id,answer_id,question,answer,outcome,shares,cost_basis,\
+        probability,mark_value,unrealized_pnl,close_time\n"
+        .to_string();
+
+    let opt_f64 = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+
+    for row in rows {
+        let fields = [
+            row.contract_id.clone(),
+            row.answer_id.clone().unwrap_or_default(),
+            row.question.clone().unwrap_or_default(),
+            row.answer.clone().unwrap_or_default(),
+            row.outcome.clone(),
+            row.shares.to_string(),
+            row.cost_basis.to_string(),
+            opt_f64(row.probability),
+            opt_f64(row.mark_value),
+            opt_f64(row.unrealized_pnl),
+            row.close_time.map(|t| t.to_string()).unwrap_or_default(),
+        ];
+
+        out += &fields
+            .iter()
+            .map(|f| csv_field(f))
+            .collect::<Vec<String>>()
+            .join(",");
+        out += "\n";
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(question: &str, mark_value: Option<f64>) -> PositionRow {
+        PositionRow {
+            contract_id: "c".to_string(),
+            answer_id: None,
+            question: Some(question.to_string()),
+            answer: None,
+            outcome: "YES".to_string(),
+            shares: 10.0,
+            cost_basis: 5.0,
+            probability: mark_value.map(|v| v / 10.0),
+            mark_value,
+            unrealized_pnl: mark_value.map(|v| v - 5.0),
+            close_time: None,
+        }
+    }
+
+    #[test]
+    fn test_sort_unknowns_last() {
+        let mut rows = vec![row("a", None), row("b", Some(1.0)), row("c", Some(3.0))];
+
+        sort_rows(&mut rows, SortKey::Value, true);
+        let order: Vec<f64> = rows.iter().map(|r| r.mark_value.unwrap_or(-1.0)).collect();
+        assert_eq!(order, vec![3.0, 1.0, -1.0]);
+
+        sort_rows(&mut rows, SortKey::Value, false);
+        let order: Vec<f64> = rows.iter().map(|r| r.mark_value.unwrap_or(-1.0)).collect();
+        assert_eq!(order, vec![1.0, 3.0, -1.0]);
+    }
+
+    #[test]
+    fn test_csv_quoting() {
+        let csv = render_csv(&[row("will \"x\", or y?", Some(2.0))]);
+        let line = csv.lines().nth(1).unwrap();
+        assert!(line.starts_with("c,,\"will \"\"x\"\", or y?\",,YES,10,5,"));
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = RowFilter {
+            search: Some("WILL".to_string()),
+            outcome: Some("yes".to_string()),
+            min_value: Some(2.0),
+        };
+
+        assert!(filter.matches(&row("will it", Some(2.5))));
+        assert!(!filter.matches(&row("will it", Some(1.0))));
+        assert!(!filter.matches(&row("won't it", Some(2.5))));
+    }
+}
@@ -1,127 +1,766 @@
-use std::sync::{Arc, Mutex};
 /// The goal here is to allow us to rate limit ourselves nicely.
 /// We want to be able to
 ///     - immediately allow requests if they will not violate the rate limit
-///     - block until we can make another request
-use std::thread::sleep;
-use std::time::{Duration, Instant};
+///     - wait (asynchronously) until we can make another request
+///     - serve waiters in the order they arrived, with urgent requests
+///       (orders, cancels) ahead of background ones (scans, searches)
+///     - back off when the server tells us we're going too fast (HTTP 429),
+///       then slowly work back up to the configured limit
+///     - limit endpoints with their own quotas separately
+///     - optionally keep recent requests in a file, so restarts remember
+///       them and several processes can share one budget
+///
+/// The state sits behind a std Mutex, which is only ever held for a few
+/// instructions and never across an await or file I/O, so it's fine in
+/// async code. The file is locked without blocking: if another process has
+/// it, we wait a moment and try again rather than stalling the thread.
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use tokio::sync::Notify;
+use tokio::time::sleep;
+
+use crate::internal_packet as ip;
+
+/// Waiters are served by priority, and in arrival order within a priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Placing and cancelling orders
+    Urgent,
+    Normal,
+    /// Bulk data collection, e.g. position scans and market searches
+    Background,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Times of the most recent requests, oldest first, at most `max_capacity`
+    prev_requests: VecDeque<Instant>,
+    /// Requests allowed per window right now; below `max_capacity` after
+    /// the server has throttled us
+    capacity: usize,
+    max_capacity: usize,
+    /// No requests until then, if the server asked us to wait
+    backoff_until: Option<Instant>,
+    /// Successes since the capacity last changed
+    successes: usize,
+
+    store: Option<Arc<TimestampStore>>,
+
+    /// Tickets of the tasks waiting in `acquire`, in arrival order.
+    /// Only the head ticket may commit.
+    waiters: VecDeque<(u64, Priority)>,
+    next_ticket: u64,
+}
+
+impl State {
+    /// The ticket that gets the next request: the earliest arrival of the
+    /// most urgent priority waiting
+    fn head(&self) -> Option<u64> {
+        self.waiters
+            .iter()
+            .min_by_key(|(_, priority)| *priority)
+            .map(|(id, _)| *id)
+    }
+
+    /// Replaces our history with the store's, which has other processes'
+    /// requests as well as ours
+    fn load(&mut self, stamps: &[u64]) {
+        let now = Instant::now();
+        let now_ms = unix_ms();
+
+        let mut requests = stamps
+            .iter()
+            .filter_map(|t| now.checked_sub(Duration::from_millis(now_ms.saturating_sub(*t))))
+            .collect::<Vec<Instant>>();
+        requests.sort();
+
+        let skip = requests.len().saturating_sub(self.max_capacity);
+        self.prev_requests = requests.into_iter().skip(skip).collect();
+    }
+
+    fn commit(&mut self) {
+        if self.prev_requests.len() == self.max_capacity {
+            self.prev_requests.pop_front();
+        }
+        self.prev_requests.push_back(Instant::now());
+    }
+}
+
+/// How long to wait before trying again when another process has the
+/// store locked
+const STORE_BUSY_WAIT: Duration = Duration::from_millis(5);
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis() as u64
+}
+
+/// Request times in a file, as unix ms, one per line. The file is locked
+/// while it's read or written, so it can be shared between processes.
+/// Nothing waits for the lock: if another process has it, reads and
+/// updates give None.
+#[derive(Debug)]
+struct TimestampStore {
+    path: PathBuf,
+}
+
+impl TimestampStore {
+    fn open(&self) -> io::Result<File> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
 
-use queues::{CircularBuffer, IsQueue};
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+    }
+
+    fn read_locked(mut file: &File) -> io::Result<Vec<u64>> {
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        Ok(contents
+            .lines()
+            .filter_map(|line| line.trim().parse::<u64>().ok())
+            .collect())
+    }
+
+    /// Whether we got the lock
+    fn locked(result: Result<(), TryLockError>) -> io::Result<bool> {
+        match result {
+            Ok(()) => Ok(true),
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    fn read(&self) -> io::Result<Option<Vec<u64>>> {
+        let file = self.open()?;
+        if !Self::locked(file.try_lock_shared())? {
+            return Ok(None);
+        }
+        let stamps = Self::read_locked(&file);
+        file.unlock()?;
+
+        stamps.map(Some)
+    }
+
+    /// Hands `f` the stored times with the file locked, then writes back
+    /// what it leaves. Only fails if `f` couldn't be run.
+    fn update<T>(&self, f: impl FnOnce(&mut Vec<u64>) -> T) -> io::Result<Option<T>> {
+        let mut file = self.open()?;
+        if !Self::locked(file.try_lock())? {
+            return Ok(None);
+        }
+
+        let mut stamps = match Self::read_locked(&file) {
+            Ok(stamps) => stamps,
+            Err(e) => {
+                let _ = file.unlock();
+                return Err(e);
+            }
+        };
+
+        let result = f(&mut stamps);
+
+        let contents = stamps.iter().map(|t| format!("{t}\n")).collect::<String>();
+        let written = file
+            .set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(contents.as_bytes()));
+        if let Err(e) = written {
+            warn!("couldn't write {}: {e}", self.path.display());
+        }
+
+        let _ = file.unlock();
+
+        Ok(Some(result))
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct RateLimiter {
     duration: Duration,
-    prev_requests: Arc<Mutex<CircularBuffer<Instant>>>,
+    state: Arc<Mutex<State>>,
+    /// Wakes waiters when the front of the queue changes
+    notify: Arc<Notify>,
 }
 
-impl RateLimiter {
-    // TODO want to add a "get burst capacity" option, which returns the
-    // number of requests that are likely to succeed immediately
+/// A place in the waiting queue. Dropping it (including when the `acquire`
+/// future is cancelled) gives up the place, so cancellation never leaves
+/// the queue stuck.
+struct Ticket<'a> {
+    id: u64,
+    rate_limiter: &'a RateLimiter,
+}
+
+impl<'a> Ticket<'a> {
+    fn new(rate_limiter: &'a RateLimiter, priority: Priority) -> Self {
+        let mut state = rate_limiter.state.lock().expect("attempting lock");
+        let id = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiters.push_back((id, priority));
+        drop(state);
 
+        // an urgent ticket may have taken the head from a waiter
+        rate_limiter.notify.notify_waiters();
+
+        Self { id, rate_limiter }
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        let mut state = self.rate_limiter.state.lock().expect("attempting lock");
+        state.waiters.retain(|(id, _)| *id != self.id);
+        drop(state);
+
+        self.rate_limiter.notify.notify_waiters();
+    }
+}
+
+impl RateLimiter {
+    /// Panics if `num_requests` is 0: a limit has to allow some requests
     pub fn new(num_requests: usize, over_duration: Duration) -> Self {
+        assert!(
+            num_requests >= 1,
+            "a rate limit must allow at least one request"
+        );
+
         Self {
             duration: over_duration,
-            prev_requests: Arc::new(Mutex::new(CircularBuffer::<Instant>::new(num_requests))),
+            state: Arc::new(Mutex::new(State {
+                prev_requests: VecDeque::with_capacity(num_requests),
+                capacity: num_requests,
+                max_capacity: num_requests,
+                backoff_until: None,
+                successes: 0,
+                store: None,
+                waiters: VecDeque::new(),
+                next_ticket: 0,
+            })),
+            notify: Arc::new(Notify::new()),
         }
     }
 
-    /// Returns the duration until we can make a request
-    fn time_until_available(&self) -> Duration {
-        let prev_reqs = self.prev_requests.lock().expect("attempting lock");
+    /// Keeps recent requests in the file at `path` from now on, and
+    /// counts the ones already there. Limiters in other processes using
+    /// the same file share the budget.
+    pub fn persist_to(&self, path: PathBuf) {
+        self.state.lock().expect("attempting lock").store = Some(Arc::new(TimestampStore { path }));
+        self.sync();
+    }
 
-        if prev_reqs.size() < prev_reqs.capacity() {
-            return Duration::new(0, 0);
+    /// Picks up requests other processes have made, unless one of them is
+    /// writing the store right now
+    fn sync(&self) {
+        let store = match self.state.lock().expect("attempting lock").store.clone() {
+            Some(store) => store,
+            None => return,
+        };
+
+        match store.read() {
+            Ok(Some(stamps)) => self.state.lock().expect("attempting lock").load(&stamps),
+            Ok(None) => {}
+            Err(e) => warn!("couldn't read {}: {e}", store.path.display()),
         }
+    }
 
-        let dt = Instant::now()
-            .checked_duration_since(prev_reqs.peek().expect("guaranteed to not be empty"))
-            .unwrap();
+    fn check_commit_locked(&self, state: &mut State) -> Result<(), Duration> {
+        let wait = self.time_until_available_locked(state);
 
-        if dt < self.duration {
-            self.duration - dt
+        if wait == Duration::new(0, 0) {
+            state.commit();
+            Ok(())
         } else {
-            Duration::new(0, 0)
+            Err(wait)
+        }
+    }
+
+    /// Commits if we can make a request now, otherwise says how long until
+    /// we can. With a store, the check and commit happen with the file
+    /// locked, so two processes can't both take the last request; `state`
+    /// is only locked for the check itself.
+    fn try_commit(&self) -> Result<(), Duration> {
+        let store = {
+            let mut state = self.state.lock().expect("attempting lock");
+            match state.store.clone() {
+                Some(store) => store,
+                None => return self.check_commit_locked(&mut state),
+            }
+        };
+
+        let updated = store.update(|stamps| {
+            let (result, max_capacity) = {
+                let mut state = self.state.lock().expect("attempting lock");
+                state.load(stamps);
+                (self.check_commit_locked(&mut state), state.max_capacity)
+            };
+
+            if result.is_ok() {
+                stamps.push(unix_ms());
+            }
+
+            // older requests can't affect the limit
+            stamps.sort();
+            let skip = stamps.len().saturating_sub(max_capacity);
+            stamps.drain(..skip);
+
+            result
+        });
+
+        match updated {
+            Ok(Some(result)) => result,
+            Ok(None) => Err(STORE_BUSY_WAIT),
+            Err(e) => {
+                warn!("couldn't update {}: {e}", store.path.display());
+                self.check_commit_locked(&mut self.state.lock().expect("attempting lock"))
+            }
         }
     }
 
+    /// How long until `n` requests can be made back to back, or None if
+    /// `n` is more than the rate limit currently allows at all
+    fn time_until_locked(&self, state: &State, n: usize) -> Option<Duration> {
+        if n > state.capacity {
+            return None;
+        }
+
+        let now = Instant::now();
+        let backoff = state
+            .backoff_until
+            .and_then(|until| until.checked_duration_since(now))
+            .unwrap_or(Duration::new(0, 0));
+
+        // only the last `capacity` requests count against us
+        let counted = state.prev_requests.len().min(state.capacity);
+        let free = state.capacity - counted;
+        if n <= free {
+            return Some(backoff);
+        }
+
+        // the (n - free)th oldest counted request has to fall out of the window
+        let blocking = state.prev_requests[state.prev_requests.len() - counted + n - free - 1];
+        let window = (blocking + self.duration)
+            .checked_duration_since(now)
+            .unwrap_or(Duration::new(0, 0));
+
+        Some(window.max(backoff))
+    }
+
+    fn time_until_available_locked(&self, state: &State) -> Duration {
+        self.time_until_locked(state, 1)
+            .expect("a rate limit allows at least one request")
+    }
+
+    /// Returns the duration until we can make a request
+    fn time_until_available(&self) -> Duration {
+        self.sync();
+        let state = self.state.lock().expect("attempting lock");
+        self.time_until_available_locked(&state)
+    }
+
     /// Get the "average pace" for the rate limit - that is, we should be able to
     /// make one request each "average pace" duration indefinitely without violating
     /// the rate limit
     fn get_average_pace(&self) -> Duration {
-        let prev_reqs = self.prev_requests.lock().expect("attempting lock");
+        let state = self.state.lock().expect("attempting lock");
+
+        self.duration / state.capacity as u32
+    }
+
+    /// The number of requests that can be made right now without waiting
+    /// (the burst capacity). Zero while anyone is waiting in `acquire`.
+    pub fn available_now(&self) -> usize {
+        self.sync();
+        let state = self.state.lock().expect("attempting lock");
+
+        let now = Instant::now();
 
-        self.duration / prev_reqs.capacity() as u32
+        if !state.waiters.is_empty() || state.backoff_until.is_some_and(|until| until > now) {
+            return 0;
+        }
+
+        let in_window = state
+            .prev_requests
+            .iter()
+            .filter(|t| now.duration_since(**t) < self.duration)
+            .count();
+
+        state.capacity.saturating_sub(in_window)
+    }
+
+    /// The server said we're going too fast. Wait `retry_after` (or a full
+    /// window if it didn't say) and halve the requests allowed per window.
+    pub fn throttle(&self, retry_after: Option<Duration>) {
+        let mut state = self.state.lock().expect("attempting lock");
+        let now = Instant::now();
+
+        // requests sent before the first 429 came back will likely get one
+        // too; only shrink once per backoff
+        if state.backoff_until.is_some_and(|until| until > now) {
+            return;
+        }
+
+        state.capacity = (state.capacity / 2).max(1);
+        state.backoff_until = Some(now + retry_after.unwrap_or(self.duration));
+        state.successes = 0;
+
+        warn!(
+            "throttled by server, now {} requests per {:?}",
+            state.capacity, self.duration
+        );
+    }
+
+    /// Every full window's worth of successful requests earns back one
+    /// request per window, up to the configured limit
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().expect("attempting lock");
+
+        if state.capacity == state.max_capacity {
+            return;
+        }
+
+        state.successes += 1;
+        if state.successes >= state.capacity {
+            state.capacity += 1;
+            state.successes = 0;
+        }
+    }
+
+    /// Requests allowed per window right now
+    pub fn capacity(&self) -> usize {
+        self.state.lock().expect("attempting lock").capacity
+    }
+
+    /// How long until we could make `n` requests back to back, ignoring
+    /// anyone waiting. None if `n` is more than the rate limit ever allows.
+    pub fn time_until(&self, n: usize) -> Option<Duration> {
+        self.sync();
+        let state = self.state.lock().expect("attempting lock");
+        self.time_until_locked(&state, n)
     }
 
     /// Returns true if we can make a request, otherwise, false
     pub fn attempt(&self) -> bool {
-        self.time_until_available() == Duration::new(0, 0)
+        self.sync();
+        let state = self.state.lock().expect("attempting lock");
+        state.waiters.is_empty() && self.time_until_available_locked(&state) == Duration::new(0, 0)
     }
 
     /// Returns true if we can make a request,
-    /// and if so, commits. Otherwise, false
+    /// and if so, commits. Otherwise, false. Never jumps the queue of
+    /// tasks waiting in `acquire`.
     pub fn attempt_commit(&mut self) -> bool {
-        let is_ok = self.attempt();
+        let no_waiters = self
+            .state
+            .lock()
+            .expect("attempting lock")
+            .waiters
+            .is_empty();
+
+        no_waiters && self.try_commit().is_ok()
+    }
+
+    /// Waits until we can make a request, then commits
+    pub async fn acquire(&self) {
+        self.acquire_with_priority(Priority::Normal).await;
+    }
+
+    pub async fn acquire_with_priority(&self, priority: Priority) {
+        self.acquire_until(None, priority).await;
+    }
+
+    /// Waits until we can make a request, unless that would take longer than
+    /// `timeout`, in which case we return false without committing.
+    pub async fn acquire_timeout(&self, timeout: Duration) -> bool {
+        self.acquire_until(Some(Instant::now() + timeout), Priority::Normal)
+            .await
+    }
+
+    /// Waits for the "average pace" (duration / num reqs) before committing,
+    /// so that a steady stream of requests is spread out instead of bursting.
+    /// If we have to wait for longer than the average pace, we acquire with
+    /// the timeout.
+    pub async fn acquire_paced(&self, timeout: Duration) -> bool {
+        self.acquire_paced_with_priority(timeout, Priority::Normal)
+            .await
+    }
+
+    /// As `acquire_paced`, except urgent requests skip the pacing and go
+    /// straight to the front of the queue.
+    pub async fn acquire_paced_with_priority(&self, timeout: Duration, priority: Priority) -> bool {
+        self.pace(priority).await;
+
+        self.acquire_until(Some(Instant::now() + timeout), priority)
+            .await
+    }
+
+    async fn pace(&self, priority: Priority) {
+        if let Some(delay) = self.pace_delay(priority) {
+            sleep(delay).await;
+        }
+    }
+
+    /// How long to hold a request back to keep to the average pace, if at all
+    fn pace_delay(&self, priority: Priority) -> Option<Duration> {
+        let avg_pace = self.get_average_pace();
+
+        (priority != Priority::Urgent && self.time_until_available() < avg_pace).then_some(avg_pace)
+    }
+
+    /// Waits until `ticket` is at the front of the queue and a request could
+    /// be made, without committing. Holding the ticket keeps the place.
+    async fn wait_ready(&self, ticket: &Ticket<'_>) {
+        loop {
+            let notified = self.notify.notified();
+
+            let our_turn = self.state.lock().expect("attempting lock").head() == Some(ticket.id);
+            let wait = if our_turn {
+                self.time_until_available()
+            } else {
+                Duration::MAX
+            };
+
+            if wait.is_zero() {
+                return;
+            }
+
+            tokio::select! {
+                _ = sleep(wait.min(Duration::from_secs(60 * 60 * 24))) => {}
+                _ = notified => {}
+            }
+        }
+    }
+
+    async fn acquire_until(&self, deadline: Option<Instant>, priority: Priority) -> bool {
+        let ticket = Ticket::new(self, priority);
+
+        loop {
+            // register for wakeups before checking, so we can't miss one
+            let notified = self.notify.notified();
+
+            let our_turn = self.state.lock().expect("attempting lock").head() == Some(ticket.id);
+
+            let wait = if our_turn {
+                match self.try_commit() {
+                    Ok(()) => return true,
+                    Err(wait) => Some(wait),
+                }
+            } else {
+                // not our turn; wait for whoever's in front to finish
+                None
+            };
 
-        if is_ok {
             let now = Instant::now();
-            self.prev_requests
-                .lock()
-                .expect("attempting lock")
-                .add(now)
-                .unwrap();
+            match (wait, deadline) {
+                (Some(wait), Some(deadline)) if now + wait > deadline => return false,
+                (None, Some(deadline)) if now >= deadline => return false,
+                _ => {}
+            }
+
+            let sleep_for = match (wait, deadline) {
+                (Some(wait), _) => wait,
+                (None, Some(deadline)) => deadline - now,
+                (None, None) => Duration::MAX,
+            };
+
+            tokio::select! {
+                _ = sleep(sleep_for.min(Duration::from_secs(60 * 60 * 24))) => {}
+                _ = notified => {}
+            }
+        }
+    }
+}
+
+/// A rate limit of `num_requests` per `duration`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    num_requests: usize,
+    duration: Duration,
+}
+
+impl Limit {
+    /// Panics if `num_requests` is 0: a limit has to allow some requests
+    pub fn new(num_requests: usize, duration: Duration) -> Self {
+        assert!(
+            num_requests >= 1,
+            "a rate limit must allow at least one request"
+        );
+
+        Self {
+            num_requests,
+            duration,
         }
+    }
+}
 
-        is_ok
+/// Rate limiters for the API. Every request counts against the limiter for
+/// its method; endpoints with a quota of their own also count against theirs.
+#[derive(Clone, Debug)]
+pub struct EndpointLimiters {
+    read: RateLimiter,
+    write: RateLimiter,
+    /// (method, endpoint pattern, limiter). A `*` in the pattern matches any
+    /// one path segment, e.g. `market/*/sell`
+    endpoints: Arc<Mutex<Vec<(ip::Method, String, RateLimiter)>>>,
+    /// Where the limiters keep their requests, if anywhere
+    store_dir: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl Default for EndpointLimiters {
+    fn default() -> Self {
+        // slightly lower than the true limits
+        Self::new(
+            Limit::new(90, Duration::from_secs(1)),
+            Limit::new(9, Duration::from_secs(60)),
+        )
     }
+}
+
+fn matches_pattern(pattern: &str, endpoint: &str) -> bool {
+    let pattern = pattern.split('/').collect::<Vec<&str>>();
+    let endpoint = endpoint.split('/').collect::<Vec<&str>>();
 
-    /// Blocks until we can make a request, unless we hit timeout,
-    /// in which case we return false. If we don't hit the timeout,
-    /// we return true.
-    pub fn block_then_commit(&mut self, timeout: Duration) -> bool {
-        if self.attempt_commit() {
-            return true;
+    pattern.len() == endpoint.len()
+        && pattern
+            .iter()
+            .zip(endpoint.iter())
+            .all(|(p, e)| *p == "*" || p == e)
+}
+
+impl EndpointLimiters {
+    pub fn new(read: Limit, write: Limit) -> Self {
+        Self {
+            read: RateLimiter::new(read.num_requests, read.duration),
+            write: RateLimiter::new(write.num_requests, write.duration),
+            endpoints: Arc::new(Mutex::new(vec![])),
+            store_dir: Arc::new(Mutex::new(None)),
         }
+    }
 
-        let time_until_available = self.time_until_available();
+    fn store_path(dir: &Path, method: ip::Method, pattern: &str) -> PathBuf {
+        let name = format!("{method:?}_{pattern}")
+            .to_lowercase()
+            .replace('/', "_")
+            .replace('*', "any");
 
-        if time_until_available > timeout {
-            return false;
+        dir.join(name)
+    }
+
+    /// Keeps every limiter's recent requests in a file in `dir`, including
+    /// the limiters of endpoints set later. Processes using the same `dir`
+    /// share their budgets.
+    pub fn persist_to(&self, dir: &Path) {
+        self.read.persist_to(dir.join("read"));
+        self.write.persist_to(dir.join("write"));
+
+        for (method, pattern, limiter) in self.endpoints.lock().expect("attempting lock").iter() {
+            limiter.persist_to(Self::store_path(dir, *method, pattern));
         }
 
-        sleep(time_until_available);
+        *self.store_dir.lock().expect("attempting lock") = Some(dir.to_path_buf());
+    }
 
-        // Now we should be able to make a request. If we *cant*
-        // make the request, something has gone terribly wrong and
-        // we should panic (I believe)
-        assert!(self.attempt_commit(), "should have succeeded");
+    /// Gives requests to endpoints matching `pattern` a limit of their own,
+    /// replacing any previous limit for the pattern
+    pub fn set_limit(&self, method: ip::Method, pattern: &str, limit: Limit) {
+        let limiter = RateLimiter::new(limit.num_requests, limit.duration);
+        if let Some(dir) = self.store_dir.lock().expect("attempting lock").as_ref() {
+            limiter.persist_to(Self::store_path(dir, method, pattern));
+        }
 
-        true
+        let mut endpoints = self.endpoints.lock().expect("attempting lock");
+        endpoints.retain(|(m, p, _)| !(*m == method && p == pattern));
+        endpoints.push((method, pattern.to_string(), limiter));
     }
 
-    /// Will attempt to block for the "average pace" (duration / num reqs).
-    /// If we have to wait for longer than the average pace, we block then
-    /// commit with the timeout.
-    pub fn block_for_average_pace_then_commit(&mut self, timeout: Duration) -> bool {
-        let avg_pace = self.get_average_pace();
+    /// The limiters a request counts against, most specific first
+    fn limiters(&self, method: ip::Method, endpoint: &str) -> Vec<RateLimiter> {
+        let mut limiters = self
+            .endpoints
+            .lock()
+            .expect("attempting lock")
+            .iter()
+            .filter(|(m, pattern, _)| *m == method && matches_pattern(pattern, endpoint))
+            .map(|(_, _, limiter)| limiter.clone())
+            .collect::<Vec<RateLimiter>>();
 
-        if self.attempt() {
-            // since we can make an attempt, sleep for avg pace and then commit
-            sleep(avg_pace);
-            assert!(self.attempt_commit(), "should have succeeded");
-            true
-        } else {
-            // if we can't make an attempt, we need to block until
-            let time_until_free = self.time_until_available();
-            if time_until_free < avg_pace {
-                sleep(avg_pace);
-                assert!(self.attempt_commit(), "should have succeeded");
-                true
-            } else {
-                self.block_then_commit(timeout)
+        limiters.push(match method {
+            ip::Method::Get => self.read.clone(),
+            ip::Method::Post => self.write.clone(),
+        });
+
+        limiters
+    }
+
+    /// Waits, paced, until the request can be made under every limit it
+    /// counts against, then commits on all of them. Nothing is committed
+    /// until every limit has room, so waiting on one limit (or being
+    /// cancelled) doesn't waste a request on another. There's no timeout: a
+    /// server backoff can legitimately take longer than any window.
+    pub async fn acquire(&self, method: ip::Method, endpoint: &str, priority: Priority) {
+        let limiters = self.limiters(method, endpoint);
+
+        let pace = limiters
+            .iter()
+            .filter_map(|limiter| limiter.pace_delay(priority))
+            .max();
+        if let Some(pace) = pace {
+            sleep(pace).await;
+        }
+
+        // most specific first, so we don't hold up everything behind the
+        // method's limit while waiting on an endpoint's
+        let mut tickets = limiters.iter().map(|_| None).collect::<Vec<_>>();
+        let mut committed = vec![false; limiters.len()];
+        loop {
+            for (i, limiter) in limiters.iter().enumerate() {
+                if !committed[i] {
+                    let ticket = tickets[i].get_or_insert_with(|| Ticket::new(limiter, priority));
+                    limiter.wait_ready(ticket).await;
+                }
+            }
+
+            // another process can get in before us, in which case we wait
+            // again for just the limits that filled up
+            for (i, limiter) in limiters.iter().enumerate() {
+                if !committed[i] && limiter.try_commit().is_ok() {
+                    committed[i] = true;
+                    tickets[i] = None;
+                }
+            }
+
+            if committed.iter().all(|&committed| committed) {
+                return;
             }
         }
     }
+
+    /// We don't know which quota the server thinks we've used up, so all
+    /// the limiters the request counted against back off
+    pub fn throttle(&self, method: ip::Method, endpoint: &str, retry_after: Option<Duration>) {
+        for limiter in self.limiters(method, endpoint) {
+            limiter.throttle(retry_after);
+        }
+    }
+
+    pub fn record_success(&self, method: ip::Method, endpoint: &str) {
+        for limiter in self.limiters(method, endpoint) {
+            limiter.record_success();
+        }
+    }
 }
 
 #[cfg(test)]
@@ -160,8 +799,8 @@ mod tests {
         assert!(rl.attempt());
     }
 
-    #[test]
-    fn test_rate_limiter_3() {
+    #[tokio::test]
+    async fn test_rate_limiter_3() {
         let mut rl = RateLimiter::new(1, Duration::from_millis(100));
 
         // should successfully commit
@@ -170,19 +809,19 @@ mod tests {
         // should fail to commit since we *just* added it
         assert!(!rl.attempt());
 
-        // should succeed since we have to block for less time than the timeout
+        // should succeed since we have to wait for less time than the timeout
         // (therefore we don't timeout, therefore it's true)
-        assert!(rl.block_then_commit(Duration::from_millis(110)));
+        assert!(rl.acquire_timeout(Duration::from_millis(110)).await);
     }
 
-    #[test]
-    fn test_rate_limiter_4() {
+    #[tokio::test]
+    async fn test_rate_limiter_4() {
         // attempt doesn't change the state
         let mut rl = RateLimiter::new(1, Duration::from_millis(100));
 
         assert!(rl.attempt_commit());
         assert!(!rl.attempt());
-        assert!(!rl.block_then_commit(Duration::from_millis(1)));
+        assert!(!rl.acquire_timeout(Duration::from_millis(1)).await);
     }
 
     #[test]
@@ -197,8 +836,8 @@ mod tests {
         assert!(rl.time_until_available() > Duration::new(0, 0));
     }
 
-    #[test]
-    fn test_rate_limiter_6() {
+    #[tokio::test]
+    async fn test_rate_limiter_6() {
         let mut rl = RateLimiter::new(10, Duration::from_millis(100));
 
         // fill up the rl real quick
@@ -207,14 +846,14 @@ mod tests {
         }
 
         // If the timeout is less than the average pace, we should timeout
-        assert!(!rl.block_for_average_pace_then_commit(Duration::from_millis(1)));
+        assert!(!rl.acquire_paced(Duration::from_millis(1)).await);
 
         // But if the timeout is greater than the average pace, we should wait for the avg pace.
         // So first, put a fresh request in
-        rl.block_then_commit(Duration::from_millis(110));
+        rl.acquire_timeout(Duration::from_millis(110)).await;
         // and now we should wait at *least* 10 ms
         let start = Instant::now();
-        assert!(rl.block_for_average_pace_then_commit(Duration::from_millis(1000)));
+        assert!(rl.acquire_paced(Duration::from_millis(1000)).await);
         let elapsed = start.elapsed();
         // TODO this will be sensitive to the speed of the machine, but I think a ms is really long
         assert!(
@@ -228,4 +867,239 @@ mod tests {
             elapsed
         );
     }
+
+    #[tokio::test]
+    async fn test_rate_limiter_fifo() {
+        // waiters get through in the order they started waiting
+        let rl = RateLimiter::new(1, Duration::from_millis(20));
+        let order = Arc::new(Mutex::new(vec![]));
+
+        let mut handles = vec![];
+        for i in 0..4 {
+            let rl = rl.clone();
+            let order = order.clone();
+            handles.push(tokio::spawn(async move {
+                rl.acquire().await;
+                order.lock().unwrap().push(i);
+            }));
+            // make sure they queue up in order
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_priority() {
+        // urgent waiters go ahead of background ones that were there first
+        let mut rl = RateLimiter::new(1, Duration::from_millis(20));
+        assert!(rl.attempt_commit());
+
+        let order = Arc::new(Mutex::new(vec![]));
+
+        let mut handles = vec![];
+        for (i, priority) in [
+            Priority::Background,
+            Priority::Normal,
+            Priority::Background,
+            Priority::Urgent,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let rl = rl.clone();
+            let order = order.clone();
+            handles.push(tokio::spawn(async move {
+                rl.acquire_with_priority(priority).await;
+                order.lock().unwrap().push(i);
+            }));
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(*order.lock().unwrap(), vec![3, 1, 0, 2]);
+    }
+
+    #[test]
+    fn test_rate_limiter_throttle() {
+        let mut rl = RateLimiter::new(4, Duration::from_millis(50));
+
+        rl.throttle(Some(Duration::from_millis(20)));
+        assert_eq!(rl.capacity(), 2);
+        assert_eq!(rl.available_now(), 0);
+        assert!(!rl.attempt_commit());
+
+        // a second 429 during the same backoff doesn't shrink it further
+        rl.throttle(None);
+        assert_eq!(rl.capacity(), 2);
+
+        sleep(Duration::from_millis(25));
+        assert!(rl.attempt_commit());
+        assert!(rl.attempt_commit());
+        assert!(!rl.attempt_commit());
+
+        // two successes earn back a request, then three more
+        for _ in 0..5 {
+            rl.record_success();
+        }
+        assert_eq!(rl.capacity(), 4);
+        rl.record_success();
+        assert_eq!(rl.capacity(), 4);
+    }
+
+    #[test]
+    fn test_endpoint_limiters() {
+        let limiters = EndpointLimiters::new(
+            Limit::new(10, Duration::from_secs(1)),
+            Limit::new(5, Duration::from_secs(1)),
+        );
+        limiters.set_limit(
+            ip::Method::Post,
+            "market/*/sell",
+            Limit::new(1, Duration::from_secs(1)),
+        );
+
+        assert_eq!(
+            limiters.limiters(ip::Method::Post, "market/abc/sell").len(),
+            2
+        );
+        assert_eq!(limiters.limiters(ip::Method::Post, "bet").len(), 1);
+        assert_eq!(
+            limiters.limiters(ip::Method::Get, "market/abc/sell").len(),
+            1
+        );
+
+        // a 429 on a sell throttles both the sell and write limits, but not reads
+        limiters.throttle(ip::Method::Post, "market/abc/sell", None);
+        assert_eq!(limiters.write.capacity(), 2);
+        assert_eq!(limiters.read.capacity(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_limiters_cancelled() {
+        let limiters = EndpointLimiters::new(
+            Limit::new(10, Duration::from_secs(1)),
+            Limit::new(1, Duration::from_secs(10)),
+        );
+        limiters.set_limit(
+            ip::Method::Post,
+            "market/*/sell",
+            Limit::new(1, Duration::from_secs(10)),
+        );
+
+        limiters
+            .acquire(ip::Method::Post, "bet", Priority::Urgent)
+            .await;
+
+        // the sell has to wait on the write limit, and gives up
+        let sell = limiters.acquire(ip::Method::Post, "market/abc/sell", Priority::Urgent);
+        assert!(tokio::time::timeout(Duration::from_millis(50), sell)
+            .await
+            .is_err());
+
+        // without having used up the sell limit
+        assert_eq!(
+            limiters.limiters(ip::Method::Post, "market/abc/sell")[0].available_now(),
+            1
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one request")]
+    fn test_zero_limit() {
+        Limit::new(0, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_rate_limiter_persisted() {
+        let path = std::env::temp_dir().join(format!("mmm_rl_test_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut first = RateLimiter::new(2, Duration::from_millis(100));
+        first.persist_to(path.clone());
+        assert!(first.attempt_commit());
+
+        // another process, or this one after a restart, sees the request
+        let mut second = RateLimiter::new(2, Duration::from_millis(100));
+        second.persist_to(path.clone());
+        assert_eq!(second.available_now(), 1);
+        assert!(second.attempt_commit());
+
+        assert!(!first.attempt_commit());
+        assert!(!second.attempt_commit());
+
+        sleep(Duration::from_millis(110));
+        assert!(first.attempt_commit());
+        assert_eq!(second.available_now(), 1);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_store_busy() {
+        let path = std::env::temp_dir().join(format!("mmm_rl_busy_test_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut rl = RateLimiter::new(2, Duration::from_millis(100));
+        rl.persist_to(path.clone());
+
+        // another process holding the file doesn't block us
+        let other = File::create(&path).unwrap();
+        other.lock().unwrap();
+        assert!(!rl.attempt_commit());
+
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(other);
+        });
+        assert!(rl.acquire_timeout(Duration::from_secs(1)).await);
+        release.await.unwrap();
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_rate_limiter_burst_capacity() {
+        let mut rl = RateLimiter::new(3, Duration::from_millis(50));
+
+        assert_eq!(rl.available_now(), 3);
+        assert_eq!(rl.time_until(3), Some(Duration::new(0, 0)));
+        assert_eq!(rl.time_until(4), None);
+
+        assert!(rl.attempt_commit());
+        sleep(Duration::from_millis(10));
+        assert!(rl.attempt_commit());
+
+        assert_eq!(rl.available_now(), 1);
+        assert_eq!(rl.time_until(1), Some(Duration::new(0, 0)));
+
+        // two requests need the first to fall out of the window, three
+        // need the second to as well
+        let two = rl.time_until(2).unwrap();
+        let three = rl.time_until(3).unwrap();
+        assert!(two > Duration::new(0, 0) && two <= Duration::from_millis(40));
+        assert!(three > two);
+
+        sleep(Duration::from_millis(50));
+        assert_eq!(rl.available_now(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_cancellation() {
+        // a cancelled waiter doesn't hold up the ones behind it
+        let mut rl = RateLimiter::new(1, Duration::from_millis(50));
+        assert!(rl.attempt_commit());
+
+        let cancelled = tokio::time::timeout(Duration::from_millis(5), rl.acquire()).await;
+        assert!(cancelled.is_err());
+
+        assert!(rl.acquire_timeout(Duration::from_millis(60)).await);
+    }
 }
@@ -0,0 +1,209 @@
+/// Retrying failed API calls. Reads are safe to repeat, so they're retried
+/// on connection errors, timeouts, 429s and 5xx. Writes are only retried
+/// when we know the server never acted on them; a `bet` whose outcome we
+/// don't know can be reconciled against the account's recent bets instead.
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::bet_stream as bs;
+use crate::errors;
+use crate::internal_packet as ip;
+use crate::manifold_types as mt;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Including the first attempt
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(8),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Backoff before retry number `retry` (from 1): exponential, with
+    /// jitter so that requests that failed together don't retry together
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_delay);
+
+        // somewhere between half and all of the backoff
+        backoff / 2 + backoff.mul_f64(fastrand::f64() / 2.0)
+    }
+
+    /// Whether a request that failed with `err` on attempt `attempt` (from
+    /// 1) should be tried again
+    pub fn should_retry(&self, method: ip::Method, err: &errors::Error, attempt: u32) -> bool {
+        if attempt >= self.max_attempts {
+            return false;
+        }
+
+        match method {
+            ip::Method::Get => is_transient(err),
+            ip::Method::Post => provably_not_executed(err),
+        }
+    }
+}
+
+/// Errors that might go away if we try again. Manifold saying no, e.g. to
+/// a bet on a closed market, isn't one of them.
+pub fn is_transient(err: &errors::Error) -> bool {
+    match err {
+        errors::Error::Transport(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+        errors::Error::RateLimited { .. } => true,
+        errors::Error::Http { status, .. } => *status >= 500,
+        _ => false,
+    }
+}
+
+/// Errors that mean the server can't have acted on the request: we never
+/// connected, or it turned us away for going too fast
+pub fn provably_not_executed(err: &errors::Error) -> bool {
+    match err {
+        errors::Error::Transport(e) => e.is_connect(),
+        errors::Error::RateLimited { .. } => true,
+        _ => false,
+    }
+}
+
+/// Errors after which we can't tell whether a write went through, e.g. a
+/// timeout waiting for the response, or a 5xx from a proxy
+pub fn outcome_unknown(err: &errors::Error) -> bool {
+    match err {
+        errors::Error::Transport(e) => !e.is_connect(),
+        errors::Error::Http { status, .. } => *status >= 500,
+        _ => false,
+    }
+}
+
+/// Slack for the difference between our clock and the server's
+const CLOCK_SKEW_MS: u64 = 5_000;
+
+/// Finds the bet among `bets` that a `bet` POST with body `order`, sent at
+/// `sent_at` (unix ms), placed - if it was placed at all. Bets in `placed`
+/// are known to be from other orders, and of the rest that could be it, the
+/// one placed soonest after `sent_at` is.
+pub fn matching_bet<'a>(
+    order: &Value,
+    bets: &'a [mt::Bet],
+    sent_at: u64,
+    placed: &bs::Seen,
+) -> Option<&'a mt::Bet> {
+    let contract_id = order["contractId"].as_str()?;
+    let outcome = order["outcome"].as_str()?;
+    let amount = order["amount"].as_f64()?;
+    let answer_id = order["answerId"].as_str();
+    let limit_prob = order["limitProb"].as_f64();
+
+    let candidates = bets.iter().filter(|bet| {
+        // limit orders may only be partly filled, so compare the order size
+        let order_amount = match &bet.limit_props {
+            Some(props) => props.order_amount,
+            None => bet.amount,
+        };
+
+        !placed.contains(&bet.id)
+            && bet.created_time + CLOCK_SKEW_MS >= sent_at
+            && bet.contract_id == contract_id
+            && bet.outcome == outcome
+            && bet.answer_id.as_deref() == answer_id
+            && (order_amount - amount).abs() < 1e-6
+            && limit_prob.is_none_or(|p| {
+                bet.limit_props
+                    .as_ref()
+                    .is_some_and(|props| (props.limit_prob - p).abs() < 1e-6)
+            })
+    });
+
+    candidates.min_by_key(|bet| bet.created_time.abs_diff(sent_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn bet(id: &str, created_time: u64, amount: f64, outcome: &str) -> mt::Bet {
+        testing::bet(id)
+            .created_time(created_time)
+            .amount(amount)
+            .outcome(outcome)
+            .build()
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::default();
+
+        for retry in 1..10 {
+            let backoff = policy
+                .base_delay
+                .saturating_mul(2u32.pow(retry - 1))
+                .min(policy.max_delay);
+            let delay = policy.delay(retry);
+            assert!(delay >= backoff / 2 && delay <= backoff, "{delay:?}");
+        }
+    }
+
+    #[test]
+    fn test_matching_bet() {
+        let order = serde_json::json!({"contractId": "m", "outcome": "YES", "amount": 10.0});
+        let bets = vec![
+            bet("old", 1_000, 10.0, "YES"),
+            bet("no", 100_000, 10.0, "NO"),
+            bet("small", 100_000, 5.0, "YES"),
+            bet("ours", 100_000, 10.0, "YES"),
+        ];
+
+        let placed = bs::Seen::default();
+
+        assert_eq!(
+            matching_bet(&order, &bets, 99_000, &placed).map(|b| b.id.as_str()),
+            Some("ours")
+        );
+        assert!(matching_bet(&order, &bets[..3], 99_000, &placed).is_none());
+    }
+
+    #[test]
+    fn test_matching_identical_orders() {
+        // two identical orders: the first went through, the second timed out
+        let order = serde_json::json!({"contractId": "m", "outcome": "YES", "amount": 10.0});
+        let bets = vec![
+            bet("second", 102_000, 10.0, "YES"),
+            bet("first", 100_000, 10.0, "YES"),
+        ];
+
+        let mut placed = bs::Seen::default();
+        placed.insert("first");
+        assert_eq!(
+            matching_bet(&order, &bets, 101_500, &placed).map(|b| b.id.as_str()),
+            Some("second")
+        );
+
+        // the bet placed soonest after the order was sent is the likeliest
+        placed = bs::Seen::default();
+        assert_eq!(
+            matching_bet(&order, &bets, 101_500, &placed).map(|b| b.id.as_str()),
+            Some("second")
+        );
+        assert_eq!(
+            matching_bet(&order, &bets, 100_000, &placed).map(|b| b.id.as_str()),
+            Some("first")
+        );
+
+        placed.insert("first");
+        placed.insert("second");
+        assert!(matching_bet(&order, &bets, 99_000, &placed).is_none());
+    }
+}
@@ -0,0 +1,584 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::circuit_breaker::TripReason;
+use crate::internal_packet as ip;
+use crate::rate_limiter as rl;
+
+/// Limits every POST from a bot is checked against before it's sent.
+/// Amounts are in mana.
+#[derive(Debug, Clone)]
+pub struct RiskLimits {
+    pub max_bet_size: f64,
+    pub max_market_exposure: f64,
+    pub max_bot_exposure: f64,
+    /// Stop trading once Manifold's daily profit is below -daily_loss_limit
+    pub daily_loss_limit: f64,
+    /// Never let a bet take the balance below this
+    pub min_balance_reserve: f64,
+    /// Per bot. Zero stops all bets and sells.
+    pub max_orders_per_minute: usize,
+}
+
+impl Default for RiskLimits {
+    fn default() -> Self {
+        Self {
+            max_bet_size: 500.0,
+            max_market_exposure: 1000.0,
+            max_bot_exposure: 2000.0,
+            daily_loss_limit: 500.0,
+            min_balance_reserve: 100.0,
+            max_orders_per_minute: 8,
+        }
+    }
+}
+
+/// Why the risk manager refused to send a packet
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RiskRejection {
+    MaxBetSize {
+        amount: f64,
+        limit: f64,
+    },
+    MarketExposure {
+        market_id: String,
+        exposure: f64,
+        limit: f64,
+    },
+    BotExposure {
+        bot_id: String,
+        exposure: f64,
+        limit: f64,
+    },
+    DailyLossLimit {
+        loss: f64,
+        limit: f64,
+    },
+    BalanceReserve {
+        balance: f64,
+        amount: f64,
+        reserve: f64,
+    },
+    OrderRate {
+        bot_id: String,
+        limit: usize,
+    },
+    /// A bet without a usable amount or contract id
+    MalformedOrder(String),
+    /// A POST we don't know the cost of, so can't check
+    UnknownOrder {
+        endpoint: String,
+    },
+    /// The circuit breaker has halted trading for this bot, or everyone
+    Halted(TripReason),
+    /// The bot hasn't been given a budget by the capital allocator
+    NoBudget {
+        bot_id: String,
+    },
+    BudgetExceeded {
+        bot_id: String,
+        amount: f64,
+        available: f64,
+    },
+}
+
+impl fmt::Display for RiskRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RiskRejection::MaxBetSize { amount, limit } => {
+                write!(f, "bet of {amount:.2} is over max bet size {limit:.2}")
+            }
+            RiskRejection::MarketExposure {
+                market_id,
+                exposure,
+                limit,
+            } => write!(
+                f,
+                "exposure {exposure:.2} in market {market_id} would exceed {limit:.2}"
+            ),
+            RiskRejection::BotExposure {
+                bot_id,
+                exposure,
+                limit,
+            } => write!(
+                f,
+                "exposure {exposure:.2} of bot {bot_id} would exceed {limit:.2}"
+            ),
+            RiskRejection::DailyLossLimit { loss, limit } => {
+                write!(f, "daily loss {loss:.2} is over limit {limit:.2}")
+            }
+            RiskRejection::BalanceReserve {
+                balance,
+                amount,
+                reserve,
+            } => write!(
+                f,
+                "bet of {amount:.2} would take balance {balance:.2} below reserve {reserve:.2}"
+            ),
+            RiskRejection::OrderRate { bot_id, limit } => {
+                write!(f, "bot {bot_id} is over {limit} orders per minute")
+            }
+            RiskRejection::MalformedOrder(reason) => write!(f, "malformed order: {reason}"),
+            RiskRejection::UnknownOrder { endpoint } => {
+                write!(f, "don't know how to check a POST to {endpoint}")
+            }
+            RiskRejection::Halted(reason) => write!(f, "trading halted: {reason}"),
+            RiskRejection::NoBudget { bot_id } => write!(f, "bot {bot_id} has no budget"),
+            RiskRejection::BudgetExceeded {
+                bot_id,
+                amount,
+                available,
+            } => write!(
+                f,
+                "bet of {amount:.2} is over bot {bot_id}'s available budget {available:.2}"
+            ),
+        }
+    }
+}
+
+/// What a POST packet does, as far as risk is concerned
+#[derive(Debug, PartialEq)]
+enum Order {
+    Bet { market_id: String, amount: f64 },
+    Sell { market_id: String },
+    Cancel,
+}
+
+impl Order {
+    fn from_packet(packet: &ip::InternalPacket) -> Result<Self, RiskRejection> {
+        if packet.endpoint == "bet" {
+            let data = packet.data.as_ref();
+
+            let amount = data
+                .and_then(|d| d.get("amount"))
+                .and_then(|a| a.as_f64())
+                .ok_or(RiskRejection::MalformedOrder(
+                    "bet has no amount".to_string(),
+                ))?;
+
+            let market_id = packet.market_id().ok_or(RiskRejection::MalformedOrder(
+                "bet has no contractId".to_string(),
+            ))?;
+
+            Ok(Order::Bet { market_id, amount })
+        } else if packet.endpoint.starts_with("bet/cancel") {
+            Ok(Order::Cancel)
+        } else if packet.endpoint.ends_with("/sell") {
+            let market_id = packet.market_id().ok_or(RiskRejection::MalformedOrder(
+                "sell has no market".to_string(),
+            ))?;
+
+            Ok(Order::Sell { market_id })
+        } else {
+            Err(RiskRejection::UnknownOrder {
+                endpoint: packet.endpoint.clone(),
+            })
+        }
+    }
+}
+
+/// Pre-trade checks for bot packets. Exposure is tracked at cost: bets add
+/// their amount, and sells take off what they sold for.
+#[derive(Debug)]
+pub struct RiskManager {
+    limits: RiskLimits,
+
+    market_exposure: HashMap<String, f64>,
+    bot_exposure: HashMap<String, f64>,
+
+    /// From the last account refresh, adjusted locally for fills since.
+    /// None until the first refresh, in which case reserve checks are skipped.
+    balance: Option<f64>,
+    daily_profit: Option<f64>,
+
+    order_rate_limiters: HashMap<String, rl::RateLimiter>,
+}
+
+impl RiskManager {
+    pub fn new(limits: RiskLimits) -> Self {
+        Self {
+            limits,
+            market_exposure: HashMap::new(),
+            bot_exposure: HashMap::new(),
+            balance: None,
+            daily_profit: None,
+            order_rate_limiters: HashMap::new(),
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: RiskLimits) {
+        // rate limiters are sized from the limits, so start them over
+        self.order_rate_limiters.clear();
+        self.limits = limits;
+    }
+
+    pub fn update_account(&mut self, balance: f64, daily_profit: Option<f64>) {
+        debug!("risk account update: balance {balance} daily profit {daily_profit:?}");
+        self.balance = Some(balance);
+        self.daily_profit = daily_profit;
+    }
+
+    pub fn market_exposure(&self, market_id: &str) -> f64 {
+        self.market_exposure.get(market_id).copied().unwrap_or(0.0)
+    }
+
+    pub fn bot_exposure(&self, bot_id: &str) -> f64 {
+        self.bot_exposure.get(bot_id).copied().unwrap_or(0.0)
+    }
+
+    /// Whether this packet cancels an order. Cancels are let through even
+    /// when trading is halted, since they only reduce risk.
+    pub fn is_cancel(packet: &ip::InternalPacket) -> bool {
+        matches!(packet.method, ip::Method::Post) && Order::from_packet(packet) == Ok(Order::Cancel)
+    }
+
+    /// Checks a packet against the limits. GETs and cancellations always
+    /// pass, and POSTs we don't know the cost of never do. Orders only count
+    /// towards the bot's orders per minute once they're sent, see
+    /// `record_sent`.
+    pub fn check(&mut self, packet: &ip::InternalPacket) -> Result<(), RiskRejection> {
+        if let ip::Method::Get = packet.method {
+            return Ok(());
+        }
+
+        match Order::from_packet(packet)? {
+            Order::Cancel => return Ok(()),
+            Order::Bet { market_id, amount } => {
+                self.check_bet(&packet.bot_id, &market_id, amount)?;
+                self.check_daily_loss()?;
+            }
+            Order::Sell { .. } => {}
+        }
+
+        let limit = self.limits.max_orders_per_minute;
+        let allowed = self
+            .order_rate_limiter(&packet.bot_id)
+            .is_some_and(|limiter| limiter.attempt());
+        if !allowed {
+            return Err(RiskRejection::OrderRate {
+                bot_id: packet.bot_id.clone(),
+                limit,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Counts a checked order towards the bot's orders per minute, as it's
+    /// about to be sent
+    pub fn record_sent(&mut self, packet: &ip::InternalPacket) {
+        if let ip::Method::Get = packet.method {
+            return;
+        }
+
+        match Order::from_packet(packet) {
+            Ok(Order::Bet { .. }) | Ok(Order::Sell { .. }) => {
+                if let Some(limiter) = self.order_rate_limiter(&packet.bot_id) {
+                    limiter.attempt_commit();
+                }
+            }
+            Ok(Order::Cancel) | Err(_) => {}
+        }
+    }
+
+    /// None if no orders are allowed at all
+    fn order_rate_limiter(&mut self, bot_id: &str) -> Option<&mut rl::RateLimiter> {
+        let limit = self.limits.max_orders_per_minute;
+        if limit == 0 {
+            return None;
+        }
+
+        Some(
+            self.order_rate_limiters
+                .entry(bot_id.to_string())
+                .or_insert_with(|| rl::RateLimiter::new(limit, Duration::from_secs(60))),
+        )
+    }
+
+    fn check_bet(&self, bot_id: &str, market_id: &str, amount: f64) -> Result<(), RiskRejection> {
+        if amount.is_nan() || amount <= 0.0 {
+            return Err(RiskRejection::MalformedOrder(format!(
+                "bet amount {amount} isn't positive"
+            )));
+        }
+
+        if amount > self.limits.max_bet_size {
+            return Err(RiskRejection::MaxBetSize {
+                amount,
+                limit: self.limits.max_bet_size,
+            });
+        }
+
+        let market_exposure = self.market_exposure(market_id) + amount;
+        if market_exposure > self.limits.max_market_exposure {
+            return Err(RiskRejection::MarketExposure {
+                market_id: market_id.to_string(),
+                exposure: market_exposure,
+                limit: self.limits.max_market_exposure,
+            });
+        }
+
+        let bot_exposure = self.bot_exposure(bot_id) + amount;
+        if bot_exposure > self.limits.max_bot_exposure {
+            return Err(RiskRejection::BotExposure {
+                bot_id: bot_id.to_string(),
+                exposure: bot_exposure,
+                limit: self.limits.max_bot_exposure,
+            });
+        }
+
+        match self.balance {
+            Some(balance) if balance - amount < self.limits.min_balance_reserve => {
+                Err(RiskRejection::BalanceReserve {
+                    balance,
+                    amount,
+                    reserve: self.limits.min_balance_reserve,
+                })
+            }
+            Some(_) => Ok(()),
+            None => {
+                warn!("no balance known yet, skipping reserve check");
+                Ok(())
+            }
+        }
+    }
+
+    fn check_daily_loss(&self) -> Result<(), RiskRejection> {
+        match self.daily_profit {
+            Some(profit) if -profit > self.limits.daily_loss_limit => {
+                Err(RiskRejection::DailyLossLimit {
+                    loss: -profit,
+                    limit: self.limits.daily_loss_limit,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Updates exposure and balance for a packet that the API accepted.
+    /// `response` is the response body.
+    pub fn record_fill(&mut self, packet: &ip::InternalPacket, response: &str) {
+        let order = match Order::from_packet(packet) {
+            Ok(order) => order,
+            Err(_) => return,
+        };
+
+        match order {
+            Order::Bet { market_id, amount } => {
+                *self.market_exposure.entry(market_id).or_insert(0.0) += amount;
+                *self
+                    .bot_exposure
+                    .entry(packet.bot_id.clone())
+                    .or_insert(0.0) += amount;
+                if let Some(balance) = self.balance.as_mut() {
+                    *balance -= amount;
+                }
+            }
+            Order::Sell { market_id } => {
+                // the sale is recorded as a bet with a negative amount
+                let proceeds = serde_json::from_str::<serde_json::Value>(response)
+                    .ok()
+                    .and_then(|sale| sale["amount"].as_f64())
+                    .map(|amount| -amount)
+                    .unwrap_or(0.0);
+
+                for exposure in [
+                    self.market_exposure.entry(market_id).or_insert(0.0),
+                    self.bot_exposure
+                        .entry(packet.bot_id.clone())
+                        .or_insert(0.0),
+                ] {
+                    *exposure = (*exposure - proceeds).max(0.0);
+                }
+
+                if let Some(balance) = self.balance.as_mut() {
+                    *balance += proceeds;
+                }
+            }
+            Order::Cancel => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bet(bot_id: &str, market_id: &str, amount: f64) -> ip::InternalPacket {
+        ip::InternalPacket::new(
+            bot_id.to_string(),
+            ip::Method::Post,
+            "bet".to_string(),
+            vec![],
+            Some(serde_json::json!({
+                "amount": amount,
+                "contractId": market_id,
+                "outcome": "YES",
+            })),
+        )
+    }
+
+    fn limits() -> RiskLimits {
+        RiskLimits {
+            max_bet_size: 100.0,
+            max_market_exposure: 150.0,
+            max_bot_exposure: 250.0,
+            daily_loss_limit: 50.0,
+            min_balance_reserve: 10.0,
+            max_orders_per_minute: 100,
+        }
+    }
+
+    #[test]
+    fn test_max_bet_size() {
+        let mut rm = RiskManager::new(limits());
+        assert!(rm.check(&bet("b", "m", 100.0)).is_ok());
+        assert_eq!(
+            rm.check(&bet("b", "m", 101.0)),
+            Err(RiskRejection::MaxBetSize {
+                amount: 101.0,
+                limit: 100.0
+            })
+        );
+    }
+
+    #[test]
+    fn test_exposure() {
+        let mut rm = RiskManager::new(limits());
+
+        let first = bet("b", "m1", 100.0);
+        assert!(rm.check(&first).is_ok());
+        rm.record_fill(&first, "{}");
+
+        // market m1 would be at 200
+        assert!(matches!(
+            rm.check(&bet("b", "m1", 100.0)),
+            Err(RiskRejection::MarketExposure { .. })
+        ));
+
+        let second = bet("b", "m2", 100.0);
+        assert!(rm.check(&second).is_ok());
+        rm.record_fill(&second, "{}");
+
+        // bot b would be at 300
+        assert!(matches!(
+            rm.check(&bet("b", "m3", 100.0)),
+            Err(RiskRejection::BotExposure { .. })
+        ));
+
+        // selling m1 for 60 frees up room
+        let sell = ip::InternalPacket::new(
+            "b".to_string(),
+            ip::Method::Post,
+            "market/m1/sell".to_string(),
+            vec![],
+            Some(serde_json::json!({"outcome": "YES"})),
+        );
+        assert!(rm.check(&sell).is_ok());
+        rm.record_fill(&sell, r#"{"amount": -60.0}"#);
+        assert_eq!(rm.market_exposure("m1"), 40.0);
+        assert!(rm.check(&bet("b", "m3", 50.0)).is_ok());
+    }
+
+    #[test]
+    fn test_balance_and_daily_loss() {
+        let mut rm = RiskManager::new(limits());
+        rm.update_account(50.0, Some(-10.0));
+
+        assert!(matches!(
+            rm.check(&bet("b", "m", 45.0)),
+            Err(RiskRejection::BalanceReserve { .. })
+        ));
+        assert!(rm.check(&bet("b", "m", 40.0)).is_ok());
+
+        rm.update_account(500.0, Some(-60.0));
+        assert!(matches!(
+            rm.check(&bet("b", "m", 1.0)),
+            Err(RiskRejection::DailyLossLimit { .. })
+        ));
+    }
+
+    #[test]
+    fn test_order_rate() {
+        let mut rm = RiskManager::new(RiskLimits {
+            max_orders_per_minute: 2,
+            ..limits()
+        });
+
+        // checked orders that are never sent don't count
+        for _ in 0..3 {
+            assert!(rm.check(&bet("a", "m", 1.0)).is_ok());
+        }
+
+        for _ in 0..2 {
+            let order = bet("a", "m", 1.0);
+            assert!(rm.check(&order).is_ok());
+            rm.record_sent(&order);
+        }
+        assert!(matches!(
+            rm.check(&bet("a", "m", 1.0)),
+            Err(RiskRejection::OrderRate { .. })
+        ));
+
+        // other bots have their own budget, and cancels always go through
+        assert!(rm.check(&bet("b", "m", 1.0)).is_ok());
+        let cancel = ip::InternalPacket::new(
+            "a".to_string(),
+            ip::Method::Post,
+            "bet/cancel/xyz".to_string(),
+            vec![],
+            None,
+        );
+        assert!(rm.check(&cancel).is_ok());
+    }
+
+    #[test]
+    fn test_no_orders_allowed() {
+        let mut rm = RiskManager::new(limits());
+        rm.set_limits(RiskLimits {
+            max_orders_per_minute: 0,
+            ..limits()
+        });
+
+        let order = bet("a", "m", 1.0);
+        assert_eq!(
+            rm.check(&order),
+            Err(RiskRejection::OrderRate {
+                bot_id: "a".to_string(),
+                limit: 0
+            })
+        );
+        rm.record_sent(&order);
+        assert!(rm.check(&order).is_err());
+    }
+
+    #[test]
+    fn test_malformed_orders() {
+        let mut rm = RiskManager::new(limits());
+
+        for amount in [0.0, -5.0, f64::NAN] {
+            assert!(matches!(
+                rm.check(&bet("b", "m", amount)),
+                Err(RiskRejection::MalformedOrder(_))
+            ));
+        }
+
+        let other = ip::InternalPacket::new(
+            "b".to_string(),
+            ip::Method::Post,
+            "bet/multi-bet".to_string(),
+            vec![],
+            Some(serde_json::json!({"amount": 1000.0, "contractId": "m"})),
+        );
+        assert_eq!(
+            rm.check(&other),
+            Err(RiskRejection::UnknownOrder {
+                endpoint: "bet/multi-bet".to_string()
+            })
+        );
+    }
+}
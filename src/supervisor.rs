@@ -0,0 +1,355 @@
+/// Runs bots. Each bot gets a task that feeds it market snapshots, events,
+/// timer ticks and responses to its orders, sends the orders its hooks
+/// return, and halts it if it reports itself failed. On shutdown, every
+/// bot's flatten plan is carried out.
+use std::collections::HashMap;
+use std::future::Future;
+
+use log::{debug, error, info, warn};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{interval, Interval};
+
+use crate::bots::{Bot, BotHealth, FlattenPlan};
+use crate::circuit_breaker as cb;
+use crate::errors;
+use crate::events as ev;
+use crate::internal_packet as ip;
+use crate::liquidation as lq;
+use crate::market_handler::MarketHandler;
+
+/// Where a bot's orders go, and their responses come from
+pub(crate) trait OrderSink {
+    /// The response to `order`. An error means we can't know what became
+    /// of it, or of any orders after it.
+    async fn exchange(
+        &mut self,
+        order: ip::InternalPacket,
+    ) -> Result<ip::InternalPacket, errors::Error>;
+}
+
+/// A bot's side of its channels to the MarketHandler
+struct OrderChannel {
+    bot_id: String,
+    tx: mpsc::Sender<ip::InternalPacket>,
+    rx: broadcast::Receiver<ip::InternalPacket>,
+}
+
+impl OrderSink for OrderChannel {
+    /// Waits for the response with the order's request id. Missing
+    /// responses because we fell behind is fatal: one of them could be it.
+    async fn exchange(
+        &mut self,
+        order: ip::InternalPacket,
+    ) -> Result<ip::InternalPacket, errors::Error> {
+        let request_id = order.request_id;
+
+        if self.tx.send(order).await.is_err() {
+            return Err(errors::Error::ChannelClosed(format!(
+                "{} order",
+                self.bot_id
+            )));
+        }
+
+        loop {
+            match self.rx.recv().await {
+                Ok(response) if response.request_id == request_id => return Ok(response),
+                Ok(response) => debug!(
+                    "{} skipping response to request {}",
+                    self.bot_id, response.request_id
+                ),
+                Err(RecvError::Lagged(missed)) => {
+                    return Err(errors::Error::Other(format!(
+                        "{} missed {missed} order responses",
+                        self.bot_id
+                    )))
+                }
+                Err(RecvError::Closed) => {
+                    return Err(errors::Error::ChannelClosed(format!(
+                        "{} response",
+                        self.bot_id
+                    )))
+                }
+            }
+        }
+    }
+}
+
+/// Sends `orders` one at a time, and whatever the bot wants to do about
+/// each response straight after it. Stops at the first order we lose track
+/// of.
+pub(crate) async fn send_orders(
+    sink: &mut impl OrderSink,
+    bot: &mut dyn Bot,
+    orders: Vec<ip::InternalPacket>,
+) -> Result<(), errors::Error> {
+    let mut pending = orders;
+    pending.reverse();
+
+    while let Some(order) = pending.pop() {
+        let response = sink.exchange(order).await?;
+
+        let mut more = bot.on_order_update(&response);
+        more.reverse();
+        pending.extend(more);
+    }
+
+    Ok(())
+}
+
+async fn tick(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Runs one bot until shutdown, or until it loses track of its orders,
+/// returning its flatten plan. A bot that loses track is halted, as we
+/// can't know what it holds.
+async fn drive(
+    mut bot: Box<dyn Bot>,
+    mut orders: OrderChannel,
+    mut events: ev::Subscription,
+    snapshots: Vec<crate::manifold_types::FullMarket>,
+    mut shutdown: watch::Receiver<bool>,
+    circuit_breaker: cb::CircuitBreaker,
+) -> FlattenPlan {
+    let bot_id = bot.get_id();
+    let mut health = BotHealth::Healthy;
+
+    let mut check_health = |bot: &dyn Bot| {
+        let latest = bot.health();
+        if latest == health {
+            return;
+        }
+
+        match &latest {
+            BotHealth::Healthy => info!("{bot_id} is healthy again"),
+            BotHealth::Degraded(reason) => warn!("{bot_id} is degraded: {reason}"),
+            BotHealth::Failed(reason) => {
+                error!("{bot_id} failed: {reason}");
+                circuit_breaker.trip(Some(&bot_id), cb::TripReason::Unhealthy(reason.clone()));
+            }
+        }
+        health = latest;
+    };
+
+    let start_orders = bot.on_start(&snapshots);
+    let mut lost = send_orders(&mut orders, bot.as_mut(), start_orders)
+        .await
+        .err();
+    check_health(bot.as_ref());
+
+    let mut timer = bot.timer_interval().map(interval);
+
+    while lost.is_none() {
+        let new_orders = tokio::select! {
+            _ = shutdown.changed() => break,
+            event = events.recv() => match event {
+                Some(event) => bot.on_event(&event),
+                None => break,
+            },
+            _ = tick(&mut timer) => bot.on_timer(),
+        };
+
+        lost = send_orders(&mut orders, bot.as_mut(), new_orders)
+            .await
+            .err();
+        check_health(bot.as_ref());
+    }
+
+    if let Some(e) = lost {
+        error!("{bot_id} lost track of its orders: {e}");
+        circuit_breaker.trip(
+            Some(&bot_id),
+            cb::TripReason::Unhealthy(format!("lost track of its orders: {e}")),
+        );
+    }
+
+    info!("shutting down {}", bot.get_id());
+
+    let mut plan = bot.on_shutdown();
+    if let Err(e) = send_orders(&mut orders, bot.as_mut(), std::mem::take(&mut plan.orders)).await {
+        error!("couldn't send {}'s shutdown orders: {e}", bot.get_id());
+    }
+
+    if let Some(filter) = plan.liquidate.as_mut() {
+        filter.bot_id = Some(bot.get_id());
+    }
+
+    plan
+}
+
+#[derive(Default)]
+pub struct Supervisor {
+    bots: Vec<Box<dyn Bot>>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_bot(&mut self, bot: Box<dyn Bot>) {
+        self.bots.push(bot);
+    }
+
+    /// Runs the bots until `shutdown` completes, then shuts them down and
+    /// liquidates what their flatten plans ask for. Returns the liquidation
+    /// report for each bot that liquidated anything.
+    pub async fn run(
+        self,
+        market_handler: &mut MarketHandler,
+        shutdown: impl Future<Output = ()>,
+    ) -> HashMap<String, lq::LiquidationReport> {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut handles = vec![];
+
+        for bot in self.bots {
+            let bot_id = bot.get_id();
+
+            let (tx, rx) = match market_handler.internal_coms_init(bot_id.clone()).await {
+                Ok(channels) => channels,
+                Err(e) => {
+                    error!("couldn't start {bot_id}: {e}");
+                    continue;
+                }
+            };
+
+            let mut snapshots = vec![];
+            for market_id in bot.markets() {
+                match market_handler.get_market(&market_id).await {
+                    Ok(market) => snapshots.push(market),
+                    Err(e) => warn!("couldn't get market {market_id} for {bot_id}: {e}"),
+                }
+            }
+
+            let events = market_handler.subscribe(bot.event_filter());
+
+            let handle = tokio::spawn(drive(
+                bot,
+                OrderChannel {
+                    bot_id: bot_id.clone(),
+                    tx,
+                    rx,
+                },
+                events,
+                snapshots,
+                shutdown_rx.clone(),
+                market_handler.circuit_breaker(),
+            ));
+            handles.push((bot_id, handle));
+        }
+
+        shutdown.await;
+        shutdown_tx.send_replace(true);
+
+        let mut reports = HashMap::new();
+        for (bot_id, handle) in handles {
+            let plan = match handle.await {
+                Ok(plan) => plan,
+                Err(e) => {
+                    error!("{bot_id} stopped abnormally: {e}");
+                    continue;
+                }
+            };
+
+            let filter = match plan.liquidate {
+                Some(filter) => filter,
+                None => continue,
+            };
+
+            match market_handler
+                .liquidate(&filter, &lq::LiquidationOptions::default())
+                .await
+            {
+                Ok(report) => {
+                    reports.insert(bot_id, report);
+                }
+                Err(e) => error!("couldn't flatten {bot_id}: {e}"),
+            }
+        }
+
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(bot_id: &str) -> ip::InternalPacket {
+        ip::InternalPacket::new(
+            bot_id.to_string(),
+            ip::Method::Post,
+            "bet".to_string(),
+            vec![],
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_exchange_matches_request_id() {
+        let (tx, mut handler_rx) = mpsc::channel(8);
+        let (handler_tx, rx) = broadcast::channel(8);
+        let mut channel = OrderChannel {
+            bot_id: "a".to_string(),
+            tx,
+            rx,
+        };
+
+        // a response to some other order arrives first
+        let stale = order("a");
+        handler_tx
+            .send(ip::InternalPacket::response_from_existing(
+                &stale,
+                "stale".to_string(),
+            ))
+            .unwrap();
+
+        let sent = order("a");
+        let exchange = channel.exchange(sent.clone());
+        let answer = async {
+            let received = handler_rx.recv().await.unwrap();
+            assert_eq!(received.request_id, sent.request_id);
+            handler_tx
+                .send(ip::InternalPacket::response_from_existing(
+                    &received,
+                    "ours".to_string(),
+                ))
+                .unwrap();
+        };
+
+        let (response, _) = tokio::join!(exchange, answer);
+        assert_eq!(response.unwrap().response.as_deref(), Some("ours"));
+    }
+
+    #[tokio::test]
+    async fn test_exchange_lagged() {
+        let (tx, _handler_rx) = mpsc::channel(8);
+        let (handler_tx, rx) = broadcast::channel(1);
+        let mut channel = OrderChannel {
+            bot_id: "a".to_string(),
+            tx,
+            rx,
+        };
+
+        for _ in 0..2 {
+            let other = order("a");
+            handler_tx
+                .send(ip::InternalPacket::response_from_existing(
+                    &other,
+                    "{}".to_string(),
+                ))
+                .unwrap();
+        }
+
+        assert!(matches!(
+            channel.exchange(order("a")).await,
+            Err(errors::Error::Other(_))
+        ));
+    }
+}
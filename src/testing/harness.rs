@@ -0,0 +1,231 @@
+/// Runs a `Bot`'s hooks the way the supervisor does, answering its orders
+/// with canned responses instead of the API
+use std::collections::HashMap;
+
+use crate::bots::{Bot, BotHealth, FlattenPlan};
+use crate::errors::{self, ApiFailure};
+use crate::events::Event;
+use crate::internal_packet::InternalPacket;
+use crate::manifold_types as mt;
+use crate::risk::RiskRejection;
+use crate::supervisor::{self, OrderSink};
+
+enum Canned {
+    Body(String),
+    Rejection(RiskRejection),
+    Failure(ApiFailure),
+}
+
+/// Answers orders like the MarketHandler would, from canned responses
+#[derive(Default)]
+struct CannedResponses {
+    /// By endpoint; anything else gets "{}"
+    responses: HashMap<String, Canned>,
+    sent: Vec<InternalPacket>,
+}
+
+impl OrderSink for CannedResponses {
+    async fn exchange(&mut self, order: InternalPacket) -> Result<InternalPacket, errors::Error> {
+        let response = match self.responses.get(&order.endpoint) {
+            Some(Canned::Body(body)) => {
+                InternalPacket::response_from_existing(&order, body.clone())
+            }
+            Some(Canned::Rejection(rejection)) => {
+                InternalPacket::rejection_from_existing(&order, rejection.clone())
+            }
+            Some(Canned::Failure(failure)) => {
+                InternalPacket::error_from_existing(&order, failure.clone())
+            }
+            None => InternalPacket::response_from_existing(&order, "{}".to_string()),
+        };
+
+        self.sent.push(order);
+        Ok(response)
+    }
+}
+
+/// Runs a bot's hooks like the supervisor does. Each step returns the
+/// orders the bot sent during it, including any it sent in response to
+/// the canned responses.
+pub struct BotHarness<B: Bot> {
+    pub bot: B,
+    sink: CannedResponses,
+    market_groups: HashMap<String, Vec<String>>,
+}
+
+impl<B: Bot> BotHarness<B> {
+    pub fn new(bot: B) -> Self {
+        Self {
+            bot,
+            sink: CannedResponses::default(),
+            market_groups: HashMap::new(),
+        }
+    }
+
+    /// Responds to orders to `endpoint` with `body`
+    pub fn respond(mut self, endpoint: &str, body: &str) -> Self {
+        self.sink
+            .responses
+            .insert(endpoint.to_string(), Canned::Body(body.to_string()));
+        self
+    }
+
+    /// Rejects orders to `endpoint`, as the risk checks would
+    pub fn reject(mut self, endpoint: &str, rejection: RiskRejection) -> Self {
+        self.sink
+            .responses
+            .insert(endpoint.to_string(), Canned::Rejection(rejection));
+        self
+    }
+
+    /// Fails orders to `endpoint`, as the API would
+    pub fn fail(mut self, endpoint: &str, failure: ApiFailure) -> Self {
+        self.sink
+            .responses
+            .insert(endpoint.to_string(), Canned::Failure(failure));
+        self
+    }
+
+    async fn send(&mut self, orders: Vec<InternalPacket>) -> Vec<InternalPacket> {
+        let before = self.sink.sent.len();
+        supervisor::send_orders(&mut self.sink, &mut self.bot, orders)
+            .await
+            .expect("canned responses are never lost");
+        self.sink.sent[before..].to_vec()
+    }
+
+    pub async fn start(&mut self, markets: &[mt::FullMarket]) -> Vec<InternalPacket> {
+        for market in markets {
+            self.market_groups.insert(
+                market.lite_market.id.clone(),
+                market.group_slugs.clone().unwrap_or_default(),
+            );
+        }
+
+        let orders = self.bot.on_start(markets);
+        self.send(orders).await
+    }
+
+    /// Events the bot's filter doesn't match aren't passed on
+    pub async fn event(&mut self, event: Event) -> Vec<InternalPacket> {
+        if !self.bot.event_filter().matches(&event, &self.market_groups) {
+            return vec![];
+        }
+
+        let orders = self.bot.on_event(&event);
+        self.send(orders).await
+    }
+
+    pub async fn bets(&mut self, bets: Vec<mt::Bet>) -> Vec<InternalPacket> {
+        let mut sent = vec![];
+        for bet in bets {
+            sent.extend(self.event(Event::NewBet(bet)).await);
+        }
+        sent
+    }
+
+    pub async fn timer(&mut self) -> Vec<InternalPacket> {
+        let orders = self.bot.on_timer();
+        self.send(orders).await
+    }
+
+    /// The flatten plan, after sending its orders
+    pub async fn shutdown(&mut self) -> FlattenPlan {
+        let mut plan = self.bot.on_shutdown();
+        let orders = std::mem::take(&mut plan.orders);
+        plan.orders = self.send(orders).await;
+        plan
+    }
+
+    pub fn health(&self) -> BotHealth {
+        self.bot.health()
+    }
+
+    /// Every order sent so far
+    pub fn sent(&self) -> &[InternalPacket] {
+        &self.sink.sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    use crate::events::EventFilter;
+    use crate::internal_packet::Method;
+
+    /// Bets on every timer tick, and cancels any bet that gets filled
+    struct Scripted;
+
+    impl Bot for Scripted {
+        fn get_id(&self) -> String {
+            "scripted".to_string()
+        }
+
+        fn event_filter(&self) -> EventFilter {
+            EventFilter::default()
+        }
+
+        fn on_event(&mut self, _event: &Event) -> Vec<InternalPacket> {
+            vec![]
+        }
+
+        fn on_order_update(&mut self, packet: &InternalPacket) -> Vec<InternalPacket> {
+            match &packet.response {
+                Some(response) if packet.endpoint == "bet" => {
+                    let id = serde_json::from_str::<Value>(response).unwrap()["betId"].clone();
+                    vec![InternalPacket::new(
+                        self.get_id(),
+                        Method::Post,
+                        format!("bet/cancel/{}", id.as_str().unwrap()),
+                        vec![],
+                        None,
+                    )]
+                }
+                _ => vec![],
+            }
+        }
+
+        fn on_timer(&mut self) -> Vec<InternalPacket> {
+            let bet = |market: &str| {
+                InternalPacket::new(
+                    self.get_id(),
+                    Method::Post,
+                    "bet".to_string(),
+                    vec![],
+                    Some(json!({"contractId": market})),
+                )
+            };
+            vec![bet("m1"), bet("m2")]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_harness() {
+        let mut harness = BotHarness::new(Scripted).respond("bet", r#"{"betId": "b1"}"#);
+
+        // each response's follow-up goes out before the next order
+        let endpoints = |sent: Vec<InternalPacket>| {
+            sent.into_iter()
+                .map(|p| p.endpoint)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(
+            endpoints(harness.timer().await),
+            vec!["bet", "bet/cancel/b1", "bet", "bet/cancel/b1"]
+        );
+
+        let mut harness = harness.reject(
+            "bet",
+            RiskRejection::MaxBetSize {
+                amount: 1.0,
+                limit: 0.0,
+            },
+        );
+        assert_eq!(endpoints(harness.timer().await), vec!["bet", "bet"]);
+        assert_eq!(harness.sent().len(), 6);
+
+        assert!(harness.shutdown().await.liquidate.is_none());
+    }
+}
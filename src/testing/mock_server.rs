@@ -0,0 +1,129 @@
+/// A stand-in for the Manifold API on localhost, for testing code that goes
+/// through a `ManifoldClient`
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A request the server got
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Without the `/v0/` prefix or the query, e.g. `market/m/sell`
+    pub path: String,
+    /// Everything after the `?`, if anything
+    pub query: String,
+    /// The request line and headers
+    pub head: String,
+    pub body: String,
+}
+
+/// Answers requests by path with each of that path's (status, body)
+/// responses in turn, repeating the last; other paths get a 404. Point a
+/// client's `base_url` at `base_url()`.
+#[derive(Debug, Clone)]
+pub struct MockServer {
+    base_url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl MockServer {
+    pub async fn start(routes: Vec<(&str, Vec<(u16, Value)>)>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("binding mock server");
+        let base_url = format!("http://{}/v0", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let mut routes = routes
+            .into_iter()
+            .map(|(path, responses)| (path.to_string(), responses))
+            .collect::<HashMap<String, Vec<(u16, Value)>>>();
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let request = match read_request(&mut socket).await {
+                    Some(request) => request,
+                    None => continue,
+                };
+
+                let (status, body) = match routes.get_mut(&request.path) {
+                    Some(responses) if responses.len() > 1 => responses.remove(0),
+                    Some(responses) if !responses.is_empty() => responses[0].clone(),
+                    _ => (404, json!({"message": "not found"})),
+                };
+                seen.lock().unwrap().push(request);
+
+                let body = body.to_string();
+                let response = format!(
+                    "HTTP/1.1 {status} X\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        Self { base_url, requests }
+    }
+
+    pub fn base_url(&self) -> String {
+        self.base_url.clone()
+    }
+
+    /// Every request so far, oldest first
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The bodies of requests to `path`, as JSON
+    pub fn bodies(&self, path: &str) -> Vec<Value> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.path == path)
+            .map(|request| serde_json::from_str(&request.body).unwrap_or(Value::Null))
+            .collect()
+    }
+}
+
+async fn read_request(socket: &mut TcpStream) -> Option<Request> {
+    let mut request = vec![];
+    let mut buf = vec![0; 4096];
+
+    let (head, body) = loop {
+        let n = socket.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        request.extend_from_slice(&buf[..n]);
+
+        let text = String::from_utf8_lossy(&request).to_string();
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let length = head
+                .lines()
+                .find_map(|line| {
+                    line.to_lowercase()
+                        .strip_prefix("content-length:")
+                        .and_then(|v| v.trim().parse::<usize>().ok())
+                })
+                .unwrap_or(0);
+            if body.len() >= length {
+                break (head.to_string(), body.to_string());
+            }
+        }
+    };
+
+    let mut request_line = head.lines().next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.trim_start_matches("/v0/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    Some(Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        head: head.clone(),
+        body,
+    })
+}
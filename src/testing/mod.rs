@@ -0,0 +1,260 @@
+/// Test helpers: fixture builders for API types, which can also give the
+/// JSON Manifold would send, a mock of the API to serve it from, and a
+/// harness for bots.
+use serde_json::{json, Value};
+
+use crate::manifold_types as mt;
+
+#[cfg(feature = "bots")]
+mod harness;
+mod mock_server;
+#[cfg(feature = "bots")]
+pub use harness::BotHarness;
+pub use mock_server::{MockServer, Request};
+
+/// Builds a `Bet`. Defaults to a M10 YES bet by "u" in market "m", moving
+/// the probability from 0.5 to 0.51.
+#[derive(Debug, Clone)]
+pub struct BetBuilder {
+    value: Value,
+}
+
+pub fn bet(id: &str) -> BetBuilder {
+    BetBuilder {
+        value: json!({
+            "id": id,
+            "userId": "u",
+            "contractId": "m",
+            "createdTime": 0,
+            "amount": 10.0,
+            "outcome": "YES",
+            "shares": 20.0,
+            "probBefore": 0.5,
+            "probAfter": 0.51,
+            "fees": {"creatorFee": 0.0, "platformFee": 0.0, "liquidityFee": 0.0},
+            "isAnte": false,
+            "isRedemption": false,
+            "isChallenge": false,
+            "visibility": "public",
+        }),
+    }
+}
+
+impl BetBuilder {
+    pub fn market(mut self, market_id: &str) -> Self {
+        self.value["contractId"] = json!(market_id);
+        self
+    }
+
+    pub fn user(mut self, user_id: &str) -> Self {
+        self.value["userId"] = json!(user_id);
+        self
+    }
+
+    pub fn answer(mut self, answer_id: &str) -> Self {
+        self.value["answerId"] = json!(answer_id);
+        self
+    }
+
+    pub fn created_time(mut self, created_time: u64) -> Self {
+        self.value["createdTime"] = json!(created_time);
+        self
+    }
+
+    pub fn amount(mut self, amount: f64) -> Self {
+        self.value["amount"] = json!(amount);
+        self.value["shares"] = json!(amount * 2.0);
+        self
+    }
+
+    pub fn outcome(mut self, outcome: &str) -> Self {
+        self.value["outcome"] = json!(outcome);
+        self
+    }
+
+    pub fn probs(mut self, before: f64, after: f64) -> Self {
+        self.value["probBefore"] = json!(before);
+        self.value["probAfter"] = json!(after);
+        self
+    }
+
+    /// Makes it a limit order for `order_amount` at `limit_prob`
+    pub fn limit(mut self, order_amount: f64, limit_prob: f64) -> Self {
+        self.value["orderAmount"] = json!(order_amount);
+        self.value["limitProb"] = json!(limit_prob);
+        self.value["isFilled"] = json!(false);
+        self.value["isCancelled"] = json!(false);
+        if self.value.get("fills").is_none() {
+            self.value["fills"] = json!([]);
+        }
+        self
+    }
+
+    /// A fill against `matched_bet_id`, or the pool if None. Only kept on
+    /// limit orders.
+    pub fn fill(mut self, matched_bet_id: Option<&str>, amount: f64, shares: f64) -> Self {
+        let fill = json!({
+            "matchedBetId": matched_bet_id,
+            "amount": amount,
+            "shares": shares,
+            "timestamp": self.value["createdTime"],
+        });
+        match self.value["fills"].as_array_mut() {
+            Some(fills) => fills.push(fill),
+            None => self.value["fills"] = json!([fill]),
+        }
+        self
+    }
+
+    pub fn json(&self) -> Value {
+        self.value.clone()
+    }
+
+    pub fn build(&self) -> mt::Bet {
+        serde_json::from_value(self.json()).expect("bad bet fixture")
+    }
+}
+
+/// Builds an `Answer` with probability 0.5, in market "m"
+#[derive(Debug, Clone)]
+pub struct AnswerBuilder {
+    value: Value,
+}
+
+pub fn answer(id: &str) -> AnswerBuilder {
+    AnswerBuilder {
+        value: json!({
+            "id": id,
+            "createdTime": 0,
+            "avatarURL": null,
+            "username": null,
+            "number": null,
+            "name": null,
+            "contractId": "m",
+            "text": id,
+            "userId": "u",
+            "probability": 0.5,
+        }),
+    }
+}
+
+impl AnswerBuilder {
+    pub fn market(mut self, market_id: &str) -> Self {
+        self.value["contractId"] = json!(market_id);
+        self
+    }
+
+    pub fn text(mut self, text: &str) -> Self {
+        self.value["text"] = json!(text);
+        self
+    }
+
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.value["probability"] = json!(probability);
+        self
+    }
+
+    pub fn json(&self) -> Value {
+        self.value.clone()
+    }
+
+    pub fn build(&self) -> mt::Answer {
+        serde_json::from_value(self.json()).expect("bad answer fixture")
+    }
+}
+
+/// Builds a `FullMarket`: an open binary cpmm market at 0.5, unless it's
+/// given answers, which make it multiple choice
+#[derive(Debug, Clone)]
+pub struct MarketBuilder {
+    value: Value,
+}
+
+pub fn market(id: &str) -> MarketBuilder {
+    MarketBuilder {
+        value: json!({
+            "id": id,
+            "creatorId": "creator",
+            "creatorUsername": "creator",
+            "creatorName": "Creator",
+            "creatorAvatarUrl": null,
+            "closeTime": null,
+            "createdTime": 0,
+            "question": format!("Question {id}?"),
+            "url": format!("https://manifold.markets/creator/{id}"),
+            "slug": id,
+            "outcomeType": "BINARY",
+            "mechanism": "cpmm-1",
+            "probability": 0.5,
+            "pool": {"YES": 100.0, "NO": 100.0},
+            "p": 0.5,
+            "totalLiquidity": 100.0,
+            "value": null,
+            "min": null,
+            "max": null,
+            "isLogScale": null,
+            "volume": 0.0,
+            "volume24Hours": 0.0,
+            "isResolved": false,
+            "resolutionTime": null,
+            "resolution": null,
+            "resolutionProbability": null,
+            "uniqueBettorCount": 0,
+            "lastUpdatedTime": null,
+            "lastBetTime": null,
+            "answers": null,
+            "textDescription": "",
+            "groupSlugs": null,
+        }),
+    }
+}
+
+impl MarketBuilder {
+    pub fn question(mut self, question: &str) -> Self {
+        self.value["question"] = json!(question);
+        self
+    }
+
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.value["probability"] = json!(probability);
+        self
+    }
+
+    pub fn close_time(mut self, close_time: i64) -> Self {
+        self.value["closeTime"] = json!(close_time);
+        self
+    }
+
+    pub fn resolved(mut self, resolution: &str) -> Self {
+        self.value["isResolved"] = json!(true);
+        self.value["resolution"] = json!(resolution);
+        self
+    }
+
+    pub fn groups(mut self, slugs: &[&str]) -> Self {
+        self.value["groupSlugs"] = json!(slugs);
+        self
+    }
+
+    pub fn answers(mut self, answers: Vec<AnswerBuilder>) -> Self {
+        let market_id = self.value["id"].as_str().unwrap_or_default().to_string();
+        let answers = answers
+            .into_iter()
+            .map(|a| a.market(&market_id).json())
+            .collect::<Vec<_>>();
+
+        self.value["outcomeType"] = json!("MULTIPLE_CHOICE");
+        self.value["mechanism"] = json!("cpmm-multi-1");
+        self.value["probability"] = Value::Null;
+        self.value["answers"] = json!(answers);
+        self
+    }
+
+    pub fn json(&self) -> Value {
+        self.value.clone()
+    }
+
+    pub fn build(&self) -> mt::FullMarket {
+        serde_json::from_value(self.json()).expect("bad market fixture")
+    }
+}
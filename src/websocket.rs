@@ -0,0 +1,506 @@
+/// Streams bets and market updates from Manifold's websocket feed instead
+/// of polling for them. Bets go out on the same feeds the poller uses;
+/// with the `polling` feature, those streams are polled again while the
+/// socket is down.
+///
+/// The protocol: we send `{"type": "subscribe", "txid": n, "topics": [...]}`
+/// and pings, and the server sends `{"type": "broadcast", "topic": ...,
+/// "data": ...}` for every update on a topic we're subscribed to.
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{interval, sleep};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use crate::bet_stream as bs;
+use crate::manifold_types as mt;
+#[cfg(feature = "polling")]
+use crate::poll_scheduler as ps;
+
+#[derive(Debug, Clone)]
+pub struct WsConfig {
+    pub url: String,
+    pub ping_interval: Duration,
+    /// How long to wait before reconnecting after the socket drops
+    pub reconnect_delay: Duration,
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self {
+            url: "wss://api.manifold.markets/ws".to_string(),
+            ping_interval: Duration::from_secs(30),
+            reconnect_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Updates that aren't bets
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub enum MarketUpdate {
+    /// Changed fields of the market
+    Market {
+        market_id: String,
+        data: Value,
+    },
+    NewAnswer {
+        market_id: String,
+        data: Value,
+    },
+    UpdatedAnswers {
+        market_id: String,
+        data: Value,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct Broadcast {
+    topic: String,
+    data: Value,
+}
+
+#[derive(Debug)]
+enum Update {
+    Bets {
+        market_id: String,
+        bets: Vec<mt::Bet>,
+    },
+    Market(MarketUpdate),
+}
+
+fn topics(market_id: &str) -> Vec<String> {
+    vec![
+        format!("contract/{market_id}/new-bet"),
+        format!("contract/{market_id}"),
+        format!("contract/{market_id}/new-answer"),
+        format!("contract/{market_id}/updated-answers"),
+    ]
+}
+
+/// Parses a message from the server; anything but a broadcast on a topic we
+/// know is None
+fn parse_message(text: &str) -> Option<Update> {
+    let value = serde_json::from_str::<Value>(text).ok()?;
+    if value["type"] != "broadcast" {
+        return None;
+    }
+
+    let Broadcast { topic, data } = serde_json::from_value(value).ok()?;
+    let parts = topic.split('/').collect::<Vec<&str>>();
+
+    match parts.as_slice() {
+        ["contract", market_id, "new-bet"] => {
+            let bets = match serde_json::from_value(data["bets"].clone()) {
+                Ok(bets) => bets,
+                Err(e) => {
+                    warn!("couldn't parse bets from websocket: {e}");
+                    return None;
+                }
+            };
+
+            Some(Update::Bets {
+                market_id: market_id.to_string(),
+                bets,
+            })
+        }
+        ["contract", market_id] => Some(Update::Market(MarketUpdate::Market {
+            market_id: market_id.to_string(),
+            data,
+        })),
+        ["contract", market_id, "new-answer"] => Some(Update::Market(MarketUpdate::NewAnswer {
+            market_id: market_id.to_string(),
+            data,
+        })),
+        ["contract", market_id, "updated-answers"] => {
+            Some(Update::Market(MarketUpdate::UpdatedAnswers {
+                market_id: market_id.to_string(),
+                data,
+            }))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Clone)]
+pub struct WsTransport {
+    /// Bet feed for each market we're subscribed to
+    subscriptions: Arc<Mutex<HashMap<String, bs::BetFeed>>>,
+    subscribe_tx: mpsc::UnboundedSender<String>,
+    updates: broadcast::Sender<MarketUpdate>,
+    connected: Arc<AtomicBool>,
+    #[cfg(feature = "polling")]
+    poll_scheduler: Option<ps::PollScheduler>,
+}
+
+#[allow(dead_code)]
+impl WsTransport {
+    /// Starts connecting in the background. Bets sent while the socket is
+    /// down are missed.
+    pub fn spawn(config: WsConfig, halt_flag: Arc<AtomicBool>) -> Self {
+        Self::start(
+            config,
+            #[cfg(feature = "polling")]
+            None,
+            halt_flag,
+        )
+    }
+
+    /// Starts connecting in the background. `poll_scheduler` polls the
+    /// subscribed markets whenever the socket is down.
+    #[cfg(feature = "polling")]
+    pub fn spawn_with_polling(
+        config: WsConfig,
+        poll_scheduler: ps::PollScheduler,
+        halt_flag: Arc<AtomicBool>,
+    ) -> Self {
+        Self::start(config, Some(poll_scheduler), halt_flag)
+    }
+
+    fn start(
+        config: WsConfig,
+        #[cfg(feature = "polling")] poll_scheduler: Option<ps::PollScheduler>,
+        halt_flag: Arc<AtomicBool>,
+    ) -> Self {
+        let (subscribe_tx, subscribe_rx) = mpsc::unbounded_channel();
+        let (updates, _) = broadcast::channel(128);
+
+        let transport = Self {
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            subscribe_tx,
+            updates,
+            connected: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "polling")]
+            poll_scheduler,
+        };
+
+        tokio::spawn(transport.clone().run(config, subscribe_rx, halt_flag));
+
+        transport
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// Sends new bets in the market to `feed`. When polling, the market's bet
+    /// stream must already be registered with the poll scheduler under its
+    /// id.
+    pub fn subscribe_market(&self, market_id: String, feed: bs::BetFeed) {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .insert(market_id.clone(), feed);
+
+        if self.subscribe_tx.send(market_id).is_err() {
+            warn!("websocket task is gone, no more bets from it");
+        }
+    }
+
+    pub fn market_updates(&self) -> broadcast::Receiver<MarketUpdate> {
+        self.updates.subscribe()
+    }
+
+    fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::SeqCst);
+
+        for market_id in self.subscriptions.lock().unwrap().keys() {
+            self.pause_polling(market_id, connected);
+        }
+    }
+
+    /// Stops polling a market while the socket covers it
+    #[cfg_attr(not(feature = "polling"), allow(unused_variables))]
+    fn pause_polling(&self, market_id: &str, paused: bool) {
+        #[cfg(feature = "polling")]
+        if let Some(poll_scheduler) = &self.poll_scheduler {
+            poll_scheduler.set_paused(market_id, paused);
+        }
+    }
+
+    /// Moves the market's poll cursor past a bet we got from the socket
+    #[cfg_attr(not(feature = "polling"), allow(unused_variables))]
+    fn advance_polling(&self, market_id: &str, bet_id: String) {
+        #[cfg(feature = "polling")]
+        if let Some(poll_scheduler) = &self.poll_scheduler {
+            poll_scheduler.advance(market_id, bet_id);
+        }
+    }
+
+    /// Whether the poll scheduler covers for the socket while it's down
+    fn polled(&self) -> bool {
+        #[cfg(feature = "polling")]
+        return self.poll_scheduler.is_some();
+        #[cfg(not(feature = "polling"))]
+        false
+    }
+
+    /// Without polling, bets sent while the socket was down are missed
+    fn send_reconnected(&self) {
+        let event = if self.polled() {
+            bs::BetStreamEvent::Reconnected
+        } else {
+            bs::BetStreamEvent::Gap { missed: None }
+        };
+
+        for feed in self.subscriptions.lock().unwrap().values() {
+            feed.send(event.clone());
+        }
+    }
+
+    fn handle_update(&self, update: Update) {
+        match update {
+            Update::Bets { market_id, bets } => {
+                let feed = match self.subscriptions.lock().unwrap().get(&market_id) {
+                    Some(feed) => feed.clone(),
+                    None => return,
+                };
+
+                for bet in bets {
+                    let bet_id = bet.id.clone();
+                    if feed.send_bet(bet) {
+                        self.advance_polling(&market_id, bet_id);
+                    }
+                }
+            }
+            Update::Market(update) => {
+                // fine if no one's listening
+                let _ = self.updates.send(update);
+            }
+        }
+    }
+
+    async fn run(
+        self,
+        config: WsConfig,
+        mut subscribe_rx: mpsc::UnboundedReceiver<String>,
+        halt_flag: Arc<AtomicBool>,
+    ) {
+        let mut txid = 0u64;
+        let mut reconnecting = false;
+
+        while !halt_flag.load(Ordering::SeqCst) {
+            let socket = match connect_async(config.url.as_str()).await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    warn!("couldn't connect to websocket {}: {e}", config.url);
+                    sleep(config.reconnect_delay).await;
+                    continue;
+                }
+            };
+
+            info!("connected to websocket {}", config.url);
+            let (mut write, mut read) = socket.split();
+
+            let markets = self
+                .subscriptions
+                .lock()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<String>>();
+            let all_topics = markets.iter().flat_map(|m| topics(m)).collect::<Vec<_>>();
+
+            txid += 1;
+            let subscribe = json!({"type": "subscribe", "txid": txid, "topics": all_topics});
+            if !all_topics.is_empty()
+                && write
+                    .send(Message::text(subscribe.to_string()))
+                    .await
+                    .is_err()
+            {
+                sleep(config.reconnect_delay).await;
+                continue;
+            }
+            self.set_connected(true);
+            if reconnecting {
+                self.send_reconnected();
+            }
+
+            let mut ping = interval(config.ping_interval);
+            loop {
+                tokio::select! {
+                    msg = read.next() => match msg {
+                        Some(Ok(Message::Text(text))) => {
+                            if let Some(update) = parse_message(&text) {
+                                self.handle_update(update);
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => {
+                            warn!("websocket error: {e}");
+                            break;
+                        }
+                    },
+                    Some(market_id) = subscribe_rx.recv() => {
+                        txid += 1;
+                        let subscribe = json!({"type": "subscribe", "txid": txid, "topics": topics(&market_id)});
+                        if write.send(Message::text(subscribe.to_string())).await.is_err() {
+                            break;
+                        }
+                        self.pause_polling(&market_id, true);
+                    },
+                    _ = ping.tick() => {
+                        txid += 1;
+                        let ping = json!({"type": "ping", "txid": txid});
+                        if write.send(Message::text(ping.to_string())).await.is_err() {
+                            break;
+                        }
+                    },
+                }
+
+                if halt_flag.load(Ordering::SeqCst) {
+                    break;
+                }
+            }
+
+            warn!("websocket disconnected, polling until it's back");
+            self.set_connected(false);
+            reconnecting = true;
+            sleep(config.reconnect_delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing;
+
+    fn bet_json(id: &str) -> Value {
+        testing::bet(id).json()
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let bets = json!({
+            "type": "broadcast",
+            "topic": "contract/m/new-bet",
+            "data": {"bets": [bet_json("b")]},
+        });
+        assert!(matches!(
+            parse_message(&bets.to_string()),
+            Some(Update::Bets { market_id, bets }) if market_id == "m" && bets.len() == 1
+        ));
+
+        let market = json!({"type": "broadcast", "topic": "contract/m", "data": {"prob": 0.4}});
+        assert!(matches!(
+            parse_message(&market.to_string()),
+            Some(Update::Market(MarketUpdate::Market { .. }))
+        ));
+
+        assert!(parse_message(r#"{"type": "ack", "txid": 1, "success": true}"#).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_without_polling() {
+        let transport = WsTransport::spawn(
+            WsConfig {
+                url: "ws://127.0.0.1:1".to_string(),
+                ping_interval: Duration::from_secs(30),
+                reconnect_delay: Duration::from_secs(30),
+            },
+            Arc::new(AtomicBool::new(true)),
+        );
+        let feed = bs::BetFeed::new(8);
+        let mut stream = feed.subscribe();
+        transport.subscribe_market("m".to_string(), feed);
+
+        transport.send_reconnected();
+        assert!(matches!(
+            stream.recv().await,
+            Some(bs::BetStreamEvent::Gap { missed: None })
+        ));
+    }
+
+    #[cfg(feature = "polling")]
+    #[tokio::test]
+    async fn test_stand_in_server() {
+        use tokio::net::TcpListener;
+        use tokio_tungstenite::accept_async;
+
+        // a stand-in for Manifold's websocket: waits for a subscription,
+        // sends one bet, then hangs up when told to
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (hang_up_tx, hang_up_rx) = tokio::sync::oneshot::channel::<()>();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(stream).await.unwrap();
+
+            let subscribe = loop {
+                if let Some(Ok(Message::Text(text))) = socket.next().await {
+                    let msg = serde_json::from_str::<Value>(&text).unwrap();
+                    if msg["type"] == "subscribe" {
+                        break msg;
+                    }
+                }
+            };
+
+            let broadcast = json!({
+                "type": "broadcast",
+                "topic": "contract/m/new-bet",
+                "data": {"bets": [bet_json("b")]},
+            });
+            socket
+                .send(Message::text(broadcast.to_string()))
+                .await
+                .unwrap();
+
+            hang_up_rx.await.unwrap();
+            socket.close(None).await.unwrap();
+
+            subscribe
+        });
+
+        let scheduler = ps::PollScheduler::new(ps::PollConfig::default());
+        let feed = bs::BetFeed::new(8);
+        let mut stream = feed.subscribe();
+        scheduler.add_stream("m".to_string(), vec![], Some("a".to_string()), feed.clone());
+
+        let transport = WsTransport::spawn_with_polling(
+            WsConfig {
+                url,
+                ping_interval: Duration::from_secs(30),
+                reconnect_delay: Duration::from_secs(30),
+            },
+            scheduler.clone(),
+            Arc::new(AtomicBool::new(false)),
+        );
+        transport.subscribe_market("m".to_string(), feed);
+
+        let event = tokio::time::timeout(Duration::from_secs(5), stream.recv())
+            .await
+            .unwrap();
+        assert!(matches!(event, Some(bs::BetStreamEvent::Bet(bet)) if bet.id == "b"));
+        assert_eq!(scheduler.is_paused("m"), Some(true));
+
+        hang_up_tx.send(()).unwrap();
+        let subscribe = server.await.unwrap();
+        assert!(subscribe["topics"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("contract/m/new-bet")));
+
+        // once the server hangs up, we're back to polling
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while transport.is_connected() || scheduler.is_paused("m") != Some(false) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}